@@ -0,0 +1,179 @@
+//! Benchmarks of cache operations on synthetic caches. Hashes are "created" by a loader which does not read the
+//! files, so no real videos or ffmpeg are needed.
+//!
+//! By default caches of 10,000 entries are used. Larger sizes can be set with a comma separated list in the
+//! `VHFC_BENCH_SIZES` environment variable, e.g. `VHFC_BENCH_SIZES=10000,100000,1000000 cargo bench`.
+
+use std::{
+    env, fs,
+    hint::black_box,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use vid_dup_finder_lib::*;
+use video_hash_filesystem_cache::*;
+
+const FILES_PER_DIR: usize = 1000;
+
+fn bench_sizes() -> Vec<usize> {
+    env::var("VHFC_BENCH_SIZES")
+        .ok()
+        .map(|sizes| sizes.split(',').filter_map(|size| size.trim().parse().ok()).collect())
+        .unwrap_or_else(|| vec![10_000])
+}
+
+//Every file is recorded as too short to hash, which is cached in the same way as a hash.
+fn synthetic_loader(src_path: &Path) -> Result<(VideoHash, VideoStats), HashCreationErrorKind> {
+    Err(HashCreationErrorKind::VideoLength(src_path.to_path_buf()))
+}
+
+//A directory of empty files, and a cache file containing an entry for each of them.
+struct Fixture {
+    root: PathBuf,
+    files: Vec<PathBuf>,
+    cache_path: PathBuf,
+}
+
+impl Fixture {
+    fn new(size: usize) -> Self {
+        let base = env::temp_dir().join(format!("vhfc_bench_{}", size));
+        let root = base.join("files");
+        let cache_path = base.join("cache.bin");
+        let _ = fs::remove_dir_all(&base);
+
+        let files = (0..size)
+            .map(|i| root.join(format!("{}", i / FILES_PER_DIR)).join(format!("{}.mp4", i)))
+            .collect::<Vec<_>>();
+        for file in &files {
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, b"").unwrap();
+        }
+
+        let fixture = Self {
+            root,
+            files,
+            cache_path,
+        };
+        let cache = fixture.open();
+        cache.update_using_fs(&fixture.projection()).unwrap();
+        cache.save().unwrap();
+
+        fixture
+    }
+
+    fn open(&self) -> VideoHashFilesystemCache {
+        VideoHashFilesystemCacheBuilder::new(u32::MAX, self.cache_path.clone())
+            .hash_loader(synthetic_loader)
+            .build()
+            .unwrap()
+    }
+
+    fn projection(&self) -> FileProjection {
+        let mut projection = FileProjection::new([&self.root], &[] as &[&Path], &[] as &[&str]).unwrap();
+        projection.project_using_fs().unwrap();
+        projection
+    }
+
+    //Change the modification time of the first `fraction` of files, so that their entries are stale.
+    fn make_stale(&self, fraction: f64, mtime: SystemTime) {
+        let count = (self.files.len() as f64 * fraction) as usize;
+        for file in &self.files[..count] {
+            fs::File::options().write(true).open(file).unwrap().set_modified(mtime).unwrap();
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.root.parent().unwrap());
+    }
+}
+
+fn cache_benches(c: &mut Criterion) {
+    for size in bench_sizes() {
+        let fixture = Fixture::new(size);
+        let cache = fixture.open();
+
+        let mut group = c.benchmark_group("cache");
+        group.sample_size(10);
+
+        group.bench_function(BenchmarkId::new("cold_load", size), |b| b.iter(|| black_box(fixture.open())));
+
+        group.bench_function(BenchmarkId::new("save", size), |b| b.iter(|| cache.save().unwrap()));
+
+        group.bench_function(BenchmarkId::new("fetch", size), |b| {
+            b.iter(|| {
+                for file in &fixture.files {
+                    let _ = black_box(cache.fetch(file));
+                }
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("fetch_update_hit", size), |b| {
+            b.iter(|| {
+                for file in &fixture.files {
+                    black_box(cache.fetch_update(file).unwrap());
+                }
+            })
+        });
+
+        for &stale in &[0.0, 0.1, 1.0] {
+            let name = format!("update_using_fs_{}%_stale", (stale * 100.0) as u32);
+            let mut mtime = SystemTime::now();
+            group.bench_function(BenchmarkId::new(name, size), |b| {
+                b.iter_batched(
+                    || {
+                        mtime += Duration::from_secs(1);
+                        fixture.make_stale(stale, mtime);
+                        fixture.projection()
+                    },
+                    |projection| cache.update_using_fs(&projection).unwrap(),
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+
+        group.finish();
+    }
+}
+
+//Projection of a tree in which every directory contains a few files and a few subdirectories.
+fn projection_benches(c: &mut Criterion) {
+    const DEPTH: usize = 6;
+    const BRANCHING: usize = 4;
+    const FILES_PER_LEVEL: usize = 3;
+
+    fn create_tree(dir: &Path, depth: usize) {
+        fs::create_dir_all(dir).unwrap();
+        for i in 0..FILES_PER_LEVEL {
+            fs::write(dir.join(format!("{}.mp4", i)), b"").unwrap();
+        }
+        if depth > 0 {
+            for i in 0..BRANCHING {
+                create_tree(&dir.join(format!("d{}", i)), depth - 1);
+            }
+        }
+    }
+
+    let root = env::temp_dir().join("vhfc_bench_projection");
+    let _ = fs::remove_dir_all(&root);
+    create_tree(&root, DEPTH);
+
+    let mut group = c.benchmark_group("projection");
+    group.sample_size(10);
+    group.bench_function(BenchmarkId::new("deep_tree", DEPTH), |b| {
+        b.iter(|| {
+            let mut projection = FileProjection::new([&root], &[] as &[&Path], &[] as &[&str]).unwrap();
+            projection.project_using_fs().unwrap();
+            black_box(projection)
+        })
+    });
+    group.finish();
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+criterion_group!(benches, cache_benches, projection_benches);
+criterion_main!(benches);
@@ -0,0 +1,1651 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex, MutexGuard, PoisonError, RwLock, TryLockError,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use itertools::Itertools;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::*;
+
+//Cache files start with this marker, which is followed by the number of entries, a length-prefixed record for the
+//key and value of each entry, and length-prefixed records for the user data, the entry times, the keyed values and
+//the insertion order. Each record is decoded separately, so that a corrupt entry does not prevent the others from
+//being loaded.
+//
+//Files written before entry times, keyed values or the insertion order were stored end after the previous record.
+//Entries in files without entry times are read as if they were created when the file was loaded, and entries without
+//a position in the insertion order are placed after all other entries.
+//
+//Cache files written before the marker was introduced start with the number of entries instead, which will never
+//be this large.
+const FORMAT_MARKER: u64 = 0x5648_4643_0000_0003;
+
+//The marker for cache files in which the entries and user data are not split into records.
+const FORMAT_MARKER_UNFRAMED: u64 = 0x5648_4643_0000_0002;
+
+//The marker for cache files written before user data was introduced, which contain only the entries.
+const FORMAT_MARKER_NO_USER_DATA: u64 = 0x5648_4643_0000_0001;
+
+//For each path, a map from user-defined names to values. Values are stored as JSON text because
+//bincode cannot deserialize self-describing values.
+type UserData = HashMap<PathBuf, BTreeMap<String, String>>;
+
+//For each path, when its entry was created and last accessed.
+type EntryTimes = HashMap<PathBuf, EntryMeta>;
+
+//Values stored under arbitrary keys instead of paths, which are not associated with a file.
+type KeyedValues<T> = HashMap<String, T>;
+
+//For each path, the position of its entry in the order in which entries were first inserted.
+type InsertionOrder = HashMap<PathBuf, u64>;
+
+//The records which follow the entries of a cache file.
+struct Trailer<T> {
+    user_data: UserData,
+    entry_times: EntryTimes,
+    keyed_values: KeyedValues<T>,
+    insertion_order: InsertionOrder,
+}
+
+impl<T> Default for Trailer<T> {
+    fn default() -> Self {
+        Self {
+            user_data: HashMap::new(),
+            entry_times: HashMap::new(),
+            keyed_values: HashMap::new(),
+            insertion_order: HashMap::new(),
+        }
+    }
+}
+
+//The times of an entry, and its position in the insertion order, which are stored as separate records in cache files.
+#[derive(Debug)]
+struct EntryInfo {
+    created_at: SystemTime,
+    //In nanoseconds since the Unix epoch, so that accesses can be recorded while entry_info is only locked for reading.
+    last_accessed: AtomicU64,
+    sequence: u64,
+}
+
+impl EntryInfo {
+    fn new(meta: EntryMeta, sequence: u64) -> Self {
+        Self {
+            created_at: meta.created_at,
+            last_accessed: AtomicU64::new(nanos_since_epoch(meta.last_accessed)),
+            sequence,
+        }
+    }
+
+    fn meta(&self) -> EntryMeta {
+        EntryMeta {
+            created_at: self.created_at,
+            last_accessed: UNIX_EPOCH + Duration::from_nanos(self.last_accessed.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn touch(&self, time: SystemTime) {
+        self.last_accessed.store(nanos_since_epoch(time), Ordering::Relaxed);
+    }
+}
+
+//Times before the epoch are stored as the epoch, and times after 2554 as the latest representable time.
+pub(crate) fn nanos_since_epoch(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    u64::try_from(since_epoch.as_nanos()).unwrap_or(u64::MAX)
+}
+
+//Serializes one field of every EntryInfo as a map from path to that field, so that the records can be written without
+//copying them.
+struct InfoRecord<'a, V> {
+    info: &'a HashMap<PathBuf, EntryInfo>,
+    field: fn(&EntryInfo) -> V,
+}
+
+impl<V: Serialize> Serialize for InfoRecord<'_, V> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.info.iter().map(|(key, info)| (key, (self.field)(info))))
+    }
+}
+
+/// A value which can be stored in a [BaseFsCache].
+pub trait CacheValue: Serialize + DeserializeOwned + Clone {
+    /// The format of values in cache files written before [FORMAT_MARKER] was introduced.
+    type Legacy: DeserializeOwned + Into<Self>;
+
+    /// True if the value only records that no value was loaded, so that
+    /// [fetch_update][crate::ProcessingFsCache::fetch_update] loads a new value even if the file was not modified.
+    fn is_placeholder(&self) -> bool {
+        false
+    }
+}
+
+/// An entry of the cache file which could not be decoded when the cache was loaded, and was skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedEntry {
+    /// The path of the entry, or None if its path could not be decoded either. None is also used when the user data,
+    /// or the remainder of the file, could not be read.
+    pub path: Option<PathBuf>,
+
+    /// A description of the problem.
+    pub error: String,
+}
+
+/// Problems found while loading the cache file. See
+/// [load_report][crate::VideoHashFilesystemCache::load_report].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Entries which could not be decoded, and were not loaded.
+    pub quarantined: Vec<QuarantinedEntry>,
+}
+
+/// When an entry of the cache was created and last accessed. See
+/// [entry_metadata][crate::VideoHashFilesystemCache::entry_metadata].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryMeta {
+    /// When the entry was first added to the cache. This is not changed when the entry is replaced because its file
+    /// was modified.
+    pub created_at: SystemTime,
+
+    /// When the entry was last fetched, created or replaced.
+    pub last_accessed: SystemTime,
+}
+
+impl EntryMeta {
+    fn new(time: SystemTime) -> Self {
+        Self {
+            created_at: time,
+            last_accessed: time,
+        }
+    }
+}
+
+pub(crate) type Entries<T> = HashMap<PathBuf, FsCacheEntry<T>>;
+
+//Reads length-prefixed records from a cache file without reading the whole file into memory.
+struct RecordScanner {
+    reader: BufReader<File>,
+    pos: u64,
+    len: u64,
+}
+
+impl RecordScanner {
+    //Returns None if the file ends first.
+    fn read_u64(&mut self) -> io::Result<Option<u64>> {
+        if self.len - self.pos < 8 {
+            return Ok(None);
+        }
+
+        let mut bytes = [0; 8];
+        self.reader.read_exact(&mut bytes)?;
+        self.pos += 8;
+        Ok(Some(u64::from_le_bytes(bytes)))
+    }
+
+    //Read the length of the next record. Returns None if the file ends before the record does, in which case no
+    //further records can be read.
+    fn next_record_len(&mut self) -> io::Result<Option<u64>> {
+        Ok(self.read_u64()?.filter(|&len| len <= self.len - self.pos))
+    }
+
+    //Read the next record. Returns None if the file ends before the record does.
+    fn next_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.next_record_len()? {
+            Some(len) => self.read_record(len).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn read_record(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        let mut record = vec![0; len as usize];
+        self.reader.read_exact(&mut record)?;
+        self.pos += len;
+        Ok(record)
+    }
+
+    //Move past a record without reading it, and return its offset.
+    fn skip_record(&mut self, len: u64) -> io::Result<u64> {
+        let offset = self.pos;
+        let relative = i64::try_from(len).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.reader.seek_relative(relative)?;
+        self.pos += len;
+        Ok(offset)
+    }
+}
+
+//The location of the value of each entry in a cache file which was opened lazily, so that values can be read when
+//they are fetched instead of when the cache is loaded.
+struct LazyIndex {
+    file: Mutex<File>,
+    //The offset and length of the value record of each entry.
+    offsets: HashMap<PathBuf, (u64, u64)>,
+}
+
+impl LazyIndex {
+    fn read_entry<T: DeserializeOwned>(
+        &self,
+        key: &Path,
+        cache_path: &Path,
+    ) -> Result<FsCacheEntry<T>, FsCacheErrorKind> {
+        let &(offset, len) = self
+            .offsets
+            .get(key)
+            .ok_or_else(|| FsCacheErrorKind::KeyMissing(key.to_path_buf()))?;
+
+        let mut bytes = vec![0; len as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut bytes))
+                .map_err(|src| FsCacheErrorKind::io(cache_path, src))?;
+        }
+
+        bincode::deserialize(&bytes).map_err(|src| FsCacheErrorKind::serialization(cache_path, *src))
+    }
+}
+
+//Decode the records which follow the entries. Only the user data is required, because the other records were added
+//later.
+fn read_trailer<'a, T: DeserializeOwned>(
+    mut records: impl Iterator<Item = &'a [u8]>,
+    quarantine: &mut impl FnMut(Option<PathBuf>, String),
+) -> Trailer<T> {
+    //User data for quarantined entries is kept, so that it is reattached if the entry is recreated.
+    let user_data = match records.next().map(bincode::deserialize) {
+        Some(Ok(user_data)) => user_data,
+        Some(Err(e)) => {
+            quarantine(None, format!("User data could not be read: {}", e));
+            HashMap::new()
+        }
+        None => {
+            quarantine(None, "User data is truncated".to_string());
+            HashMap::new()
+        }
+    };
+
+    Trailer {
+        user_data,
+        entry_times: read_optional_record("Entry times", records.next(), quarantine),
+        keyed_values: read_optional_record("Keyed values", records.next(), quarantine),
+        insertion_order: read_optional_record("Insertion order", records.next(), quarantine),
+    }
+}
+
+fn read_optional_record<V: DeserializeOwned + Default>(
+    name: &str,
+    record: Option<&[u8]>,
+    quarantine: &mut impl FnMut(Option<PathBuf>, String),
+) -> V {
+    match record.map(bincode::deserialize) {
+        Some(Ok(value)) => value,
+        Some(Err(e)) => {
+            quarantine(None, format!("{} could not be read: {}", name, e));
+            V::default()
+        }
+        None => V::default(),
+    }
+}
+
+fn write_record(buf: &mut Vec<u8>, value: &impl Serialize) -> bincode::Result<()> {
+    buf.extend_from_slice(&bincode::serialized_size(value)?.to_le_bytes());
+    bincode::serialize_into(buf, value)
+}
+
+/// A value stored in the cache, along with the modification time of the file it was created from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FsCacheEntry<T> {
+    pub mtime: SystemTime,
+    pub value: T,
+}
+
+/// A map from paths to values which is persisted to disk. Any write/delete operation
+/// counts toward the save threshold, and the cache saves itself when the threshold is reached.
+///
+/// The cache may be saved from any thread at any time, including while other threads are modifying it.
+pub struct BaseFsCache<T> {
+    cache_path: RwLock<PathBuf>,
+    cache_save_threshold: u32,
+    modifications: AtomicU32,
+    //Held for the duration of a save, so that concurrent saves do not write to the temporary file at the same time.
+    save_lock: Mutex<()>,
+    save_retries: u32,
+    save_retry_backoff: Duration,
+    entries: RwLock<HashMap<PathBuf, FsCacheEntry<T>>>,
+    //To avoid deadlock, when both locks are needed, entries must be locked first.
+    user_data: RwLock<UserData>,
+    //Accessing an entry updates its time without counting as a modification. When this is needed with entries or
+    //user_data, it must be locked last.
+    entry_info: RwLock<HashMap<PathBuf, EntryInfo>>,
+    //The position in the insertion order to give to the next new entry. Only changed while entry_info is locked.
+    next_sequence: AtomicU64,
+    //Independent of the entries, so never needs to be locked at the same time as them, except when saving.
+    keyed_values: RwLock<KeyedValues<T>>,
+    //Some if the cache was opened lazily and its values have not yet been loaded into memory, in which case entries
+    //is empty. Once the values are loaded this is None forever. When this and entries are both needed, this must be
+    //locked first.
+    lazy_index: RwLock<Option<LazyIndex>>,
+    load_report: LoadReport,
+}
+
+impl<T> BaseFsCache<T>
+where
+    T: CacheValue,
+{
+    /// Load the cache at cache_path, or create an empty cache if the file does not exist.
+    ///
+    /// If cache_path does not exist and a base_path is given, then the cache is loaded from base_path instead, but is
+    /// still saved to cache_path.
+    ///
+    /// Entries which cannot be decoded are skipped and listed in [load_report][Self::load_report], unless
+    /// `strict` is true, in which case an error is returned instead.
+    ///
+    /// If `lazy` is true, only the keys are read, and each value is read from the file when it is fetched. All values
+    /// are loaded into memory before the cache is first modified or saved. Cache files written before values were
+    /// stored in separate records are always loaded in full.
+    pub fn new(
+        cache_save_threshold: u32,
+        cache_path: PathBuf,
+        base_path: Option<&Path>,
+        strict: bool,
+        lazy: bool,
+    ) -> Result<Self, FsCacheErrorKind> {
+        let load_path = match base_path {
+            Some(base_path) if !cache_path.exists() => base_path,
+            _ => &cache_path,
+        };
+
+        let (entries, trailer, lazy_index, load_report) = match (load_path.exists(), lazy) {
+            (false, _) => (HashMap::new(), Trailer::default(), None, LoadReport::default()),
+            (true, true) => match Self::index_entries(load_path, strict)? {
+                Some((lazy_index, trailer, report)) => (HashMap::new(), trailer, Some(lazy_index), report),
+                None => {
+                    let (entries, trailer, report) = Self::read_entries(load_path, strict)?;
+                    (entries, trailer, None, report)
+                }
+            },
+            (true, false) => {
+                let (entries, trailer, report) = Self::read_entries(load_path, strict)?;
+                (entries, trailer, None, report)
+            }
+        };
+        let Trailer {
+            user_data,
+            entry_times,
+            keyed_values,
+            insertion_order,
+        } = trailer;
+        let keys = match &lazy_index {
+            Some(index) => index.offsets.keys().collect::<Vec<_>>(),
+            None => entries.keys().collect(),
+        };
+        let (entry_info, next_sequence) = load_entry_info(keys, entry_times, insertion_order);
+
+        for QuarantinedEntry { path, error } in &load_report.quarantined {
+            let path = path.as_ref().map(|path| path.display().to_string());
+            warn!(
+                "Skipped corrupt entry in cache {}: {}: {}",
+                load_path.display(),
+                path.as_deref().unwrap_or("(unknown path)"),
+                error
+            );
+        }
+
+        Ok(Self {
+            cache_path: RwLock::new(cache_path),
+            cache_save_threshold,
+            modifications: AtomicU32::new(0),
+            save_lock: Mutex::new(()),
+            save_retries: 0,
+            save_retry_backoff: Duration::from_secs(0),
+            entries: RwLock::new(entries),
+            user_data: RwLock::new(user_data),
+            entry_info: RwLock::new(entry_info),
+            next_sequence: AtomicU64::new(next_sequence),
+            keyed_values: RwLock::new(keyed_values),
+            lazy_index: RwLock::new(lazy_index),
+            load_report,
+        })
+    }
+
+    /// Problems found while loading the cache file.
+    pub fn load_report(&self) -> &LoadReport {
+        &self.load_report
+    }
+
+    /// Retry failed saves up to `retries` more times, waiting `backoff` before the first retry and
+    /// doubling the wait after each retry.
+    pub fn set_save_retry(&mut self, retries: u32, backoff: Duration) {
+        self.save_retries = retries;
+        self.save_retry_backoff = backoff;
+    }
+
+    pub fn cache_path(&self) -> PathBuf {
+        self.cache_path.read().unwrap().clone()
+    }
+
+    /// Write the cache to disk. The cache is first written to a temporary file which is then
+    /// moved over the cache file, so that an interrupted save does not destroy the previous cache.
+    ///
+    /// The entries are serialized while holding the read lock, so every modification either
+    /// appears completely in the saved file or not at all. Concurrent saves are serialized.
+    pub fn save(&self) -> Result<(), FsCacheErrorKind> {
+        let _save_guard = self.lock_saves();
+        self.save_with_retry()
+    }
+
+    //Hold the save lock. It guards no data, only the cache file, so a save which panicked leaves nothing to repair
+    //and a poisoned lock can be used as normal.
+    fn lock_saves(&self) -> MutexGuard<'_, ()> {
+        self.save_lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    //Must be called while holding save_lock. Only save_lock is held while waiting to retry,
+    //so other threads can continue to modify the cache.
+    fn save_with_retry(&self) -> Result<(), FsCacheErrorKind> {
+        let cache_path = self.cache_path();
+        let mut attempt = 0;
+        let mut delay = self.save_retry_backoff;
+
+        loop {
+            match self.save_to(&cache_path) {
+                Err(e @ FsCacheErrorKind::CacheFileIo { .. }) if attempt < self.save_retries => {
+                    warn!(
+                        "Failed to save cache to {} (attempt {}), retrying: {}",
+                        cache_path.display(),
+                        attempt + 1,
+                        e
+                    );
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Save the cache to new_path, check that the new file can be read back, and then delete
+    /// the old cache file. If the new file cannot be written or read back, or the old cache file cannot be deleted,
+    /// then the new file is deleted, the cache continues to use the old cache file, and its modifications are still
+    /// unsaved.
+    pub fn move_to(&self, new_path: PathBuf) -> Result<(), FsCacheErrorKind> {
+        let _save_guard = self.lock_saves();
+
+        let old_path = self.cache_path();
+        if new_path == old_path {
+            return self.save_to(&new_path);
+        }
+
+        //The modifications are only saved once the cache has moved, so they are not forgotten if the move fails.
+        let saved_modifications = self.write_snapshot(&new_path)?;
+        let move_result = Self::read_entries(&new_path, true).and_then(|_| {
+            *self.cache_path.write().unwrap() = new_path.clone();
+            if old_path.exists() {
+                fs::remove_file(&old_path).map_err(|src| FsCacheErrorKind::io(&old_path, src))?;
+            }
+            Ok(())
+        });
+
+        if let Err(e) = move_result {
+            *self.cache_path.write().unwrap() = old_path;
+            let _ = fs::remove_file(&new_path);
+            return Err(e);
+        }
+
+        self.mark_saved(saved_modifications);
+        Ok(())
+    }
+
+    fn read_entries(cache_path: &Path, strict: bool) -> Result<(Entries<T>, Trailer<T>, LoadReport), FsCacheErrorKind> {
+        let io_err = |src| FsCacheErrorKind::io(cache_path, src);
+        let serialization_err = |src: bincode::Error| FsCacheErrorKind::serialization(cache_path, *src);
+
+        let file = File::open(cache_path).map_err(io_err)?;
+        let len = file.metadata().map_err(io_err)?.len();
+        let mut scanner = RecordScanner {
+            reader: BufReader::new(file),
+            pos: 0,
+            len,
+        };
+
+        match scanner.read_u64().map_err(io_err)? {
+            Some(FORMAT_MARKER) => {
+                let count = scanner
+                    .read_u64()
+                    .map_err(io_err)?
+                    .ok_or_else(|| serialization_err(io::Error::from(io::ErrorKind::UnexpectedEof).into()))?;
+                let (entries, trailer, report) = Self::read_records(&mut scanner, count).map_err(io_err)?;
+                check_strict(cache_path, &report, strict)?;
+                Ok((entries, trailer, report))
+            }
+            Some(FORMAT_MARKER_UNFRAMED) => {
+                let (entries, user_data) = bincode::deserialize_from(scanner.reader).map_err(serialization_err)?;
+                let trailer = Trailer {
+                    user_data,
+                    ..Trailer::default()
+                };
+                Ok((entries, trailer, LoadReport::default()))
+            }
+            Some(FORMAT_MARKER_NO_USER_DATA) => {
+                let entries = bincode::deserialize_from(scanner.reader).map_err(serialization_err)?;
+                Ok((entries, Trailer::default(), LoadReport::default()))
+            }
+            //Older cache files have no marker, so read the whole file again in the old format.
+            _ => {
+                let mut reader = scanner.reader;
+                reader.seek(SeekFrom::Start(0)).map_err(io_err)?;
+                let legacy_entries: Entries<T::Legacy> =
+                    bincode::deserialize_from(reader).map_err(serialization_err)?;
+                let entries = legacy_entries
+                    .into_iter()
+                    .map(|(key, FsCacheEntry { mtime, value })| {
+                        (
+                            key,
+                            FsCacheEntry {
+                                mtime,
+                                value: value.into(),
+                            },
+                        )
+                    })
+                    .collect();
+                Ok((entries, Trailer::default(), LoadReport::default()))
+            }
+        }
+    }
+
+    //Read the count records following FORMAT_MARKER and the record count, skipping entries which cannot be decoded.
+    //Only a file which cannot be read is an error.
+    fn read_records(scanner: &mut RecordScanner, count: u64) -> io::Result<(Entries<T>, Trailer<T>, LoadReport)> {
+        let mut entries = HashMap::new();
+        let mut report = LoadReport::default();
+        let mut quarantine = |path, error: String| report.quarantined.push(QuarantinedEntry { path, error });
+
+        for _ in 0..count {
+            let (key_bytes, value_bytes) = match (scanner.next_record()?, scanner.next_record()?) {
+                (Some(key_bytes), Some(value_bytes)) => (key_bytes, value_bytes),
+                //The records can no longer be found, so the rest of the file is lost.
+                _ => {
+                    quarantine(
+                        None,
+                        "The remainder of the cache file is truncated or unreadable".to_string(),
+                    );
+                    return Ok((entries, Trailer::default(), report));
+                }
+            };
+
+            let key: PathBuf = match bincode::deserialize(&key_bytes) {
+                Ok(key) => key,
+                Err(e) => {
+                    quarantine(None, e.to_string());
+                    continue;
+                }
+            };
+            match bincode::deserialize(&value_bytes) {
+                Ok(entry) => {
+                    entries.insert(key, entry);
+                }
+                Err(e) => quarantine(Some(key), e.to_string()),
+            }
+        }
+
+        //The trailing records are small compared to the values, so they are read in full.
+        let mut records = vec![];
+        while let Some(record) = scanner.next_record()? {
+            records.push(record);
+        }
+        let trailer = read_trailer(records.iter().map(Vec::as_slice), &mut quarantine);
+
+        Ok((entries, trailer, report))
+    }
+
+    //Read the keys of a cache file and the locations of their values, without reading the values. Returns None if
+    //the file is not in the current format, in which case it must be loaded with read_entries.
+    fn index_entries(
+        cache_path: &Path,
+        strict: bool,
+    ) -> Result<Option<(LazyIndex, Trailer<T>, LoadReport)>, FsCacheErrorKind> {
+        let io_err = |src| FsCacheErrorKind::io(cache_path, src);
+
+        let file = File::open(cache_path).map_err(io_err)?;
+        let len = file.metadata().map_err(io_err)?.len();
+        let mut scanner = RecordScanner {
+            reader: BufReader::new(file),
+            pos: 0,
+            len,
+        };
+
+        //If the header cannot be read, read_entries reports the problem.
+        if scanner.read_u64().map_err(io_err)? != Some(FORMAT_MARKER) {
+            return Ok(None);
+        }
+        let count = match scanner.read_u64().map_err(io_err)? {
+            Some(count) => count,
+            None => return Ok(None),
+        };
+
+        let mut offsets = HashMap::new();
+        let mut report = LoadReport::default();
+        let mut quarantine = |path, error: String| report.quarantined.push(QuarantinedEntry { path, error });
+
+        let mut truncated = false;
+        for _ in 0..count {
+            let key_bytes = match scanner.next_record_len().map_err(io_err)? {
+                Some(key_len) => scanner.read_record(key_len).map_err(io_err)?,
+                None => {
+                    truncated = true;
+                    break;
+                }
+            };
+            let value_offset = match scanner.next_record_len().map_err(io_err)? {
+                Some(value_len) => (scanner.skip_record(value_len).map_err(io_err)?, value_len),
+                None => {
+                    truncated = true;
+                    break;
+                }
+            };
+
+            match bincode::deserialize(&key_bytes) {
+                Ok(key) => {
+                    offsets.insert(key, value_offset);
+                }
+                Err(e) => quarantine(None, e.to_string()),
+            }
+        }
+
+        let trailer = if truncated {
+            quarantine(
+                None,
+                "The remainder of the cache file is truncated or unreadable".to_string(),
+            );
+            Trailer::default()
+        } else {
+            //The trailing records are small compared to the values, so they are read in full.
+            let mut records = vec![];
+            while let Some(record) = scanner.next_record().map_err(io_err)? {
+                records.push(record);
+            }
+            read_trailer(records.iter().map(Vec::as_slice), &mut quarantine)
+        };
+
+        check_strict(cache_path, &report, strict)?;
+
+        let lazy_index = LazyIndex {
+            file: Mutex::new(scanner.reader.into_inner()),
+            offsets,
+        };
+        Ok(Some((lazy_index, trailer, report)))
+    }
+
+    //Load every value of a lazily opened cache into memory, so that the cache can be modified or saved. Values which
+    //cannot be decoded are skipped. Does nothing if the values are already in memory.
+    fn materialize(&self) -> Result<(), FsCacheErrorKind> {
+        let mut lazy_index = self.lazy_index.write().unwrap();
+        let index = match &*lazy_index {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        let cache_path = self.cache_path();
+
+        //Read the values in the order they are stored in the file. If the file cannot be read then the cache stays
+        //lazy, with none of the values loaded, so that it can be tried again.
+        let mut keys = index.offsets.iter().collect::<Vec<_>>();
+        keys.sort_by_key(|(_key, offset)| **offset);
+        let mut loaded = HashMap::with_capacity(keys.len());
+        for (key, _offset) in keys {
+            match index.read_entry(key, &cache_path) {
+                Ok(entry) => {
+                    loaded.insert(key.clone(), entry);
+                }
+                Err(e @ FsCacheErrorKind::CacheFileIo { .. }) => return Err(e),
+                Err(e) => warn!(
+                    "Skipped corrupt entry in cache {}: {}: {}",
+                    cache_path.display(),
+                    key.display(),
+                    e
+                ),
+            }
+        }
+
+        self.entries.write().unwrap().extend(loaded);
+        *lazy_index = None;
+        Ok(())
+    }
+
+    fn write_records(
+        entries: &Entries<T>,
+        user_data: &UserData,
+        entry_info: &HashMap<PathBuf, EntryInfo>,
+        keyed_values: &KeyedValues<T>,
+    ) -> bincode::Result<Vec<u8>> {
+        let mut buf = vec![];
+        bincode::serialize_into(&mut buf, &FORMAT_MARKER)?;
+        bincode::serialize_into(&mut buf, &(entries.len() as u64))?;
+        for (key, entry) in entries {
+            write_record(&mut buf, key)?;
+            write_record(&mut buf, entry)?;
+        }
+        write_record(&mut buf, user_data)?;
+        write_record(
+            &mut buf,
+            &InfoRecord {
+                info: entry_info,
+                field: EntryInfo::meta,
+            },
+        )?;
+        write_record(&mut buf, keyed_values)?;
+        write_record(
+            &mut buf,
+            &InfoRecord {
+                info: entry_info,
+                field: |info| info.sequence,
+            },
+        )?;
+
+        Ok(buf)
+    }
+
+    //Must be called while holding save_lock.
+    fn save_to(&self, cache_path: &Path) -> Result<(), FsCacheErrorKind> {
+        let saved_modifications = self.write_snapshot(cache_path)?;
+        self.mark_saved(saved_modifications);
+        Ok(())
+    }
+
+    //Forget the given number of modifications, which have been saved. Modifications made since the snapshot was taken
+    //are still unsaved.
+    fn mark_saved(&self, saved_modifications: u32) {
+        let _ = self
+            .modifications
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |modifications| {
+                Some(modifications.saturating_sub(saved_modifications))
+            });
+    }
+
+    //Write the cache to cache_path, and return the number of modifications included in what was written. Must be
+    //called while holding save_lock.
+    fn write_snapshot(&self, cache_path: &Path) -> Result<u32, FsCacheErrorKind> {
+        let mut tmp_path = cache_path.to_path_buf().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let io_err = |src| FsCacheErrorKind::io(cache_path, src);
+
+        self.materialize()?;
+        let (snapshot, saved_modifications) = {
+            let entries = self.entries.read().unwrap();
+            let user_data = self.user_data.read().unwrap();
+            let entry_info = self.entry_info.read().unwrap();
+            let keyed_values = self.keyed_values.read().unwrap();
+            let snapshot = Self::write_records(&entries, &user_data, &entry_info, &keyed_values)
+                .map_err(|src| FsCacheErrorKind::serialization(cache_path, *src))?;
+            (snapshot, self.modifications.load(Ordering::SeqCst))
+        };
+
+        let write_result = (|| {
+            let mut writer = BufWriter::new(File::create(&tmp_path).map_err(io_err)?);
+            writer.write_all(&snapshot).map_err(io_err)?;
+            writer.flush().map_err(io_err)?;
+            drop(writer);
+
+            fs::rename(&tmp_path, cache_path).map_err(io_err)
+        })();
+
+        if write_result.is_ok() {
+            cache_metrics::record_save(snapshot.len());
+        }
+
+        #[cfg(feature = "tracing")]
+        match &write_result {
+            Ok(()) => tracing::info!(path = %cache_path.display(), bytes = snapshot.len(), "saved cache"),
+            Err(e) => tracing::warn!(path = %cache_path.display(), error = %e, "failed to save cache"),
+        }
+
+        write_result.map(|()| saved_modifications)
+    }
+
+    /// The number of modifications since the cache was last saved.
+    pub fn pending_saves(&self) -> u32 {
+        self.modifications.load(Ordering::SeqCst)
+    }
+
+    /// Forget about modifications since the last save, without saving.
+    pub fn flush_without_save(&self) {
+        self.modifications.store(0, Ordering::SeqCst);
+    }
+
+    pub fn contains_key(&self, key: &Path) -> bool {
+        if let Some(index) = &*self.lazy_index.read().unwrap() {
+            return index.offsets.contains_key(key);
+        }
+        self.entries.read().unwrap().contains_key(key)
+    }
+
+    /// The number of ``keys`` which are in the cache, found while holding the read lock once.
+    pub fn count_contained(&self, keys: &[PathBuf]) -> usize {
+        if let Some(index) = &*self.lazy_index.read().unwrap() {
+            return keys.iter().filter(|key| index.offsets.contains_key(*key)).count();
+        }
+        let entries = self.entries.read().unwrap();
+        keys.iter().filter(|key| entries.contains_key(*key)).count()
+    }
+
+    pub fn keys(&self) -> Vec<PathBuf> {
+        if let Some(index) = &*self.lazy_index.read().unwrap() {
+            return index.offsets.keys().cloned().collect();
+        }
+        self.entries.read().unwrap().keys().cloned().collect()
+    }
+
+    /// The keys of the entries whose values satisfy ``f``, found while holding the read lock once. If the cache was
+    /// opened lazily, all values are loaded into memory first.
+    pub fn keys_where(&self, f: impl Fn(&T) -> bool) -> Vec<PathBuf> {
+        self.materialize_or_warn();
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_key, entry)| f(&entry.value))
+            .map(|(key, _entry)| key.clone())
+            .collect()
+    }
+
+    /// A copy of all entries in the cache. If the cache was opened lazily, all values are loaded into memory first.
+    pub fn entries(&self) -> Vec<(PathBuf, FsCacheEntry<T>)> {
+        self.materialize_or_warn();
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// All entries, in the order they were first inserted.
+    pub fn entries_in_order(&self) -> Vec<(PathBuf, FsCacheEntry<T>)> {
+        self.materialize_or_warn();
+        let entries = self.entries.read().unwrap();
+        let entry_info = self.entry_info.read().unwrap();
+        let sequence = |key: &Path| entry_info.get(key).map_or(u64::MAX, |info| info.sequence);
+
+        entries
+            .iter()
+            .sorted_by(|(key_1, _), (key_2, _)| sequence(key_1).cmp(&sequence(key_2)).then_with(|| key_1.cmp(key_2)))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    pub fn fetch(&self, key: &Path) -> Result<T, FsCacheErrorKind> {
+        self.fetch_entry(key).map(|entry| entry.value)
+    }
+
+    pub fn fetch_entry(&self, key: &Path) -> Result<FsCacheEntry<T>, FsCacheErrorKind> {
+        if let Some(index) = &*self.lazy_index.read().unwrap() {
+            return index.read_entry(key, &self.cache_path());
+        }
+        self.entries
+            .read()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| FsCacheErrorKind::KeyMissing(key.to_path_buf()))
+    }
+
+    pub fn insert(&self, key: PathBuf, mtime: SystemTime, value: T) -> Result<(), FsCacheErrorKind> {
+        self.insert_many(std::iter::once((key, FsCacheEntry { mtime, value })))
+            .map(|_| ())
+    }
+
+    /// Insert many entries while taking the write lock only once. Returns the number of entries inserted.
+    pub fn insert_many(
+        &self,
+        new_entries: impl IntoIterator<Item = (PathBuf, FsCacheEntry<T>)>,
+    ) -> Result<usize, FsCacheErrorKind> {
+        self.materialize()?;
+        let count = {
+            let mut entries = self.entries.write().unwrap();
+            let mut entry_info = self.entry_info.write().unwrap();
+            let now = SystemTime::now();
+            let mut count = 0;
+            for (key, entry) in new_entries {
+                entry_info
+                    .entry(key.clone())
+                    .and_modify(|info| info.touch(now))
+                    .or_insert_with(|| {
+                        EntryInfo::new(EntryMeta::new(now), self.next_sequence.fetch_add(1, Ordering::SeqCst))
+                    });
+                entries.insert(key, entry);
+                count += 1;
+            }
+            count
+        };
+
+        self.record_modifications(count)?;
+        Ok(count)
+    }
+
+    /// Replace the key and value of every entry with ``f(key, value)``, removing entries for which ``f`` returns None.
+    /// If several entries end up with the same key, the entry with the newest mtime is kept.
+    /// Returns the number of entries which were renamed or removed.
+    pub fn map_keys(&self, f: impl Fn(&Path, T) -> Option<(PathBuf, T)>) -> Result<usize, FsCacheErrorKind> {
+        self.materialize()?;
+        let count = {
+            let mut entries = self.entries.write().unwrap();
+            let mut user_data = self.user_data.write().unwrap();
+            let mut entry_info = self.entry_info.write().unwrap();
+
+            //sort the old entries so that the result of a collision with equal mtimes is deterministic.
+            let mut old_entries = entries.drain().collect::<Vec<_>>();
+            old_entries.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+            let mut old_user_data = std::mem::take(&mut *user_data);
+            let mut old_entry_info = std::mem::take(&mut *entry_info);
+
+            let mut count = 0;
+            for (old_key, FsCacheEntry { mtime, value }) in old_entries {
+                let (new_key, entry) = match f(&old_key, value) {
+                    Some((new_key, value)) => (new_key, FsCacheEntry { mtime, value }),
+                    None => {
+                        count += 1;
+                        continue;
+                    }
+                };
+
+                if new_key != old_key {
+                    count += 1;
+                }
+
+                //User data and entry times follow the entry they belong to.
+                match entries.get(&new_key) {
+                    Some(existing) if existing.mtime >= entry.mtime => {}
+                    _ => {
+                        replace_or_remove(&mut user_data, new_key.clone(), old_user_data.remove(&old_key));
+                        replace_or_remove(&mut entry_info, new_key.clone(), old_entry_info.remove(&old_key));
+                        entries.insert(new_key, entry);
+                    }
+                }
+            }
+            count
+        };
+
+        self.record_modifications(count)?;
+        Ok(count)
+    }
+
+    /// Move the entry at ``old_key`` (along with its user data and entry times) to ``new_key``, replacing its value
+    /// with ``value``. The mtime of the entry is kept. Returns KeyMissing if ``old_key`` is not in the cache, and
+    /// KeyExists if ``new_key`` is already in the cache.
+    pub fn rename(&self, old_key: &Path, new_key: PathBuf, value: T) -> Result<(), FsCacheErrorKind> {
+        self.materialize()?;
+        {
+            let mut entries = self.entries.write().unwrap();
+            let mut user_data = self.user_data.write().unwrap();
+            let mut entry_info = self.entry_info.write().unwrap();
+
+            if entries.contains_key(&new_key) {
+                return Err(FsCacheErrorKind::KeyExists(new_key));
+            }
+            let mtime = match entries.remove(old_key) {
+                Some(entry) => entry.mtime,
+                None => return Err(FsCacheErrorKind::KeyMissing(old_key.to_path_buf())),
+            };
+
+            let old_user_data = user_data.remove(old_key);
+            replace_or_remove(&mut user_data, new_key.clone(), old_user_data);
+            let old_entry_info = entry_info.remove(old_key);
+            replace_or_remove(&mut entry_info, new_key.clone(), old_entry_info);
+            entries.insert(new_key, FsCacheEntry { mtime, value });
+        }
+
+        self.record_modifications(1)
+    }
+
+    /// Modify every value in the cache in place. ``f`` is called with the key and value of each entry,
+    /// and returns true if it changed the value. Returns the number of values changed.
+    pub fn modify_values(&self, f: impl Fn(&Path, &mut T) -> bool) -> Result<usize, FsCacheErrorKind> {
+        self.materialize()?;
+        let count = self
+            .entries
+            .write()
+            .unwrap()
+            .iter_mut()
+            .filter_map(|(key, entry)| f(key, &mut entry.value).then_some(()))
+            .count();
+
+        self.record_modifications(count)?;
+        Ok(count)
+    }
+
+    /// The total number of entries, and the number of entries whose values match ``f``.
+    #[cfg(feature = "metrics")]
+    pub fn count_values(&self, f: impl Fn(&T) -> bool) -> (usize, usize) {
+        self.materialize_or_warn();
+        let entries = self.entries.read().unwrap();
+        (entries.len(), entries.values().filter(|entry| f(&entry.value)).count())
+    }
+
+    /// Release any excess capacity held by the in-memory maps.
+    pub fn shrink_to_fit(&self) {
+        let mut entries = self.entries.write().unwrap();
+        let mut user_data = self.user_data.write().unwrap();
+        let mut entry_info = self.entry_info.write().unwrap();
+        entries.shrink_to_fit();
+        user_data.shrink_to_fit();
+        entry_info.shrink_to_fit();
+        self.keyed_values.write().unwrap().shrink_to_fit();
+    }
+
+    /// A rough estimate of the number of bytes used by the in-memory maps. Space used by each entry outside of the
+    /// map is estimated from a sample of the entries, with values approximated by their serialized size, so the
+    /// estimate is cheap even for large caches. Each map is only locked while it is measured.
+    pub fn memory_usage_estimate(&self) -> usize {
+        let lazy_index_size = match &*self.lazy_index.read().unwrap() {
+            Some(index) => {
+                index.offsets.capacity() * std::mem::size_of::<(PathBuf, (u64, u64))>()
+                    + sampled_heap_size(index.offsets.keys(), |key| key.capacity())
+            }
+            None => 0,
+        };
+
+        let entries_size = {
+            let entries = self.entries.read().unwrap();
+            entries.capacity() * std::mem::size_of::<(PathBuf, FsCacheEntry<T>)>()
+                + sampled_heap_size(entries.iter(), |(key, entry)| {
+                    key.capacity() + bincode::serialized_size(&entry.value).unwrap_or_default() as usize
+                })
+        };
+
+        let user_data_size = {
+            let user_data = self.user_data.read().unwrap();
+            user_data.capacity() * std::mem::size_of::<(PathBuf, BTreeMap<String, String>)>()
+                + sampled_heap_size(user_data.iter(), |(key, data)| {
+                    key.capacity()
+                        + data
+                            .iter()
+                            .map(|(name, value)| name.capacity() + value.capacity())
+                            .sum::<usize>()
+                })
+        };
+
+        let entry_info_size = {
+            let entry_info = self.entry_info.read().unwrap();
+            entry_info.capacity() * std::mem::size_of::<(PathBuf, EntryInfo)>()
+                + sampled_heap_size(entry_info.keys(), |key| key.capacity())
+        };
+
+        let keyed_values_size = {
+            let keyed_values = self.keyed_values.read().unwrap();
+            keyed_values.capacity() * std::mem::size_of::<(String, T)>()
+                + sampled_heap_size(keyed_values.iter(), |(key, value)| {
+                    key.capacity() + bincode::serialized_size(value).unwrap_or_default() as usize
+                })
+        };
+
+        lazy_index_size + entries_size + user_data_size + entry_info_size + keyed_values_size
+    }
+
+    /// Set the user data called name for the entry at key. The entry must exist.
+    pub fn set_user_data(&self, key: &Path, name: &str, value: String) -> Result<(), FsCacheErrorKind> {
+        self.materialize()?;
+        {
+            let entries = self.entries.read().unwrap();
+            if !entries.contains_key(key) {
+                return Err(FsCacheErrorKind::KeyMissing(key.to_path_buf()));
+            }
+            self.user_data
+                .write()
+                .unwrap()
+                .entry(key.to_path_buf())
+                .or_default()
+                .insert(name.to_string(), value);
+        }
+
+        self.record_modifications(1)
+    }
+
+    pub fn get_user_data(&self, key: &Path, name: &str) -> Option<String> {
+        self.user_data.read().unwrap().get(key)?.get(name).cloned()
+    }
+
+    //All user data for the entry at key, by name.
+    pub fn all_user_data(&self, key: &Path) -> BTreeMap<String, String> {
+        self.user_data.read().unwrap().get(key).cloned().unwrap_or_default()
+    }
+
+    //A copy of the user data of every entry, by key and then by name.
+    #[cfg(any(feature = "csv", feature = "ron", feature = "sqlite"))]
+    pub fn user_data_map(&self) -> HashMap<PathBuf, BTreeMap<String, String>> {
+        self.user_data.read().unwrap().clone()
+    }
+
+    /// Add user data to existing entries, replacing any values with the same names. Data for keys which are not in
+    /// the cache is ignored. Each entry whose data was changed counts as one modification.
+    #[cfg(any(feature = "csv", feature = "ron", feature = "sqlite"))]
+    pub fn extend_user_data(
+        &self,
+        data: impl IntoIterator<Item = (PathBuf, BTreeMap<String, String>)>,
+    ) -> Result<(), FsCacheErrorKind> {
+        self.materialize()?;
+        let count = {
+            let entries = self.entries.read().unwrap();
+            let mut user_data = self.user_data.write().unwrap();
+            let mut count = 0;
+            for (key, values) in data {
+                if entries.contains_key(&key) && !values.is_empty() {
+                    user_data.entry(key).or_default().extend(values);
+                    count += 1;
+                }
+            }
+            count
+        };
+
+        self.record_modifications(count)
+    }
+
+    /// When the entry at key was created and last accessed.
+    pub fn entry_meta(&self, key: &Path) -> Option<EntryMeta> {
+        self.entry_info.read().unwrap().get(key).map(EntryInfo::meta)
+    }
+
+    /// Record that the entry at key was accessed now. Only a read lock is taken, so that concurrent fetches do not
+    /// wait for each other. This does not count as a modification (it is not included in
+    /// [pending_saves][Self::pending_saves]), so the new time is only written when the cache is next saved.
+    pub fn touch(&self, key: &Path) {
+        if let Some(info) = self.entry_info.read().unwrap().get(key) {
+            info.touch(SystemTime::now());
+        }
+    }
+
+    /// Store value under an arbitrary key which is not a path. Keyed values are separate from the entries, and are
+    /// not changed by any other method.
+    pub fn insert_keyed(&self, key: String, value: T) -> Result<(), FsCacheErrorKind> {
+        self.keyed_values.write().unwrap().insert(key, value);
+        self.record_modifications(1)
+    }
+
+    pub fn fetch_keyed(&self, key: &str) -> Option<T> {
+        self.keyed_values.read().unwrap().get(key).cloned()
+    }
+
+    /// Remove the value stored under key, returning true if it existed.
+    pub fn remove_keyed(&self, key: &str) -> Result<bool, FsCacheErrorKind> {
+        let removed = self.keyed_values.write().unwrap().remove(key).is_some();
+        if removed {
+            self.record_modifications(1)?;
+        }
+        Ok(removed)
+    }
+
+    pub fn keyed_keys(&self) -> Vec<String> {
+        self.keyed_values.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Remove the user data called name for the entry at key, returning its value if it existed.
+    pub fn remove_user_data(&self, key: &Path, name: &str) -> Result<Option<String>, FsCacheErrorKind> {
+        let removed = {
+            let mut user_data = self.user_data.write().unwrap();
+            let removed = user_data.get_mut(key).and_then(|data| data.remove(name));
+            if user_data.get(key).is_some_and(BTreeMap::is_empty) {
+                user_data.remove(key);
+            }
+            removed
+        };
+
+        if removed.is_some() {
+            self.record_modifications(1)?;
+        }
+        Ok(removed)
+    }
+
+    /// Remove the entry at key, along with its user data.
+    pub fn remove(&self, key: &Path) -> Result<(), FsCacheErrorKind> {
+        self.materialize()?;
+        let removed = {
+            let mut entries = self.entries.write().unwrap();
+            self.user_data.write().unwrap().remove(key);
+            self.entry_info.write().unwrap().remove(key);
+            entries.remove(key)
+        };
+
+        #[cfg(feature = "tracing")]
+        if removed.is_some() {
+            tracing::debug!(path = %key.display(), "removed entry");
+        }
+
+        match removed {
+            Some(_) => self.record_modifications(1),
+            None => Err(FsCacheErrorKind::KeyMissing(key.to_path_buf())),
+        }
+    }
+
+    /// Remove many entries (and their user data) while taking the write lock only once. Keys which are not in the
+    /// cache are ignored. Returns the number of entries removed.
+    pub fn remove_many(&self, keys: impl IntoIterator<Item = PathBuf>) -> Result<usize, FsCacheErrorKind> {
+        self.materialize()?;
+        let count = {
+            let mut entries = self.entries.write().unwrap();
+            let mut user_data = self.user_data.write().unwrap();
+            let mut entry_info = self.entry_info.write().unwrap();
+            keys.into_iter()
+                .filter(|key| {
+                    user_data.remove(key);
+                    entry_info.remove(key);
+                    entries.remove(key).is_some()
+                })
+                .count()
+        };
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(count, "removed entries");
+
+        self.record_modifications(count)?;
+        Ok(count)
+    }
+
+    /// Remove all entries (and all user data) while taking the write lock only once, and return the entries.
+    pub fn drain(&self) -> Result<Entries<T>, FsCacheErrorKind> {
+        self.materialize()?;
+        let drained = {
+            let mut entries = self.entries.write().unwrap();
+            self.user_data.write().unwrap().clear();
+            self.entry_info.write().unwrap().clear();
+            std::mem::take(&mut *entries)
+        };
+
+        self.record_modifications(drained.len())?;
+        Ok(drained)
+    }
+
+    //For operations which cannot return an error. If the values cannot be loaded, the operation sees only the values
+    //which were loaded before the failure.
+    fn materialize_or_warn(&self) {
+        if let Err(e) = self.materialize() {
+            warn!(
+                "Failed to load lazily opened cache {}: {}",
+                self.cache_path().display(),
+                e
+            );
+        }
+    }
+
+    //Saves the cache if the number of modifications since the last save reaches the threshold.
+    fn record_modifications(&self, count: usize) -> Result<(), FsCacheErrorKind> {
+        let count = count as u32;
+        let modifications = self.modifications.fetch_add(count, Ordering::SeqCst) + count;
+        if count > 0 && modifications >= self.cache_save_threshold {
+            //If another thread is already saving then don't wait for it. If its snapshot
+            //did not include these modifications, the next modification will trigger another save.
+            match self.save_lock.try_lock() {
+                Ok(_save_guard) => self.save_with_retry()?,
+                Err(TryLockError::WouldBlock) => (),
+                Err(TryLockError::Poisoned(e)) => {
+                    let _save_guard = e.into_inner();
+                    self.save_with_retry()?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//The number of items measured by sampled_heap_size.
+const MEMORY_SAMPLE_SIZE: usize = 64;
+
+//Estimate the total of heap_size over all items from the first MEMORY_SAMPLE_SIZE items.
+fn sampled_heap_size<I: ExactSizeIterator>(items: I, heap_size: impl Fn(I::Item) -> usize) -> usize {
+    let count = items.len();
+    let sample = items.take(MEMORY_SAMPLE_SIZE).map(heap_size).collect::<Vec<_>>();
+    match sample.len() {
+        0 => 0,
+        sample_len => sample.iter().sum::<usize>() * count / sample_len,
+    }
+}
+
+fn check_strict(cache_path: &Path, report: &LoadReport, strict: bool) -> Result<(), FsCacheErrorKind> {
+    match report.quarantined.first() {
+        Some(QuarantinedEntry { error, .. }) if strict => Err(FsCacheErrorKind::Serialization {
+            path: cache_path.to_path_buf(),
+            src: error.clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+//Store the data of an entry which has been moved to key, or remove any data left at key by the entry being replaced.
+fn replace_or_remove<V>(map: &mut HashMap<PathBuf, V>, key: PathBuf, value: Option<V>) {
+    match value {
+        Some(value) => map.insert(key, value),
+        None => map.remove(&key),
+    };
+}
+
+//The times and insertion order of the entries at keys, read from the records of a cache file, and the position to give
+//to the next new entry. Entries without a position (because the cache file was written before positions were stored)
+//are placed after all other entries, in order of creation time and then path.
+fn load_entry_info(
+    keys: Vec<&PathBuf>,
+    mut entry_times: EntryTimes,
+    mut insertion_order: InsertionOrder,
+) -> (HashMap<PathBuf, EntryInfo>, u64) {
+    let now = SystemTime::now();
+    let (ordered, unordered): (Vec<_>, Vec<_>) = keys
+        .into_iter()
+        .map(|key| {
+            let meta = entry_times.remove(key).unwrap_or_else(|| EntryMeta::new(now));
+            (key, meta, insertion_order.remove(key))
+        })
+        .partition(|(_key, _meta, sequence)| sequence.is_some());
+
+    let mut entry_info = HashMap::with_capacity(ordered.len() + unordered.len());
+    let mut next_sequence = 0;
+    for (key, meta, sequence) in ordered {
+        let sequence = sequence.unwrap();
+        next_sequence = next_sequence.max(sequence.saturating_add(1));
+        entry_info.insert(key.clone(), EntryInfo::new(meta, sequence));
+    }
+    for (key, meta, _sequence) in unordered
+        .into_iter()
+        .sorted_by(|(key_1, meta_1, _), (key_2, meta_2, _)| (meta_1.created_at, key_1).cmp(&(meta_2.created_at, key_2)))
+    {
+        entry_info.insert(key.clone(), EntryInfo::new(meta, next_sequence));
+        next_sequence += 1;
+    }
+
+    (entry_info, next_sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicBool, time::UNIX_EPOCH};
+
+    use super::*;
+
+    impl CacheValue for String {
+        type Legacy = String;
+    }
+
+    fn open(cache_path: &Path) -> BaseFsCache<String> {
+        BaseFsCache::new(1000, cache_path.to_path_buf(), None, true, false).unwrap()
+    }
+
+    fn keys_in_order(cache: &BaseFsCache<String>) -> Vec<PathBuf> {
+        cache.entries_in_order().into_iter().map(|(key, _entry)| key).collect()
+    }
+
+    #[test]
+    fn concurrent_saves_never_write_part_of_an_insertion() {
+        const BATCH_SIZE: usize = 10;
+        let tree = TestTree::new();
+        let cache_path = tree.path("cache.bin");
+        let cache = open(&cache_path);
+        cache.save().unwrap();
+        let writing = AtomicBool::new(true);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for batch in 0..100 {
+                    let entries = (0..BATCH_SIZE).map(|i| {
+                        let key = format!("/{}/{}", batch, i);
+                        (
+                            PathBuf::from(&key),
+                            FsCacheEntry {
+                                mtime: UNIX_EPOCH,
+                                value: key,
+                            },
+                        )
+                    });
+                    cache.insert_many(entries).unwrap();
+                }
+                writing.store(false, Ordering::SeqCst);
+            });
+
+            while writing.load(Ordering::SeqCst) {
+                cache.save().unwrap();
+                let saved = open(&cache_path);
+                assert_eq!(saved.keys().len() % BATCH_SIZE, 0);
+            }
+        });
+
+        cache.save().unwrap();
+        assert_eq!(open(&cache_path).keys().len(), 100 * BATCH_SIZE);
+    }
+
+    #[test]
+    fn saves_succeed_after_a_panicked_save() {
+        let tree = TestTree::new();
+        let cache_path = tree.path("cache.bin");
+        let cache = BaseFsCache::<String>::new(1, cache_path.clone(), None, true, false).unwrap();
+
+        let panicked = thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _save_guard = cache.lock_saves();
+                    panic!("save panicked");
+                })
+                .join()
+                .is_err()
+        });
+        assert!(panicked);
+        assert!(cache.save_lock.is_poisoned());
+
+        //Reaching the save threshold saves through the poisoned lock.
+        cache.insert(PathBuf::from("/a"), UNIX_EPOCH, "a".to_string()).unwrap();
+        assert_eq!(open(&cache_path).keys(), vec![PathBuf::from("/a")]);
+        cache.save().unwrap();
+    }
+
+    #[test]
+    fn cache_files_written_by_generic_filesystem_cache_are_loaded() {
+        //Saved by generic_filesystem_cache 0.1.0, caching the file name of each of the files /gfc_fixture/a.mp4
+        //and /gfc_fixture/b.mp4, which were modified 1000 and 2000 seconds after the epoch.
+        let fixture = include_bytes!("../tests/fixtures/generic_filesystem_cache-0.1.0.bin");
+        let tree = TestTree::new();
+        let cache_path = tree.path("cache.bin");
+        fs::write(&cache_path, fixture).unwrap();
+
+        let check_entries = |cache: &BaseFsCache<String>| {
+            for (name, secs) in [("a.mp4", 1000), ("b.mp4", 2000)] {
+                let entry = cache.fetch_entry(&Path::new("/gfc_fixture").join(name)).unwrap();
+                assert_eq!(entry.mtime, UNIX_EPOCH + Duration::from_secs(secs));
+                assert_eq!(entry.value, name);
+            }
+            assert_eq!(cache.keys().len(), 2);
+        };
+        let cache = open(&cache_path);
+        check_entries(&cache);
+
+        //The next save rewrites the file in the current format.
+        cache.save().unwrap();
+        assert_ne!(fs::read(&cache_path).unwrap(), fixture);
+        check_entries(&open(&cache_path));
+    }
+
+    #[test]
+    fn entries_in_order_follows_insertion_across_reloads() {
+        let tree = TestTree::new();
+        let cache = open(&tree.path("cache.bin"));
+        let before = SystemTime::now();
+        for key in ["/c", "/a", "/b"] {
+            cache.insert(PathBuf::from(key), UNIX_EPOCH, key.to_string()).unwrap();
+        }
+        //Replacing an entry does not move it.
+        cache.insert(PathBuf::from("/c"), UNIX_EPOCH, "c2".to_string()).unwrap();
+
+        let expected = vec![PathBuf::from("/c"), PathBuf::from("/a"), PathBuf::from("/b")];
+        assert_eq!(keys_in_order(&cache), expected);
+
+        //Creation times are the real time of insertion.
+        let created_at = cache.entry_meta(Path::new("/a")).unwrap().created_at;
+        assert!(before <= created_at && created_at <= SystemTime::now());
+
+        cache.save().unwrap();
+        let cache = open(&tree.path("cache.bin"));
+        assert_eq!(keys_in_order(&cache), expected);
+        assert_eq!(cache.entry_meta(Path::new("/a")).unwrap().created_at, created_at);
+
+        cache.insert(PathBuf::from("/0"), UNIX_EPOCH, "0".to_string()).unwrap();
+        assert_eq!(keys_in_order(&cache).last().unwrap(), Path::new("/0"));
+    }
+
+    #[test]
+    fn touch_is_saved_without_counting_as_a_modification() {
+        let tree = TestTree::new();
+        let cache = open(&tree.path("cache.bin"));
+        let key = Path::new("/a");
+        cache.insert(key.to_path_buf(), UNIX_EPOCH, "a".to_string()).unwrap();
+        cache.save().unwrap();
+        let created = cache.entry_meta(key).unwrap();
+
+        thread::sleep(Duration::from_millis(10));
+        cache.touch(key);
+        let touched = cache.entry_meta(key).unwrap();
+        assert_eq!(touched.created_at, created.created_at);
+        assert!(touched.last_accessed > created.last_accessed);
+        assert_eq!(cache.pending_saves(), 0);
+
+        cache.save().unwrap();
+        assert_eq!(open(&tree.path("cache.bin")).entry_meta(key), Some(touched));
+    }
+
+    #[test]
+    fn move_to_saves_and_removes_old_file() {
+        let tree = TestTree::new();
+        let cache = open(&tree.path("old.bin"));
+        cache.insert(PathBuf::from("/a"), UNIX_EPOCH, "a".to_string()).unwrap();
+        cache.save().unwrap();
+        cache.insert(PathBuf::from("/b"), UNIX_EPOCH, "b".to_string()).unwrap();
+
+        cache.move_to(tree.path("new.bin")).unwrap();
+
+        assert_eq!(cache.pending_saves(), 0);
+        assert_eq!(cache.cache_path(), tree.path("new.bin"));
+        assert!(!tree.path("old.bin").exists());
+        assert_eq!(open(&tree.path("new.bin")).keys().len(), 2);
+    }
+
+    #[test]
+    fn failed_move_to_keeps_old_file_and_modifications() {
+        let tree = TestTree::new();
+        let cache = open(&tree.path("old.bin"));
+        cache.insert(PathBuf::from("/a"), UNIX_EPOCH, "a".to_string()).unwrap();
+
+        assert!(cache.move_to(tree.path("missing_dir/new.bin")).is_err());
+
+        assert_eq!(cache.pending_saves(), 1);
+        assert_eq!(cache.cache_path(), tree.path("old.bin"));
+        assert!(!tree.path("missing_dir/new.bin").exists());
+    }
+
+    #[test]
+    fn corrupt_entries_are_quarantined_unless_strict() {
+        let tree = TestTree::new();
+        let cache_path = tree.path("cache.bin");
+        let cache = open(&cache_path);
+        cache
+            .insert(PathBuf::from("/a"), UNIX_EPOCH, "value-a".to_string())
+            .unwrap();
+        cache
+            .insert(PathBuf::from("/b"), UNIX_EPOCH, "value-b".to_string())
+            .unwrap();
+        cache.save().unwrap();
+
+        //Make the value of /b invalid UTF-8.
+        let mut contents = fs::read(&cache_path).unwrap();
+        let pos = contents.windows(7).position(|window| window == b"value-b").unwrap();
+        contents[pos] = 0xff;
+        fs::write(&cache_path, contents).unwrap();
+
+        assert!(BaseFsCache::<String>::new(1000, cache_path.clone(), None, true, false).is_err());
+        let cache = BaseFsCache::<String>::new(1000, cache_path, None, false, false).unwrap();
+        assert_eq!(cache.keys(), vec![PathBuf::from("/a")]);
+        assert_eq!(cache.load_report().quarantined.len(), 1);
+        assert_eq!(
+            cache.load_report().quarantined[0].path.as_deref(),
+            Some(Path::new("/b"))
+        );
+    }
+
+    #[test]
+    fn failed_materialize_stays_lazy() {
+        let tree = TestTree::new();
+        let cache_path = tree.path("cache.bin");
+        let cache = open(&cache_path);
+        for key in ["/a", "/b", "/c"] {
+            cache.insert(PathBuf::from(key), UNIX_EPOCH, key.to_string()).unwrap();
+        }
+        cache.save().unwrap();
+        let contents = fs::read(&cache_path).unwrap();
+
+        let cache = BaseFsCache::<String>::new(1000, cache_path.clone(), None, true, true).unwrap();
+        //Cut the file off where the last value starts, so that it cannot be read.
+        let last_offset = {
+            let lazy_index = cache.lazy_index.read().unwrap();
+            let offsets = lazy_index.as_ref().unwrap().offsets.values();
+            offsets.map(|(offset, _len)| *offset).max().unwrap()
+        };
+        fs::write(&cache_path, &contents[..last_offset as usize]).unwrap();
+        assert!(cache.materialize().is_err());
+        assert!(cache.entries.read().unwrap().is_empty());
+        assert!(cache.contains_key(Path::new("/c")));
+
+        fs::write(&cache_path, &contents).unwrap();
+        assert_eq!(cache.keys_where(|_value| true).len(), 3);
+    }
+
+    #[test]
+    fn entries_without_insertion_order_are_placed_last() {
+        let tree = TestTree::new();
+        let cache_path = tree.path("cache.bin");
+        let time = |secs| EntryMeta::new(UNIX_EPOCH + Duration::from_secs(secs));
+
+        //A cache file written before the insertion order was stored, which ends after the keyed values.
+        let entries = ["/a", "/b", "/c"]
+            .iter()
+            .map(|key| {
+                (
+                    PathBuf::from(key),
+                    FsCacheEntry {
+                        mtime: UNIX_EPOCH,
+                        value: key.to_string(),
+                    },
+                )
+            })
+            .collect::<Entries<String>>();
+        let entry_times = vec![
+            (PathBuf::from("/a"), time(3)),
+            (PathBuf::from("/b"), time(1)),
+            (PathBuf::from("/c"), time(1)),
+        ]
+        .into_iter()
+        .collect::<EntryTimes>();
+        let mut buf = vec![];
+        bincode::serialize_into(&mut buf, &FORMAT_MARKER).unwrap();
+        bincode::serialize_into(&mut buf, &(entries.len() as u64)).unwrap();
+        for (key, entry) in &entries {
+            write_record(&mut buf, key).unwrap();
+            write_record(&mut buf, entry).unwrap();
+        }
+        write_record(&mut buf, &UserData::new()).unwrap();
+        write_record(&mut buf, &entry_times).unwrap();
+        write_record(&mut buf, &KeyedValues::<String>::new()).unwrap();
+        fs::write(&cache_path, buf).unwrap();
+
+        let cache = open(&cache_path);
+        assert_eq!(
+            keys_in_order(&cache),
+            vec![PathBuf::from("/b"), PathBuf::from("/c"), PathBuf::from("/a")]
+        );
+        assert_eq!(cache.entry_meta(Path::new("/a")), Some(time(3)));
+
+        cache.insert(PathBuf::from("/0"), UNIX_EPOCH, "0".to_string()).unwrap();
+        assert_eq!(keys_in_order(&cache).last().unwrap(), Path::new("/0"));
+    }
+}
@@ -1,24 +1,159 @@
+use std::path::Path;
+
 use serde::{Deserialize, Serialize};
 use vid_dup_finder_lib::*;
 
+use crate::{base_fs_cache::CacheValue, VideoProbe};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CachedVideoData {
     pub hash: VideoHash,
-    pub stats: VideoStats,
+    //None if the cache was not storing stats when the hash was created.
+    pub stats: Option<VideoStats>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntry(pub EntryState);
+
+//Entries used to contain a Result. The first two variants are named and ordered in the same way, so that they are
+//serialized in the same way and existing cache files can still be read.
+//
 //loss of space is acceptable on the assmption that most of the time we try and
 //load a video, the load will probably succeed.
 #[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum EntryState {
+    #[serde(rename = "Ok")]
+    Hashed(CachedVideoData),
+
+    #[serde(rename = "Err")]
+    Failed(HashCreationErrorKind),
 
-pub struct CacheEntry(pub Result<CachedVideoData, HashCreationErrorKind>);
+    //The video was not hashed because it exceeded the limits of an update, but its metadata could be read.
+    MetadataOnly(VideoProbe),
+
+    //The hash could not be created (e.g. because the video is too short), but the metadata of the video could be read.
+    //Treated in the same way as Failed, other than by fetch_any.
+    FailedWithProbe(HashCreationErrorKind, VideoProbe),
+}
 
 impl From<Result<(VideoHash, VideoStats), HashCreationErrorKind>> for CacheEntry {
     fn from(x: Result<(VideoHash, VideoStats), HashCreationErrorKind>) -> Self {
         match x {
-            Ok((hash, stats)) => CacheEntry(Ok(CachedVideoData { hash, stats })),
-            Err(e) => CacheEntry(Err(e)),
+            Ok((hash, stats)) => CacheEntry(EntryState::Hashed(CachedVideoData {
+                hash,
+                stats: Some(stats),
+            })),
+            Err(e) => CacheEntry(EntryState::Failed(e)),
+        }
+    }
+}
+
+impl CacheEntry {
+    //Apply f to the data of a successfully created hash, in the same way as Result::map.
+    pub fn map(self, f: impl FnOnce(CachedVideoData) -> CachedVideoData) -> CacheEntry {
+        match self.0 {
+            EntryState::Hashed(data) => CacheEntry(EntryState::Hashed(f(data))),
+            state => CacheEntry(state),
+        }
+    }
+
+    //Keep the metadata of a video for which hash creation failed, if it could be read.
+    pub fn with_probe(self, probe: Option<VideoProbe>) -> CacheEntry {
+        match (self.0, probe) {
+            (EntryState::Failed(e), Some(probe)) => CacheEntry(EntryState::FailedWithProbe(e, probe)),
+            (state, _) => CacheEntry(state),
+        }
+    }
+
+    //The hash or the error that occurred while creating it, or None if the entry only contains metadata.
+    pub fn result(&self) -> Option<Result<&CachedVideoData, &HashCreationErrorKind>> {
+        match &self.0 {
+            EntryState::Hashed(data) => Some(Ok(data)),
+            EntryState::Failed(e) | EntryState::FailedWithProbe(e, _) => Some(Err(e)),
+            EntryState::MetadataOnly(_) => None,
+        }
+    }
+
+    pub fn into_result(self) -> Option<Result<CachedVideoData, HashCreationErrorKind>> {
+        match self.0 {
+            EntryState::Hashed(data) => Some(Ok(data)),
+            EntryState::Failed(e) | EntryState::FailedWithProbe(e, _) => Some(Err(e)),
+            EntryState::MetadataOnly(_) => None,
+        }
+    }
+
+    //Replace the path stored inside the error with src_path, after the video has been renamed. VideoHash does not
+    //allow its path to be changed, so a hash keeps the path of the file it was created from.
+    pub fn with_src_path(self, src_path: &Path) -> CacheEntry {
+        let state = match self.0 {
+            EntryState::Failed(mut e) => {
+                *error_src_path(&mut e) = src_path.to_path_buf();
+                EntryState::Failed(e)
+            }
+            EntryState::FailedWithProbe(mut e, probe) => {
+                *error_src_path(&mut e) = src_path.to_path_buf();
+                EntryState::FailedWithProbe(e, probe)
+            }
+            state @ (EntryState::Hashed(_) | EntryState::MetadataOnly(_)) => state,
+        };
+        CacheEntry(state)
+    }
+
+    //True if the entry is a hash of a video shorter than min_duration. Entries without stats are never shorter,
+    //because their duration is not known.
+    pub fn is_shorter_than(&self, min_duration: f64) -> bool {
+        match &self.0 {
+            EntryState::Hashed(CachedVideoData { stats: Some(stats), .. }) => stats.duration < min_duration,
+            _ => false,
         }
     }
+
+    //The entry as it is shown when videos shorter than min_duration are treated as too short to hash: a hash of a
+    //shorter video is shown as a VideoLength error, keeping the duration and resolution of the video.
+    pub fn with_min_duration(self, src_path: &Path, min_duration: f64) -> CacheEntry {
+        match self.0 {
+            EntryState::Hashed(CachedVideoData { stats: Some(stats), .. }) if stats.duration < min_duration => {
+                CacheEntry(EntryState::Failed(HashCreationErrorKind::VideoLength(
+                    src_path.to_path_buf(),
+                )))
+                .with_probe(VideoProbe::from_stats(&stats))
+            }
+            state => CacheEntry(state),
+        }
+    }
+}
+
+fn error_src_path(e: &mut HashCreationErrorKind) -> &mut std::path::PathBuf {
+    match e {
+        HashCreationErrorKind::DetermineVideo { src_path, .. } => src_path,
+        HashCreationErrorKind::VideoLength(src_path) => src_path,
+        HashCreationErrorKind::VideoProcessing { src_path, .. } => src_path,
+    }
+}
+
+impl CacheValue for CacheEntry {
+    type Legacy = LegacyCacheEntry;
+
+    //A metadata-only entry is replaced by a hash as soon as the file is updated without limits.
+    fn is_placeholder(&self) -> bool {
+        matches!(self.0, EntryState::MetadataOnly(_))
+    }
+}
+
+//The format of entries in cache files written before stats became optional.
+#[derive(Deserialize)]
+pub struct LegacyCachedVideoData {
+    hash: VideoHash,
+    stats: VideoStats,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Deserialize)]
+pub struct LegacyCacheEntry(Result<LegacyCachedVideoData, HashCreationErrorKind>);
+
+impl From<LegacyCacheEntry> for CacheEntry {
+    fn from(legacy: LegacyCacheEntry) -> Self {
+        CacheEntry::from(legacy.0.map(|LegacyCachedVideoData { hash, stats }| (hash, stats)))
+    }
 }
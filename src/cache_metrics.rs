@@ -0,0 +1,53 @@
+//Recording of metrics through the `metrics` facade. When the `metrics` feature is disabled, all of these
+//functions are empty, so they are compiled away.
+//
+//The metrics are listed in the "Metrics" section of the crate documentation.
+
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+mod names {
+    pub const ENTRIES: &str = "vhfc_entries";
+    pub const ERROR_ENTRIES: &str = "vhfc_error_entries";
+    pub const HASHES_COMPUTED: &str = "vhfc_hashes_computed_total";
+    pub const HASH_DURATION: &str = "vhfc_hash_duration_seconds";
+    pub const CACHE_HITS: &str = "vhfc_cache_hits_total";
+    pub const CACHE_MISSES: &str = "vhfc_cache_misses_total";
+    pub const SAVES: &str = "vhfc_saves_total";
+    pub const SAVE_BYTES: &str = "vhfc_save_bytes";
+}
+#[cfg(feature = "metrics")]
+use names::*;
+
+#[inline]
+pub(crate) fn record_hash(elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!(HASHES_COMPUTED).increment(1);
+        metrics::histogram!(HASH_DURATION).record(elapsed.as_secs_f64());
+    }
+}
+
+#[inline]
+pub(crate) fn record_fetch(hit: bool) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!(if hit { CACHE_HITS } else { CACHE_MISSES }).increment(1);
+}
+
+#[inline]
+pub(crate) fn record_save(bytes: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!(SAVES).increment(1);
+        metrics::histogram!(SAVE_BYTES).record(bytes as f64);
+    }
+}
+
+//Counting entries takes time, so callers only do so when the feature is enabled.
+#[cfg(feature = "metrics")]
+pub(crate) fn record_entries(total: usize, errors: usize) {
+    metrics::gauge!(ENTRIES).set(total as f64);
+    metrics::gauge!(ERROR_ENTRIES).set(errors as f64);
+}
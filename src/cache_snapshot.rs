@@ -0,0 +1,278 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use itertools::Itertools;
+use rand::{seq::SliceRandom, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use vid_dup_finder_lib::*;
+
+use crate::{
+    cache_entry::{CachedVideoData, EntryState},
+    video_hash_filesystem_cache::fold_case,
+    *,
+};
+
+/// An immutable view of the contents of a [VideoHashFilesystemCache] at the moment it was created. Created by
+/// [snapshot][`VideoHashFilesystemCache::snapshot`].
+///
+/// A snapshot does not change while it is held, even if the cache it was taken from is updated by another thread.
+/// This allows a long search or report to see a consistent set of entries. Cloning a snapshot is cheap, and returns
+/// another handle to the same entries.
+///
+/// Paths passed to a snapshot are normalized in the same way as paths passed to the cache it was taken from. No
+/// methods visit the filesystem.
+#[derive(Clone)]
+pub struct CacheSnapshot {
+    entries: Arc<HashMap<PathBuf, CacheEntry>>,
+    canonicalize_paths: bool,
+    //A copy of the index of case-folded keys of the cache, if its keys are case-insensitive.
+    case_folded_keys: Option<Arc<HashMap<String, PathBuf>>>,
+}
+
+impl CacheSnapshot {
+    pub(crate) fn new(
+        entries: HashMap<PathBuf, CacheEntry>,
+        canonicalize_paths: bool,
+        case_folded_keys: Option<HashMap<String, PathBuf>>,
+    ) -> Self {
+        Self {
+            entries: Arc::new(entries),
+            canonicalize_paths,
+            case_folded_keys: case_folded_keys.map(Arc::new),
+        }
+    }
+
+    /// The number of entries in the snapshot, including entries for which hash creation failed and metadata-only
+    /// entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the snapshot contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over every entry in the snapshot in an unspecified order. Entries for which hash creation failed are
+    /// returned as errors. Metadata-only entries are not returned.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, Result<&VideoHash, &HashCreationErrorKind>)> + '_ {
+        self.entries
+            .iter()
+            .filter_map(|(src_path, entry)| Some((src_path.as_path(), entry.result()?.map(|data| &data.hash))))
+    }
+
+    /// See [VideoHashFilesystemCache::fetch].
+    pub fn fetch(&self, src_path: impl AsRef<Path>) -> Result<VideoHash, VdfCacheError> {
+        let src_path = src_path.as_ref();
+        match &self.fetch_entry(src_path)?.0 {
+            EntryState::Hashed(CachedVideoData { hash, .. }) => Ok(hash.clone()),
+            EntryState::Failed(error) | EntryState::FailedWithProbe(error, _) => Err(VdfCacheError::CachedFailure {
+                path: src_path.to_path_buf(),
+                error: error.clone(),
+            }),
+            EntryState::MetadataOnly(_) => Err(VdfCacheError::NotHashed(src_path.to_path_buf())),
+        }
+    }
+
+    /// See [VideoHashFilesystemCache::fetch_stats].
+    pub fn fetch_stats(&self, src_path: impl AsRef<Path>) -> Result<VideoStats, VdfCacheError> {
+        let src_path = src_path.as_ref();
+        match &self.fetch_entry(src_path)?.0 {
+            EntryState::Hashed(CachedVideoData { stats, .. }) => stats
+                .clone()
+                .ok_or_else(|| VdfCacheError::StatsNotStored(src_path.to_path_buf())),
+            EntryState::Failed(error) | EntryState::FailedWithProbe(error, _) => Err(VdfCacheError::CachedFailure {
+                path: src_path.to_path_buf(),
+                error: error.clone(),
+            }),
+            EntryState::MetadataOnly(_) => Err(VdfCacheError::NotHashed(src_path.to_path_buf())),
+        }
+    }
+
+    /// Get every [VideoHash] in the snapshot, for passing to a search.
+    pub fn all_hashes(&self) -> Vec<VideoHash> {
+        self.hashed_data().map(|(_src_path, data)| data.hash.clone()).collect()
+    }
+
+    /// See [VideoHashFilesystemCache::all_stats].
+    pub fn all_stats(&self) -> Vec<(PathBuf, VideoStats)> {
+        self.hashed_data()
+            .filter_map(|(src_path, data)| data.stats.clone().map(|stats| (src_path.to_path_buf(), stats)))
+            .collect()
+    }
+
+    /// See [VideoHashFilesystemCache::top_n_by_duration].
+    pub fn top_n_by_duration(&self, n: usize) -> Vec<(PathBuf, VideoStats)> {
+        let mut all_stats = self.all_stats();
+        all_stats.sort_by(|(path_1, stats_1), (path_2, stats_2)| {
+            stats_2
+                .duration
+                .total_cmp(&stats_1.duration)
+                .then_with(|| path_1.cmp(path_2))
+        });
+        all_stats.truncate(n);
+        all_stats
+    }
+
+    /// See [VideoHashFilesystemCache::bottom_n_by_duration].
+    pub fn bottom_n_by_duration(&self, n: usize) -> Vec<(PathBuf, VideoStats)> {
+        let mut all_stats = self.all_stats();
+        all_stats.sort_by(|(path_1, stats_1), (path_2, stats_2)| {
+            stats_1
+                .duration
+                .total_cmp(&stats_2.duration)
+                .then_with(|| path_1.cmp(path_2))
+        });
+        all_stats.truncate(n);
+        all_stats
+    }
+
+    /// See [VideoHashFilesystemCache::all_cached_paths].
+    pub fn all_cached_paths(&self) -> Vec<PathBuf> {
+        self.hashed_data()
+            .map(|(src_path, _data)| src_path.to_path_buf())
+            .collect()
+    }
+
+    /// See [VideoHashFilesystemCache::iter_by_directory].
+    pub fn iter_by_directory(&self) -> impl Iterator<Item = (PathBuf, HashEntries)> {
+        let mut dirs = BTreeMap::<PathBuf, Vec<_>>::new();
+        for (src_path, hash) in self.iter() {
+            let dir = src_path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+            let hash = hash.cloned().map_err(Clone::clone);
+            dirs.entry(dir).or_default().push((src_path.to_path_buf(), hash));
+        }
+
+        dirs.into_iter().map(|(dir, mut entries)| {
+            entries.sort_by(|(path_1, _), (path_2, _)| path_1.cmp(path_2));
+            (dir, entries)
+        })
+    }
+
+    /// See [VideoHashFilesystemCache::sample].
+    pub fn sample(&self, n: usize, seed: u64) -> HashEntries {
+        //Sort first, because the order of the HashMap differs between runs.
+        let entries = self
+            .entries
+            .iter()
+            .filter_map(|(src_path, entry)| Some((src_path, entry.result()?)))
+            .sorted_by(|(path_1, _), (path_2, _)| path_1.cmp(path_2))
+            .collect::<Vec<_>>();
+        //StdRng may change between versions of rand, so use an algorithm which is fixed.
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+        entries
+            .choose_multiple(&mut rng, n)
+            .sorted_by(|(path_1, _), (path_2, _)| path_1.cmp(path_2))
+            .map(|(src_path, result)| {
+                (
+                    (*src_path).clone(),
+                    result.map(|data| data.hash.clone()).map_err(Clone::clone),
+                )
+            })
+            .collect()
+    }
+
+    /// See [VideoHashFilesystemCache::flag_matching].
+    pub fn flag_matching(&self, reference: &[VideoHash], tolerance: f64) -> Vec<PathBuf> {
+        let matches = search_with_references(
+            reference.iter().cloned(),
+            self.all_hashes(),
+            NormalizedTolerance::new(tolerance),
+        );
+
+        matches
+            .iter()
+            .flat_map(|group| group.duplicates().map(Path::to_path_buf))
+            .sorted()
+            .dedup()
+            .collect()
+    }
+
+    /// See [VideoHashFilesystemCache::flag_matching_by].
+    pub fn flag_matching_by(
+        &self,
+        reference: &[VideoHash],
+        max_distance: f64,
+        distance: impl Fn(&VideoHash, &VideoHash) -> f64,
+    ) -> Vec<PathBuf> {
+        self.hashed_data()
+            .filter(|(_src_path, data)| {
+                reference
+                    .iter()
+                    .any(|reference_hash| distance(reference_hash, &data.hash) <= max_distance)
+            })
+            .map(|(src_path, _data)| src_path.to_path_buf())
+            .sorted()
+            .collect()
+    }
+
+    /// See [VideoHashFilesystemCache::exact_hash_groups].
+    pub fn exact_hash_groups(&self) -> Vec<Vec<PathBuf>> {
+        self.exact_hash_groups_filtered(None, false)
+    }
+
+    /// See [VideoHashFilesystemCache::exact_hash_groups_filtered].
+    pub fn exact_hash_groups_filtered(&self, prefix: Option<&Path>, require_equal_duration: bool) -> Vec<Vec<PathBuf>> {
+        let prefix = prefix.map(|prefix| self.key(prefix));
+
+        //Only durations which are exactly equal are grouped, so compare their bit patterns.
+        let duration = |data: &CachedVideoData| data.stats.as_ref().map(|stats| stats.duration.to_bits());
+
+        self.hashed_data()
+            .filter(|(src_path, _data)| match &prefix {
+                Some(prefix) => src_path.starts_with(prefix),
+                None => true,
+            })
+            .filter(|(_src_path, data)| !require_equal_duration || duration(data).is_some())
+            .filter_map(|(src_path, data)| {
+                let duration = if require_equal_duration { duration(data) } else { None };
+                Some(((hash_contents(&data.hash)?, duration), src_path.to_path_buf()))
+            })
+            .into_group_map()
+            .into_values()
+            .filter(|group| group.len() >= 2)
+            .map(|group| group.into_iter().sorted().collect::<Vec<_>>())
+            .sorted()
+            .collect()
+    }
+
+    //The data of every entry for which a hash was created.
+    fn hashed_data(&self) -> impl Iterator<Item = (&Path, &CachedVideoData)> + '_ {
+        self.entries.iter().filter_map(|(src_path, entry)| match &entry.0 {
+            EntryState::Hashed(data) => Some((src_path.as_path(), data)),
+            _ => None,
+        })
+    }
+
+    fn fetch_entry(&self, src_path: &Path) -> Result<&CacheEntry, VdfCacheError> {
+        let key = self.key(src_path);
+        self.entries.get(&key).ok_or(VdfCacheError::NotCached(key))
+    }
+
+    //Normalize a path in the same way as VideoHashFilesystemCache::key, using the case-folded keys as they were when
+    //the snapshot was taken.
+    fn key(&self, src_path: impl AsRef<Path>) -> PathBuf {
+        let key = if self.canonicalize_paths {
+            canonicalize_path(src_path)
+        } else {
+            normalize_path(src_path)
+        };
+
+        match &self.case_folded_keys {
+            Some(index) => index.get(&fold_case(&key)).cloned().unwrap_or(key),
+            None => key,
+        }
+    }
+}
+
+//The values of a hash, without the path of its video, so that hashes of different videos can be compared. VideoHash
+//does not expose its values, so they are read from its serialized form.
+fn hash_contents(hash: &VideoHash) -> Option<Vec<u8>> {
+    let mut value = serde_json::to_value(hash).ok()?;
+    value.as_object_mut()?.remove("src_path");
+    serde_json::to_vec(&value).ok()
+}
@@ -0,0 +1,100 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Statistics about the hashes created by a [VideoHashFilesystemCache][crate::VideoHashFilesystemCache]
+/// since it was created, or since the last call to [reset_stats][crate::VideoHashFilesystemCache::reset_stats].
+///
+/// Hash times include both successful and failed attempts to create a hash. All times are zero if no
+/// hashes have been created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of attempts to create a hash.
+    pub hashes_created: u64,
+
+    /// Total time spent creating hashes.
+    pub total_hash_time: Duration,
+
+    /// Mean time spent creating each hash, in microseconds.
+    pub avg_hash_time_micros: u64,
+
+    /// The shortest time spent creating a hash, in microseconds.
+    pub min_hash_time_micros: u64,
+
+    /// The longest time spent creating a hash, in microseconds.
+    pub max_hash_time_micros: u64,
+}
+
+/// The time spent in each stage of the last scan, recorded by
+/// [update_using_fs][crate::VideoHashFilesystemCache::update_using_fs].
+///
+/// All times are wall-clock times, and `hashing` and `io` add up to the elapsed time of the update. When hashes are
+/// created in parallel, the time spent checking and hashing files is divided between `hashing` and `io` in proportion
+/// to the time spent on each by all threads. For the total time spent creating hashes see [CacheStats].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanTimings {
+    /// Time spent walking the filesystem to find files, taken from
+    /// [projection_time][crate::FileProjection::projection_time].
+    pub projection: Duration,
+
+    /// Time spent creating hashes of new and modified files. This includes any automatic saves of the cache.
+    pub hashing: Duration,
+
+    /// Time spent collecting the paths to update from the cache and the projection, reading modification times to
+    /// order them, and checking files which were not hashed for modifications.
+    pub io: Duration,
+}
+
+//Lock-free accumulation of hash creation times, so that stats can be recorded from
+//parallel loading threads.
+pub(crate) struct StatsRecorder {
+    hashes_created: AtomicU64,
+    total_micros: AtomicU64,
+    min_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl StatsRecorder {
+    pub fn new() -> Self {
+        Self {
+            hashes_created: AtomicU64::new(0),
+            total_micros: AtomicU64::new(0),
+            min_micros: AtomicU64::new(u64::MAX),
+            max_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_hash_time(&self, elapsed: Duration) {
+        crate::cache_metrics::record_hash(elapsed);
+
+        let micros = elapsed.as_micros() as u64;
+        self.hashes_created.fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.min_micros.fetch_min(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hashes_created = self.hashes_created.load(Ordering::Relaxed);
+        if hashes_created == 0 {
+            return CacheStats::default();
+        }
+
+        let total_micros = self.total_micros.load(Ordering::Relaxed);
+        CacheStats {
+            hashes_created,
+            total_hash_time: Duration::from_micros(total_micros),
+            avg_hash_time_micros: total_micros / hashes_created,
+            min_hash_time_micros: self.min_micros.load(Ordering::Relaxed),
+            max_hash_time_micros: self.max_micros.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.hashes_created.store(0, Ordering::Relaxed);
+        self.total_micros.store(0, Ordering::Relaxed);
+        self.min_micros.store(u64::MAX, Ordering::Relaxed);
+        self.max_micros.store(0, Ordering::Relaxed);
+    }
+}
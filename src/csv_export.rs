@@ -0,0 +1,201 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    io::{Read, Write},
+    path::PathBuf,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    base_fs_cache::FsCacheEntry,
+    cache_entry::{CachedVideoData, EntryState},
+    video_hash_filesystem_cache::{user_data_from_json, user_data_to_json},
+    *,
+};
+
+//One row of a CSV file. mtime is in nanoseconds since the Unix epoch, size is the size of the file in bytes, and
+//user_data is a JSON object.
+#[derive(Serialize, Deserialize)]
+struct CsvRow {
+    path: PathBuf,
+    hash_hex: String,
+    mtime: Option<u64>,
+    size: Option<u64>,
+    user_data: Option<String>,
+}
+
+impl VideoHashFilesystemCache {
+    /// Write the path and hash of every [VideoHash][vid_dup_finder_lib::VideoHash] in the cache to ``writer`` as CSV,
+    /// sorted by path. The columns are:
+    ///
+    /// * `path`: The path of the video.
+    /// * `hash_hex`: The hash as hexadecimal, in the same encoding as
+    ///   [fetch_hash_bytes][`VideoHashFilesystemCache::fetch_hash_bytes`].
+    /// * `mtime`: The modification time of the video when it was hashed, in nanoseconds since the Unix epoch.
+    /// * `size`: The size of the video in bytes, or empty if its [VideoStats][vid_dup_finder_lib::VideoStats] were
+    ///   not stored.
+    /// * `user_data`: The [user data][`VideoHashFilesystemCache::set_user_data`] of the entry as a JSON object of
+    ///   values by name, or empty if it has none.
+    ///
+    /// Entries for which hash creation failed, metadata-only entries and paths which are not valid UTF-8 are not
+    /// written.
+    pub fn export_csv(&self, writer: impl Write) -> Result<(), VdfCacheError> {
+        let mut entries = self.cache.entries();
+        entries.sort_by(|(path_1, _), (path_2, _)| path_1.cmp(path_2));
+        let user_data = self.cache.user_data_map();
+
+        let mut writer = csv::Writer::from_writer(writer);
+        for (path, entry) in entries {
+            let CachedVideoData { hash, stats } = match entry.value.0 {
+                EntryState::Hashed(data) => data,
+                _ => continue,
+            };
+
+            if path.to_str().is_none() {
+                warn!("Not exporting {} to CSV: path is not valid UTF-8", path.display());
+                continue;
+            }
+
+            let hash_bytes = bincode::serialize(&hash).map_err(|e| VdfCacheError::HashBytes(e.to_string()))?;
+            let user_data = user_data.get(&path).map(user_data_to_json);
+            writer.serialize(CsvRow {
+                path,
+                hash_hex: to_hex(&hash_bytes),
+                mtime: entry
+                    .mtime
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .and_then(|mtime| u64::try_from(mtime.as_nanos()).ok()),
+                size: stats.map(|stats| stats.size),
+                user_data,
+            })?;
+        }
+
+        writer.flush().map_err(csv::Error::from)?;
+        Ok(())
+    }
+
+    /// Insert the hashes in a CSV file from ``reader`` into the cache. The file must have a header row with the same
+    /// columns as written by [export_csv][`VideoHashFilesystemCache::export_csv`], except that `mtime` and `size`
+    /// and `user_data` may be empty or omitted. Each path is stored under the same key as by
+    /// [fetch_update][`VideoHashFilesystemCache::fetch_update`], so the filesystem is only visited to canonicalize
+    /// paths when [canonicalize_paths][`VideoHashFilesystemCacheBuilder::canonicalize_paths`] is enabled.
+    ///
+    /// Rows without an `mtime` are stored as if the video was last modified at the Unix epoch, so they will be hashed
+    /// again by the next update unless a different check is set with
+    /// [set_staleness_fn][`VideoHashFilesystemCache::set_staleness_fn`]. The `size` column is not stored.
+    ///
+    /// If ``overwrite`` is false, rows for paths which are already in the cache are skipped. Rows which cannot be
+    /// parsed are skipped, and are listed in the returned report with their row number, counting the header as
+    /// row 1. They do not prevent the remaining rows from being imported.
+    ///
+    /// Returns an error if the CSV file could not be read or the cache could not be written.
+    pub fn import_csv(&self, reader: impl Read, overwrite: bool) -> Result<ImportReport, VdfCacheError> {
+        let mut entries = vec![];
+        let mut user_data = vec![];
+        let mut failed_rows = vec![];
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+        for (row_num, row) in reader.deserialize::<CsvRow>().enumerate() {
+            //Report row numbers as shown in a spreadsheet, where the header is row 1.
+            let row_num = row_num + 2;
+            let row = match row {
+                Ok(row) => row,
+                //Errors which are not tied to a row mean that the rest of the file cannot be read.
+                Err(e) if e.position().is_none() => return Err(e.into()),
+                Err(e) => {
+                    failed_rows.push((row_num, e.into()));
+                    continue;
+                }
+            };
+
+            let parsed = parse_row(&row).map(|(entry, data)| (self.import_entry(&row.path, entry), data));
+            match parsed {
+                Ok(((key, entry), data)) => {
+                    if overwrite || !self.cache.contains_key(&key) {
+                        user_data.push((key.clone(), data));
+                        entries.push((key, entry));
+                    }
+                }
+                Err(e) => failed_rows.push((row_num, e)),
+            }
+        }
+
+        if !failed_rows.is_empty() {
+            warn!("{} rows of CSV could not be imported", failed_rows.len());
+        }
+
+        let ret = self.cache.insert_many(entries);
+        self.rebuild_key_index();
+
+        let imported = ret.map_err(VdfCacheError::from_cache_write)?;
+        self.cache
+            .extend_user_data(user_data)
+            .map_err(VdfCacheError::from_cache_write)?;
+        Ok(ImportReport { imported, failed_rows })
+    }
+}
+
+//The entry and user data stored in a row.
+fn parse_row(row: &CsvRow) -> Result<(FsCacheEntry<CacheEntry>, BTreeMap<String, String>), VdfCacheError> {
+    let hash_bytes = from_hex(&row.hash_hex)
+        .ok_or_else(|| VdfCacheError::HashBytes(format!("Invalid hexadecimal: {:?}", row.hash_hex)))?;
+    let hash = VideoHashFilesystemCache::decode_hash_bytes(&hash_bytes)?;
+    let user_data = match &row.user_data {
+        Some(json) => {
+            user_data_from_json(json).map_err(|e| VdfCacheError::CsvError(format!("Invalid user data: {}", e)))?
+        }
+        None => BTreeMap::new(),
+    };
+
+    let entry = FsCacheEntry {
+        mtime: UNIX_EPOCH + Duration::from_nanos(row.mtime.unwrap_or(0)),
+        value: CacheEntry(EntryState::Hashed(CachedVideoData { hash, stats: None })),
+    };
+    Ok((entry, user_data))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() {
+        return None;
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match std::str::from_utf8(pair) {
+            Ok(pair) if pair.len() == 2 => u8::from_str_radix(pair, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_which_cannot_be_parsed_are_reported() {
+        let tree = TestTree::new();
+        let cache = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .build()
+            .unwrap();
+
+        let csv = "path,hash_hex,mtime,size\n/a.mp4,not hex,,\n/b.mp4,0,,\n";
+        let report = cache.import_csv(csv.as_bytes(), true).unwrap();
+
+        assert_eq!(report.imported, 0);
+        let row_nums = report
+            .failed_rows
+            .iter()
+            .map(|(row_num, _e)| *row_num)
+            .collect::<Vec<_>>();
+        assert_eq!(row_nums, vec![2, 3]);
+        cache.flush_without_save();
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+};
+
+use vid_dup_finder_lib::*;
+
+use crate::*;
+
+/// A property used by [rank_duplicates][`VideoHashFilesystemCache::rank_duplicates`] to decide which copy of a video
+/// is best. Each criterion prefers the larger value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RankCriterion {
+    /// Prefer more pixels (width multiplied by height).
+    Resolution,
+
+    /// Prefer a higher average bitrate, i.e. the file size in bits divided by the duration in seconds.
+    Bitrate,
+
+    /// Prefer a longer duration.
+    Duration,
+
+    /// Prefer a larger file.
+    FileSize,
+
+    /// Prefer paths which contain the given text, e.g. the directory of a curated library. The path is compared
+    /// in its lossy UTF-8 form.
+    PathContains(String),
+}
+
+/// A path in a group of duplicates, ranked by [rank_duplicates][`VideoHashFilesystemCache::rank_duplicates`].
+#[derive(Debug, Clone)]
+pub struct RankedEntry {
+    /// The path of the video.
+    pub path: PathBuf,
+
+    /// The stats of the video, or the reason they could not be fetched, e.g. because hash creation failed or stats
+    /// were not stored. Entries without stats are ranked after all entries with stats.
+    pub stats: Result<VideoStats, VdfCacheError>,
+
+    /// The score of the video for each criterion, in the order the criteria were given, where larger is better. For
+    /// [PathContains][RankCriterion::PathContains] the score is 1 if the path matches and 0 otherwise. Empty if the
+    /// stats could not be fetched.
+    pub scores: Vec<f64>,
+}
+
+impl VideoHashFilesystemCache {
+    /// Rank a group of duplicate videos from best to worst, for instance to decide which copy to keep. Videos are
+    /// compared by the first of ``criteria``, then by the next criterion when they are equal, and so on. Videos which
+    /// are equal by all criteria are ordered by path.
+    ///
+    /// The stats of each video are fetched in the same way as [fetch_stats][`VideoHashFilesystemCache::fetch_stats`].
+    /// Videos whose stats cannot be fetched are ranked last, ordered by path, along with the reason. The videos are
+    /// not read. The filesystem is only visited to canonicalize the paths in ``group`` when
+    /// [canonicalize_paths][`VideoHashFilesystemCacheBuilder::canonicalize_paths`] is enabled.
+    pub fn rank_duplicates(&self, group: &[PathBuf], criteria: &[RankCriterion]) -> Vec<RankedEntry> {
+        let mut ranked = group
+            .iter()
+            .map(|path| {
+                let stats = self.fetch_stats(path);
+                let scores = match &stats {
+                    Ok(stats) => criteria.iter().map(|criterion| score(path, stats, criterion)).collect(),
+                    Err(_) => vec![],
+                };
+                RankedEntry {
+                    path: path.clone(),
+                    stats,
+                    scores,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        ranked.sort_by(|entry_1, entry_2| {
+            //Entries with stats first, then higher scores first.
+            let scores_cmp = match (entry_1.stats.is_ok(), entry_2.stats.is_ok()) {
+                (true, true) => compare_scores(&entry_2.scores, &entry_1.scores),
+                (has_stats_1, has_stats_2) => has_stats_2.cmp(&has_stats_1),
+            };
+            scores_cmp.then_with(|| entry_1.path.cmp(&entry_2.path))
+        });
+
+        ranked
+    }
+}
+
+fn score(path: &Path, stats: &VideoStats, criterion: &RankCriterion) -> f64 {
+    let (width, height) = stats.resolution;
+    match criterion {
+        RankCriterion::Resolution => f64::from(width) * f64::from(height),
+        RankCriterion::Bitrate if stats.duration > 0.0 => stats.size as f64 * 8.0 / stats.duration,
+        RankCriterion::Bitrate => 0.0,
+        RankCriterion::Duration => stats.duration,
+        RankCriterion::FileSize => stats.size as f64,
+        RankCriterion::PathContains(text) => {
+            if path.to_string_lossy().contains(text.as_str()) {
+                1.0
+            } else {
+                0.0
+            }
+        }
+    }
+}
+
+fn compare_scores(scores_1: &[f64], scores_2: &[f64]) -> Ordering {
+    scores_1
+        .iter()
+        .zip(scores_2)
+        .map(|(score_1, score_2)| score_1.total_cmp(score_2))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
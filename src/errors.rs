@@ -1,15 +1,322 @@
-use generic_filesystem_cache::*;
+use std::{fmt, io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use vid_dup_finder_lib::*;
 
 /// Errors occurring while inserting or removing an item from a cache.
-#[derive(Error, Debug)]
+///
+/// Errors can be cloned and serialized, for instance to send them to another thread or to save them in a report.
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum VdfCacheError {
     /// An error occurred when creating a [VideoHash][vid_dup_finder_lib::VideoHash]
     #[error(transparent)]
     CreateHashError(#[from] HashCreationErrorKind),
 
-    /// An caching error occurred.
+    /// An caching error occurred. Paths which are not in the cache are always reported as
+    /// [NotCached][Self::NotCached] instead.
     #[error(transparent)]
-    CacheErrror(#[from] FsCacheErrorKind),
+    CacheErrror(FsCacheErrorKind),
+
+    /// The cache could not be saved to disk, for instance because the drive containing the cache file
+    /// has been removed. The contents of the cache are kept in memory, so they can be recovered by calling
+    /// [save][crate::VideoHashFilesystemCache::save] again once the problem has been fixed.
+    #[error("Failed to save cache to {path}: {src}")]
+    SaveFailed {
+        path: PathBuf,
+        #[source]
+        src: FsCacheErrorKind,
+    },
+
+    /// The cache path refers to an existing directory, so the cache cannot be stored there.
+    #[error("Cache path is a directory: {0}")]
+    CachePathIsDirectory(PathBuf),
+
+    /// The cache has no entry for the given path. This is returned for every cache miss, so it can be matched to
+    /// handle misses separately from other errors.
+    #[error("Not cached: {0}")]
+    NotCached(PathBuf),
+
+    /// The cache has no hash stored under the given key. See
+    /// [fetch_by_key][crate::VideoHashFilesystemCache::fetch_by_key].
+    #[error("Not cached under key: {0}")]
+    KeyNotCached(String),
+
+    /// The cache already has an entry for the given path. See
+    /// [rename][crate::VideoHashFilesystemCache::rename].
+    #[error("Already cached: {0}")]
+    AlreadyCached(PathBuf),
+
+    /// The file at ``new`` does not have the size or modification time recorded for ``old``, so it may not be the
+    /// same video. See [rename_verified][crate::VideoHashFilesystemCache::rename_verified].
+    #[error("{new} does not match the cached entry for {old}")]
+    RenamedFileDiffers { old: PathBuf, new: PathBuf },
+
+    /// The cache has only the metadata of the video at the given path, because it exceeded the limits of an update.
+    /// See [fetch_any][crate::VideoHashFilesystemCache::fetch_any].
+    #[error("Not hashed: {0}")]
+    NotHashed(PathBuf),
+
+    /// The cache has an entry for the given path, which records that its hash could not be created.
+    #[error("Cached failure for {path}: {error}")]
+    CachedFailure {
+        path: PathBuf,
+        #[source]
+        error: HashCreationErrorKind,
+    },
+
+    /// The cache contains a hash for the video, but its stats were not stored.
+    #[error("Stats were not stored for {0}")]
+    StatsNotStored(PathBuf),
+
+    /// A path passed to a [ScopedCache][crate::ScopedCache] refers to a location outside of its root,
+    /// for instance because it is absolute or contains too many `..` components.
+    #[error("{path} is outside of {root}")]
+    PathEscapesRoot { root: PathBuf, path: PathBuf },
+
+    /// A video file exists but could not be accessed, for instance due to its permissions.
+    #[error("Could not access {path}: {src}")]
+    FileInaccessible {
+        path: PathBuf,
+        #[source]
+        src: IoErrorInfo,
+    },
+
+    /// A [VideoHash][vid_dup_finder_lib::VideoHash] could not be converted to or from bytes. See
+    /// [decode_hash_bytes][crate::VideoHashFilesystemCache::decode_hash_bytes].
+    #[error("Failed to encode or decode hash bytes: {0}")]
+    HashBytes(String),
+
+    /// The thread pool for creating hashes could not be created.
+    /// See [max_parallel_hashes][crate::VideoHashFilesystemCacheBuilder::max_parallel_hashes].
+    #[cfg(feature = "parallel_loading")]
+    #[error("Failed to create thread pool: {0}")]
+    ThreadPoolError(String),
+
+    /// An error occurred while exporting to or importing from an SQLite database.
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    SqliteError(String),
+
+    /// An error occurred while exporting to or importing from CSV.
+    #[cfg(feature = "csv")]
+    #[error("CSV error: {0}")]
+    CsvError(String),
+
+    /// An error occurred while exporting to or importing from RON.
+    #[cfg(feature = "ron")]
+    #[error("RON error: {0}")]
+    RonError(String),
+}
+
+impl From<FsCacheErrorKind> for VdfCacheError {
+    fn from(e: FsCacheErrorKind) -> Self {
+        match e {
+            FsCacheErrorKind::KeyMissing(path) => Self::NotCached(path),
+            e => Self::CacheErrror(e),
+        }
+    }
+}
+
+#[cfg(feature = "parallel_loading")]
+impl From<rayon::ThreadPoolBuildError> for VdfCacheError {
+    fn from(e: rayon::ThreadPoolBuildError) -> Self {
+        Self::ThreadPoolError(e.to_string())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for VdfCacheError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::SqliteError(e.to_string())
+    }
+}
+
+#[cfg(feature = "csv")]
+impl From<csv::Error> for VdfCacheError {
+    fn from(e: csv::Error) -> Self {
+        Self::CsvError(e.to_string())
+    }
+}
+
+#[cfg(feature = "ron")]
+impl From<ron::Error> for VdfCacheError {
+    fn from(e: ron::Error) -> Self {
+        Self::RonError(e.to_string())
+    }
+}
+
+/// Receives the nonfatal errors which occur while updating the cache, instead of them being returned. See
+/// [error_handler][crate::VideoHashFilesystemCacheBuilder::error_handler].
+///
+/// This is implemented for all functions and closures with the same signature as [handle][ErrorHandler::handle].
+pub trait ErrorHandler: Send + Sync {
+    /// Report a nonfatal error.
+    fn handle(&self, error: VdfCacheError);
+}
+
+impl<F> ErrorHandler for F
+where
+    F: Fn(VdfCacheError) + Send + Sync,
+{
+    fn handle(&self, error: VdfCacheError) {
+        self(error)
+    }
+}
+
+impl fmt::Debug for dyn ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ErrorHandler")
+    }
+}
+
+impl VdfCacheError {
+    //Convert an error from an operation which may have saved the cache to disk. Errors reading or writing
+    //the cache file can only come from saving, so they are reported as SaveFailed.
+    pub(crate) fn from_cache_write(e: FsCacheErrorKind) -> Self {
+        match &e {
+            FsCacheErrorKind::CacheFileIo { path, .. } | FsCacheErrorKind::Serialization { path, .. } => {
+                Self::SaveFailed {
+                    path: path.clone(),
+                    src: e,
+                }
+            }
+            FsCacheErrorKind::KeyMissing(_) | FsCacheErrorKind::KeyExists(_) => Self::from(e),
+        }
+    }
+
+    /// Returns true if this error is expected when caching a directory containing a mixture of video and
+    /// non-video files: i.e. the file is not a video ([DetermineVideo][HashCreationErrorKind::DetermineVideo]), or is
+    /// too short to be hashed ([VideoLength][HashCreationErrorKind::VideoLength]). This applies both to newly created
+    /// hashes and to [cached failures][Self::CachedFailure].
+    ///
+    /// Returns false for all other errors, which are likely to need attention.
+    pub fn is_expected(&self) -> bool {
+        let e = match self {
+            Self::CreateHashError(e) | Self::CachedFailure { error: e, .. } => e,
+            _ => return false,
+        };
+
+        matches!(
+            e,
+            HashCreationErrorKind::DetermineVideo { .. } | HashCreationErrorKind::VideoLength(_)
+        )
+    }
+}
+
+/// Errors occurring while reading or writing the cache file, or accessing its entries.
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+pub enum FsCacheErrorKind {
+    /// An Io error occurred while reading or writing the cache file.
+    #[error("Io error accessing cache file {path}: {src}")]
+    CacheFileIo {
+        path: PathBuf,
+        #[source]
+        src: IoErrorInfo,
+    },
+
+    /// The cache file could not be serialized or deserialized.
+    #[error("Failed to serialize or deserialize cache file {path}: {src}")]
+    Serialization { path: PathBuf, src: String },
+
+    /// The requested path is not in the cache.
+    #[error("Path not found in cache: {0}")]
+    KeyMissing(PathBuf),
+
+    /// The path is already in the cache, so another entry cannot be moved to it.
+    #[error("Path already in cache: {0}")]
+    KeyExists(PathBuf),
+}
+
+impl FsCacheErrorKind {
+    pub(crate) fn io(path: impl Into<PathBuf>, src: io::Error) -> Self {
+        Self::CacheFileIo {
+            path: path.into(),
+            src: src.into(),
+        }
+    }
+
+    //Io errors which occur while (de)serializing are reported as CacheFileIo, so that
+    //they are handled in the same way as all other Io errors.
+    pub(crate) fn serialization(path: impl Into<PathBuf>, src: bincode::ErrorKind) -> Self {
+        match src {
+            bincode::ErrorKind::Io(src) => Self::io(path, src),
+            src => Self::Serialization {
+                path: path.into(),
+                src: src.to_string(),
+            },
+        }
+    }
+
+    //True if this error was caused by an Io error that may succeed if retried.
+    pub(crate) fn is_transient_io(&self) -> bool {
+        use std::io::ErrorKind::*;
+
+        match self {
+            Self::CacheFileIo { src, .. } => matches!(src.kind, TimedOut | Interrupted | WouldBlock),
+            _ => false,
+        }
+    }
+}
+
+/// An Io error, stored in a form that can be cloned and serialized.
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[error("{message}")]
+pub struct IoErrorInfo {
+    #[serde(with = "io_error_kind")]
+    pub kind: io::ErrorKind,
+    pub message: String,
+}
+
+impl From<io::Error> for IoErrorInfo {
+    fn from(e: io::Error) -> Self {
+        Self {
+            kind: e.kind(),
+            message: e.to_string(),
+        }
+    }
+}
+
+//io::ErrorKind is not serializable, so it is stored using its name.
+mod io_error_kind {
+    use std::io::ErrorKind::{self, *};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    const KINDS: &[ErrorKind] = &[
+        NotFound,
+        PermissionDenied,
+        ConnectionRefused,
+        ConnectionReset,
+        ConnectionAborted,
+        NotConnected,
+        AddrInUse,
+        AddrNotAvailable,
+        BrokenPipe,
+        AlreadyExists,
+        WouldBlock,
+        InvalidInput,
+        InvalidData,
+        TimedOut,
+        WriteZero,
+        Interrupted,
+        Unsupported,
+        UnexpectedEof,
+        OutOfMemory,
+    ];
+
+    pub fn serialize<S: Serializer>(kind: &ErrorKind, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:?}", kind))
+    }
+
+    //Unrecognized kinds become Other.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ErrorKind, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(KINDS
+            .iter()
+            .copied()
+            .find(|kind| format!("{:?}", kind) == name)
+            .unwrap_or(Other))
+    }
 }
@@ -1,15 +1,26 @@
 use std::{
-    collections::{hash_map::RandomState, HashSet},
+    collections::{hash_map::RandomState, HashMap, HashSet},
     ffi::{OsStr, OsString},
+    fmt,
+    fs::Metadata,
     path::{Path, PathBuf},
     result::Result,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use itertools::Itertools;
+#[cfg(feature = "parallel_projection")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use walkdir::WalkDir;
 
+use crate::{normalize_path, strip_verbatim, verbatim_path};
+
 /// Errors encountered during the file enumeration process.
 #[derive(Error, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum FileProjectionError {
@@ -27,6 +38,34 @@ pub enum FileProjectionError {
     /// a src_path is excluded by an excl_path.
     #[error("A start path is excluded by an excl path")]
     SrcPathExcluded { src_path: PathBuf, excl_path: PathBuf },
+
+    /// Two projections could not be combined because the excl_path of one overlaps
+    /// with a src_path of the other.
+    #[error("Cannot combine projections: {src_path} overlaps with excl path {excl_path}")]
+    ProjectionConflict { src_path: PathBuf, excl_path: PathBuf },
+
+    /// A projected file could not be canonicalized. The file is projected using its uncanonicalized path.
+    #[error("Failed to canonicalize {path}: {error}")]
+    Canonicalize { path: PathBuf, error: String },
+
+    /// An operation which requires a projected FileProjection was given an unprojected one.
+    #[error("FileProjection has not been projected")]
+    NotProjected,
+
+    /// The src_path ``a`` contains the src_path ``b`` (or they are the same path). See
+    /// [validate_no_prefix_overlap][FileProjection::validate_no_prefix_overlap].
+    #[error("Src paths overlap: {a} contains {b}")]
+    OverlappingSrcPaths { a: PathBuf, b: PathBuf },
+
+    /// The excl_path ``a`` contains the excl_path ``b`` (or they are the same path). See
+    /// [validate_no_prefix_overlap][FileProjection::validate_no_prefix_overlap].
+    #[error("Excl paths overlap: {a} contains {b}")]
+    OverlappingExclPaths { a: PathBuf, b: PathBuf },
+
+    /// Projection was cancelled before it finished. See
+    /// [project_using_fs_cancellable][FileProjection::project_using_fs_cancellable].
+    #[error("Projection cancelled")]
+    Cancelled,
 }
 
 impl From<walkdir::Error> for FileProjectionError {
@@ -41,7 +80,85 @@ impl From<&walkdir::Error> for FileProjectionError {
     }
 }
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+/// Nonfatal problems encountered while projecting using the filesystem.
+#[derive(Error, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ProjectionWarning {
+    /// A file or directory could not be read because of its permissions.
+    #[error("Permission denied: {0}")]
+    PermissionDenied(PathBuf),
+
+    /// A symlink points to a file or directory which does not exist.
+    #[error("Broken symlink: {0}")]
+    BrokenSymlink(PathBuf),
+
+    /// Any other Io error. The path is not always known.
+    #[error("Io error ({kind:?}) at {path:?}")]
+    IoError {
+        path: Option<PathBuf>,
+        kind: std::io::ErrorKind,
+    },
+
+    /// A symlink loop was detected at the given path.
+    #[error("Filesystem loop: {0}")]
+    WalkLoop(PathBuf),
+
+    /// A src_path does not exist, and was skipped because of [MissingRootPolicy::Skip].
+    #[error("Src path not found: {0}")]
+    MissingSrcPath(PathBuf),
+}
+
+impl ProjectionWarning {
+    /// The path at which the problem occurred, if known.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::PermissionDenied(path)
+            | Self::BrokenSymlink(path)
+            | Self::WalkLoop(path)
+            | Self::MissingSrcPath(path) => Some(path),
+            Self::IoError { path, .. } => path.as_deref(),
+        }
+    }
+}
+
+impl From<&walkdir::Error> for ProjectionWarning {
+    fn from(e: &walkdir::Error) -> Self {
+        use std::io::ErrorKind;
+
+        let path = e.path().map(Path::to_path_buf);
+
+        if e.loop_ancestor().is_some() {
+            return Self::WalkLoop(path.unwrap_or_default());
+        }
+
+        let kind = e.io_error().map(std::io::Error::kind).unwrap_or(ErrorKind::Other);
+        match (kind, path) {
+            (ErrorKind::PermissionDenied, Some(path)) => Self::PermissionDenied(path),
+            //When following links, walkdir reports a broken symlink as NotFound.
+            (ErrorKind::NotFound, Some(path)) if path.symlink_metadata().is_ok() => Self::BrokenSymlink(path),
+            (kind, path) => Self::IoError { path, kind },
+        }
+    }
+}
+
+impl From<walkdir::Error> for ProjectionWarning {
+    fn from(e: walkdir::Error) -> Self {
+        Self::from(&e)
+    }
+}
+
+/// What [project_using_fs][FileProjection::project_using_fs] does when a src_path does not exist.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Default, Serialize, Deserialize)]
+pub enum MissingRootPolicy {
+    /// Return [PathNotFound][FileProjectionError::PathNotFound]. This is the default.
+    #[default]
+    Fail,
+
+    /// Skip the src_path and record a [MissingSrcPath][ProjectionWarning::MissingSrcPath] warning. This is
+    /// useful for src_paths on removable drives which may not be mounted.
+    Skip,
+}
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 enum FileProjectionState {
     Unprojected,
     ProjectedUsingFs,
@@ -50,19 +167,182 @@ enum FileProjectionState {
 
 use FileProjectionState::*;
 
+//Identifies a file on disk, regardless of how it is referred to.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum FileIdentity {
+    #[cfg(unix)]
+    Inode {
+        dev: u64,
+        ino: u64,
+    },
+    Path(PathBuf),
+}
+
+impl FileIdentity {
+    //Falls back to the path if the metadata cannot be read, or if inodes are not available on this platform.
+    fn of(canonical_path: &Path) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if let Ok(metadata) = std::fs::metadata(canonical_path) {
+                return Self::Inode {
+                    dev: metadata.dev(),
+                    ino: metadata.ino(),
+                };
+            }
+        }
+
+        Self::Path(canonical_path.to_path_buf())
+    }
+}
+
+//The first pair of paths where the first contains the second.
+//See FileProjection::validate_no_prefix_overlap.
+fn validate_no_prefix_overlap(src_paths: &[PathBuf], excl_paths: &[PathBuf]) -> Result<(), FileProjectionError> {
+    if let Some((a, b)) = find_overlap(src_paths) {
+        return Err(FileProjectionError::OverlappingSrcPaths { a, b });
+    }
+    if let Some((a, b)) = find_overlap(excl_paths) {
+        return Err(FileProjectionError::OverlappingExclPaths { a, b });
+    }
+
+    Ok(())
+}
+
+//The overlap between two src_paths is whichever one is the child of the other.
+fn find_overlapping_regions(ours: &[PathBuf], theirs: &[PathBuf]) -> Vec<PathBuf> {
+    ours.iter()
+        .cartesian_product(theirs)
+        .filter_map(|(ours, theirs)| {
+            if ours.starts_with(theirs) {
+                Some(ours.clone())
+            } else if theirs.starts_with(ours) {
+                Some(theirs.clone())
+            } else {
+                None
+            }
+        })
+        .unique()
+        .collect()
+}
+
+fn find_overlap(paths: &[PathBuf]) -> Option<(PathBuf, PathBuf)> {
+    paths.iter().tuple_combinations().find_map(|(path_1, path_2)| {
+        if path_2.starts_with(path_1) {
+            Some((path_1.clone(), path_2.clone()))
+        } else if path_1.starts_with(path_2) {
+            Some((path_2.clone(), path_1.clone()))
+        } else {
+            None
+        }
+    })
+}
+
+//Convert an extension to the form returned by Path::extension (without a leading dot),
+//in lowercase.
+fn normalize_ext(ext: impl AsRef<OsStr>) -> OsString {
+    let ext = ext.as_ref();
+    let mut ext = match ext.to_str() {
+        Some(ext_str) => OsString::from(ext_str.strip_prefix('.').unwrap_or(ext_str)),
+        None => ext.to_os_string(),
+    };
+    ext.make_ascii_lowercase();
+    ext
+}
+
+/// The reason why a path is or is not included in a [FileProjection]. See [FileProjection::explain].
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub enum InclusionDecision {
+    /// The path is a child of a src_path and is not excluded.
+    Included,
+
+    /// The path is a child of the given excl_path.
+    ExcludedByPath(PathBuf),
+
+    /// The path (or one of its parent directories) has the given ignored extension.
+    ExcludedByExt(OsString),
+
+    /// The path is not a child of any src_path.
+    NotUnderSrcPaths,
+
+    /// The path is in the skip set. See [FileProjection::set_skip_set].
+    InSkipSet,
+
+    /// The file was modified too recently, so may still be being written. See
+    /// [FileProjection::set_skip_recently_modified].
+    RecentlyModified,
+
+    /// The file was rejected by the custom filter. See [FileProjection::set_custom_filter].
+    ExcludedByFilter,
+
+    /// An error occurred while reading the path (or one of its parent directories)
+    /// from the filesystem during projection.
+    WalkError(String),
+}
+
+//A filter set with FileProjection::set_custom_filter. Closures cannot be serialized, so a deserialized projection has
+//no filter.
+type FilterFn = dyn Fn(&Path, &Metadata) -> bool + Send + Sync;
+
+#[derive(Clone, Default)]
+struct CustomFilter(Option<Arc<FilterFn>>);
+
+impl fmt::Debug for CustomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("CustomFilter(Some(..))"),
+            None => f.write_str("CustomFilter(None)"),
+        }
+    }
+}
+
 /// A utility struct for holding a set of paths, and all children from those paths.
 /// Contains an associated set of "exclude" paths whose children should not be returned.
-#[derive(Debug, Clone)]
+///
+/// A FileProjection can be serialized along with its projected files, so that the results of a slow
+/// projection can be saved and reused. [warnings][Self::warnings] are not serialized.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileProjection {
     src_paths: Vec<PathBuf>,
     excl_paths: Vec<PathBuf>,
     projected_files: HashSet<PathBuf>,
     state: FileProjectionState,
     excl_exts: Vec<OsString>,
+    //Not serialized because io::ErrorKind is not serializable.
+    #[serde(skip)]
+    warnings: Vec<ProjectionWarning>,
+    skipped_limit: Option<usize>,
+    skipped: Vec<(PathBuf, InclusionDecision)>,
+    canonicalize: bool,
+    canonicalize_errors: Vec<FileProjectionError>,
+    //The canonical forms of the src_paths and excl_paths, if the last projection canonicalized paths. Projected files
+    //are found under these, so they are checked along with the configured paths, which are left unchanged.
+    #[serde(default)]
+    canonical_src_paths: Vec<PathBuf>,
+    #[serde(default)]
+    canonical_excl_paths: Vec<PathBuf>,
+    same_file_system: bool,
+    excl_mount_points: Vec<PathBuf>,
+    projection_time: Duration,
+    skip_set: HashSet<PathBuf>,
+    missing_root_policy: MissingRootPolicy,
+    //The src_paths which were skipped by the last projection because they did not exist.
+    #[serde(default)]
+    missing_src_paths: Vec<PathBuf>,
+    max_files_per_directory: Option<usize>,
+    skip_recently_modified: Option<Duration>,
+    #[serde(skip)]
+    custom_filter: CustomFilter,
 }
 
 impl FileProjection {
-    /// Create a new FileProjection with the given src_paths, excl_paths and ignore-extensions
+    /// Create a new FileProjection with the given src_paths, excl_paths and ignore-extensions.
+    /// Relative paths are made absolute using the current directory, and `.` and `..` components are removed,
+    /// so that projected files are always absolute.
+    ///
+    /// Ignore-extensions are expected in the form returned by [Path::extension], e.g. `"mp4"`. A single
+    /// leading dot is removed (so `".mp4"` is also accepted), and extensions are matched case-insensitively.
+    ///
     /// Child files can either be got by projecting the src_paths, either from
     /// the filesystem (project_using_fs), or from some list (project_using_list).
     /// Once projection has occurred the projected files will be cached by this struct
@@ -75,15 +355,9 @@ impl FileProjection {
         excl_paths: impl IntoIterator<Item = impl AsRef<Path>>,
         excl_exts: impl IntoIterator<Item = impl AsRef<OsStr>>,
     ) -> Result<Self, FileProjectionError> {
-        let src_paths = src_paths
-            .into_iter()
-            .map(|p| p.as_ref().to_path_buf())
-            .collect::<Vec<_>>();
+        let src_paths = src_paths.into_iter().map(normalize_path).collect::<Vec<_>>();
 
-        let excl_paths = excl_paths
-            .into_iter()
-            .map(|p| p.as_ref().to_path_buf())
-            .collect::<Vec<_>>();
+        let excl_paths = excl_paths.into_iter().map(normalize_path).collect::<Vec<_>>();
 
         //check that the same path does not appear in srcs and excls
         excl_paths
@@ -100,29 +374,327 @@ impl FileProjection {
             })
             .transpose()?;
 
-        Ok(Self {
+        Ok(Self::with_config(
+            src_paths,
+            excl_paths,
+            excl_exts.into_iter().map(normalize_ext).collect(),
+        ))
+    }
+
+    //An unprojected FileProjection with default options.
+    fn with_config(src_paths: Vec<PathBuf>, excl_paths: Vec<PathBuf>, excl_exts: Vec<OsString>) -> Self {
+        Self {
             src_paths,
             excl_paths,
             projected_files: Default::default(),
             state: Unprojected,
-            excl_exts: excl_exts.into_iter().map(|x| x.as_ref().to_os_string()).collect(),
-        })
+            excl_exts,
+            warnings: vec![],
+            skipped_limit: None,
+            skipped: vec![],
+            canonicalize: false,
+            canonicalize_errors: vec![],
+            canonical_src_paths: vec![],
+            canonical_excl_paths: vec![],
+            same_file_system: false,
+            excl_mount_points: vec![],
+            projection_time: Duration::from_secs(0),
+            skip_set: HashSet::new(),
+            missing_root_policy: MissingRootPolicy::default(),
+            missing_src_paths: vec![],
+            max_files_per_directory: None,
+            skip_recently_modified: None,
+            custom_filter: CustomFilter::default(),
+        }
     }
 
     /// Returns true if the given path is a child of any src_path,
-    /// and is not a child of any excl_path.
+    /// is not a child of any excl_path, and is not in the skip set. Children of src_paths which were skipped
+    /// because they did not exist are not contained (see [set_missing_root_policy][Self::set_missing_root_policy]).
+    ///
+    /// Relative paths are made absolute using the current directory, and `.` and `..` components
+    /// are removed, before comparison.
+    ///
+    /// If [set_skip_recently_modified][Self::set_skip_recently_modified] or
+    /// [set_custom_filter][Self::set_custom_filter] is used, then the metadata of the path is read and false is
+    /// returned for a file which is modified too recently or rejected by the filter, in the same way as during
+    /// projection. Otherwise the filesystem is not visited.
+    ///
+    /// Paths are compared by whole components, so a path is a child of a src_path or excl_path if it is equal to it,
+    /// or if it begins with all of its components. For example `/media/foo/a.mp4` and `/media/foo` are both children
+    /// of `/media/foo`, but `/media/foobar` is not. A trailing slash makes no difference, so `/media/foo/` is treated
+    /// in the same way as `/media/foo`.
     pub fn contains(&self, src_path: impl AsRef<Path>) -> bool {
-        self.raw_includes(&src_path) && !self.raw_excludes(&src_path)
+        let src_path = normalize_path(src_path);
+        self.raw_includes(&src_path)
+            && !self
+                .missing_src_paths
+                .iter()
+                .any(|missing| src_path.starts_with(missing))
+            && !self.raw_excludes(&src_path)
+            && !self.skip_set.contains(&src_path)
+            && self.file_decision(&verbatim_path(&src_path), &src_path).is_none()
     }
 
+    //Path::starts_with compares whole components, so a sibling whose name shares a prefix (`/a/foobar` for `/a/foo`)
+    //does not match.
     fn raw_includes(&self, p: impl AsRef<Path>) -> bool {
-        self.src_paths.iter().any(|src_path| p.as_ref().starts_with(src_path))
+        self.all_src_paths().any(|src_path| p.as_ref().starts_with(src_path))
     }
 
     fn raw_excludes(&self, p: impl AsRef<Path>) -> bool {
+        self.excluding_path(p).is_some()
+    }
+
+    //The src_paths, followed by their canonical forms if the projection canonicalized them.
+    fn all_src_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.src_paths.iter().chain(&self.canonical_src_paths)
+    }
+
+    /// Check that no src_path contains another src_path, and that no excl_path contains another excl_path. Paths
+    /// are compared by whole components, in the same way as [contains][Self::contains], so this also applies to paths
+    /// on different filesystems, e.g. a src_path on one drive and another src_path on a drive mounted inside it.
+    ///
+    /// Each src_path is walked separately, so the files under an overlapping src_path are found once for each
+    /// src_path containing them. The duplicate files are merged, but the shared directories are read twice, and any
+    /// warnings, skipped files and errors under them are reported twice. Overlapping excl_paths do not change which
+    /// files are projected, but usually mean that the projection was not configured as intended.
+    ///
+    /// This is checked automatically by [project_using_fs][Self::project_using_fs].
+    ///
+    /// # Return values
+    /// Returns Err(OverlappingSrcPaths) or Err(OverlappingExclPaths) for the first overlapping pair of paths, where
+    /// ``a`` contains ``b``.
+    pub fn validate_no_prefix_overlap(&self) -> Result<(), FileProjectionError> {
+        validate_no_prefix_overlap(&self.src_paths, &self.excl_paths)
+    }
+
+    /// Explain why the given path is or is not included in this projection. This does not visit
+    /// the filesystem. If the projection has been projected using the filesystem, then errors
+    /// encountered while reading the path or its parent directories are also reported.
+    pub fn explain(&self, path: &Path) -> InclusionDecision {
+        use InclusionDecision::*;
+
+        let path = normalize_path(path);
+        let path = path.as_path();
+
+        let src_path = match self.all_src_paths().find(|src_path| path.starts_with(src_path)) {
+            Some(src_path) => src_path,
+            None => return NotUnderSrcPaths,
+        };
+
+        if let Some(excl_path) = self.excluding_path(path) {
+            return ExcludedByPath(excl_path.to_path_buf());
+        }
+
+        if let Some(mount_point) = self.excl_mount_points.iter().find(|m| path.starts_with(m)) {
+            return ExcludedByPath(mount_point.clone());
+        }
+
+        if self.skip_set.contains(path) {
+            return InSkipSet;
+        }
+
+        //directories with an ignored extension are not descended into during projection,
+        //so check the parents of the path too.
+        let ignored_ext = path
+            .ancestors()
+            .take_while(|ancestor| ancestor.starts_with(src_path))
+            .find_map(|ancestor| self.ignore_ext(ancestor));
+        if let Some(ext) = ignored_ext {
+            return ExcludedByExt(ext.to_os_string());
+        }
+
+        let warning = self.warnings.iter().find(|warning| {
+            warning
+                .path()
+                .is_some_and(|warning_path| path.starts_with(warning_path))
+        });
+        if let Some(warning) = warning {
+            return WalkError(warning.to_string());
+        }
+
+        Included
+    }
+
+    /// The time spent walking the filesystem in [project_using_fs][Self::project_using_fs]. This is zero if
+    /// projection has not been done, or was done using a list. For the union or intersection of two projections,
+    /// this is the sum of the time spent projecting each of them.
+    pub fn projection_time(&self) -> Duration {
+        self.projection_time
+    }
+
+    /// Nonfatal problems encountered during [project_using_fs][Self::project_using_fs], such as
+    /// unreadable directories and broken symlinks. These correspond to the errors returned by
+    /// project_using_fs, along with any broken symlinks found.
+    pub fn warnings(&self) -> &[ProjectionWarning] {
+        &self.warnings
+    }
+
+    /// Record paths which are skipped while projecting using the filesystem, along with the reason
+    /// for skipping them. At most `max_entries` skipped paths are recorded. Skipped directories are
+    /// recorded, but their children are not.
+    ///
+    /// Skipped paths can be retrieved with [skipped][Self::skipped] after projection.
+    pub fn record_skipped(&mut self, max_entries: usize) {
+        self.skipped_limit = Some(max_entries);
+    }
+
+    /// The paths skipped during [project_using_fs][Self::project_using_fs], if enabled by
+    /// [record_skipped][Self::record_skipped].
+    pub fn skipped(&self) -> &[(PathBuf, InclusionDecision)] {
+        &self.skipped
+    }
+
+    /// When enabled, [project_using_fs][Self::project_using_fs] does not descend into directories
+    /// which are on a different filesystem to the src_path being walked (e.g. mounted drives or snapshots),
+    /// in the same way as `find -xdev`. The device id of each directory entry is compared with the device id of the
+    /// src_path. Disabled by default.
+    ///
+    /// This keeps a walk out of pseudo-filesystems such as `/proc` and `/sys` and out of network automounts, where
+    /// it can otherwise hang, even when a src_path is close to them (e.g. `/`). The same option also applies to
+    /// [iter_fs][Self::iter_fs].
+    #[doc(alias = "set_same_filesystem")]
+    #[doc(alias = "xdev")]
+    pub fn set_same_file_system(&mut self, enabled: bool) {
+        self.same_file_system = enabled;
+    }
+
+    /// Set mount points (or any other directories) which [project_using_fs][Self::project_using_fs] will
+    /// not descend into. Unlike excl_paths these paths do not need to exist. This is useful for skipping
+    /// particular mounts when [set_same_file_system][Self::set_same_file_system] is not enabled.
+    pub fn set_excl_mount_points(&mut self, mount_points: impl IntoIterator<Item = impl AsRef<Path>>) {
+        self.excl_mount_points = mount_points.into_iter().map(normalize_path).collect();
+    }
+
+    //Decide whether an entry found while walking the filesystem should be pruned. Returns None
+    //if the entry (and its children) should be visited.
+    fn walk_decision(&self, path: &Path) -> Option<InclusionDecision> {
+        if let Some(excl_path) = self.excluding_path(path) {
+            Some(InclusionDecision::ExcludedByPath(excl_path.to_path_buf()))
+        } else if let Some(mount_point) = self.excl_mount_points.iter().find(|m| path.starts_with(m)) {
+            Some(InclusionDecision::ExcludedByPath(mount_point.clone()))
+        } else if self.skip_set.contains(path) {
+            Some(InclusionDecision::InSkipSet)
+        } else {
+            self.ignore_ext(path)
+                .map(|ext| InclusionDecision::ExcludedByExt(ext.to_os_string()))
+        }
+    }
+
+    fn excluding_path(&self, p: impl AsRef<Path>) -> Option<&Path> {
         self.excl_paths
             .iter()
-            .any(|excl_path| p.as_ref().starts_with(excl_path))
+            .chain(&self.canonical_excl_paths)
+            .find(|excl_path| p.as_ref().starts_with(excl_path))
+            .map(PathBuf::as_path)
+    }
+
+    /// Set what [project_using_fs][Self::project_using_fs] does when a src_path does not exist. When
+    /// [Skip][MissingRootPolicy::Skip] is used, missing src_paths are not walked, and are reported in
+    /// [warnings][Self::warnings]. They are still listed by [src_paths][Self::src_paths], but
+    /// [contains][Self::contains] returns false for their children, so that cached hashes under them are kept.
+    pub fn set_missing_root_policy(&mut self, policy: MissingRootPolicy) {
+        self.missing_root_policy = policy;
+    }
+
+    /// Set a group of files which will not be projected, for instance files which have already been
+    /// processed by an earlier run of a resumable job. Unlike excl_paths, each path excludes only that exact
+    /// file, not its children.
+    ///
+    /// Relative paths are made absolute using the current directory, and `.` and `..` components
+    /// are removed.
+    pub fn set_skip_set(&mut self, skip_set: HashSet<PathBuf>) {
+        self.skip_set = skip_set.into_iter().map(normalize_path).collect();
+    }
+
+    /// Limit the number of files which [project_using_fs][Self::project_using_fs] takes from each directory to
+    /// `max_files`. Files in subdirectories are counted separately for each subdirectory. Files over the limit are
+    /// not projected, and are not recorded by [record_skipped][Self::record_skipped].
+    ///
+    /// When a limit is set, the files in each directory are visited in order of their names, so the same files are
+    /// projected each time. Combined with [set_skip_set][Self::set_skip_set], this allows many directories to be
+    /// processed a few files at a time without enumerating every file first.
+    ///
+    /// By default there is no limit.
+    pub fn set_max_files_per_directory(&mut self, max_files: usize) {
+        self.max_files_per_directory = Some(max_files);
+    }
+
+    /// Do not project files which were modified less than `min_age` ago, because they may still be being written
+    /// (e.g. downloads in progress). This prevents partially written files from being hashed and cached. Files with
+    /// a modification time in the future are also skipped. Skipped files are recorded by
+    /// [record_skipped][Self::record_skipped] as [RecentlyModified][InclusionDecision::RecentlyModified].
+    ///
+    /// This only applies when visiting the filesystem, i.e. in [project_using_fs][Self::project_using_fs],
+    /// [iter_fs][Self::iter_fs] and [contains][Self::contains]. By default recently modified files are projected.
+    pub fn set_skip_recently_modified(&mut self, min_age: Duration) {
+        self.skip_recently_modified = Some(min_age);
+    }
+
+    /// Only project files for which `filter` returns true. `filter` is called with the path and [Metadata] of each
+    /// file, so files can be filtered on their size, permissions or any other attribute. Symlinks are followed, so the
+    /// metadata is that of the file which the symlink points to. Files whose metadata cannot be read are projected.
+    /// Rejected files are recorded by [record_skipped][Self::record_skipped] as
+    /// [ExcludedByFilter][InclusionDecision::ExcludedByFilter].
+    ///
+    /// This only applies when visiting the filesystem, i.e. in [project_using_fs][Self::project_using_fs],
+    /// [iter_fs][Self::iter_fs] and [contains][Self::contains]. The filter is not serialized with the projection. By
+    /// default all files are projected.
+    pub fn set_custom_filter(&mut self, filter: impl Fn(&Path, &Metadata) -> bool + Send + Sync + 'static) {
+        self.custom_filter = CustomFilter(Some(Arc::new(filter)));
+    }
+
+    //Decide whether a file should be skipped because of its metadata, which is read from fs_path only if an option
+    //that needs it is set. Directories and files whose metadata cannot be read are not skipped, so that the error is
+    //reported when they are hashed.
+    fn file_decision(&self, fs_path: &Path, path: &Path) -> Option<InclusionDecision> {
+        if self.skip_recently_modified.is_none() && self.custom_filter.0.is_none() {
+            return None;
+        }
+
+        let metadata = match std::fs::metadata(fs_path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return None,
+        };
+
+        if let Some(min_age) = self.skip_recently_modified {
+            //Files modified in the future were probably modified very recently on a machine with a different clock.
+            let recently_modified = match metadata.modified().map(|mtime| SystemTime::now().duration_since(mtime)) {
+                Ok(Ok(age)) => age < min_age,
+                Ok(Err(_)) => true,
+                Err(_) => false,
+            };
+            if recently_modified {
+                return Some(InclusionDecision::RecentlyModified);
+            }
+        }
+
+        match &self.custom_filter.0 {
+            Some(filter) if !filter(path, &metadata) => Some(InclusionDecision::ExcludedByFilter),
+            _ => None,
+        }
+    }
+
+    /// When enabled, [project_using_fs][Self::project_using_fs] resolves symlinks in the src_paths, excl_paths
+    /// and projected files using [std::fs::canonicalize], and removes any duplicate files. This prevents the same
+    /// file from being projected twice when it is reachable through more than one src_path, and means that
+    /// files will be cached using their canonical paths.
+    ///
+    /// The configured src_paths and excl_paths are not changed, and [contains][Self::contains] accepts paths under
+    /// either their configured or their canonical forms. A file which is a symlink to a path that is excluded, or
+    /// that is outside every src_path, is not projected.
+    ///
+    /// Canonicalization can be slow on network filesystems, so it is disabled by default. Files which
+    /// cannot be canonicalized are projected using their original path, and the errors can be retrieved
+    /// with [canonicalize_errors][Self::canonicalize_errors].
+    pub fn canonicalize_paths(&mut self, enabled: bool) {
+        self.canonicalize = enabled;
+    }
+
+    /// Errors that occurred while canonicalizing projected files. See [canonicalize_paths][Self::canonicalize_paths].
+    pub fn canonicalize_errors(&self) -> &[FileProjectionError] {
+        &self.canonicalize_errors
     }
 
     /// Visit the filesystem to get all child files which are a child of any of Self::src_paths,
@@ -130,15 +702,35 @@ impl FileProjection {
     ///
     /// # Return values
     /// Returns Err() if any path in Self::src_paths or Self::excl_paths cannot be read from
-    /// the filesystem.
+    /// the filesystem, or if they overlap. See [validate_no_prefix_overlap][Self::validate_no_prefix_overlap].
     ///
     /// Otherwise returns Ok() containing a list of all other errors encountered while retrieving
     /// paths from the filesystem.
     ///
+    /// # Parallelism
+    /// When the `parallel_projection` cargo feature is enabled, each src_path is walked in parallel
+    /// on the global rayon thread pool. This is only beneficial when there are several src_paths.
+    ///
     /// # Panics
     /// This function will panic if either project_using_fs or project_using_list
     /// has already been called.
     pub fn project_using_fs(&mut self) -> Result<Vec<walkdir::Error>, FileProjectionError> {
+        self.project_using_fs_cancellable(&AtomicBool::new(false))
+    }
+
+    /// The same as [project_using_fs][Self::project_using_fs], but stops early if ``cancel`` is set by another
+    /// thread, for instance when the user presses a "stop" button while a large or slow tree is being walked. The
+    /// flag is checked before each file and directory is visited.
+    ///
+    /// If the projection is cancelled then Err(Cancelled) is returned, and the projection is left unprojected and
+    /// unchanged, so it can be projected again later.
+    ///
+    /// # Panics
+    /// This function will panic if project_using_list has already been called.
+    pub fn project_using_fs_cancellable(
+        &mut self,
+        cancel: &AtomicBool,
+    ) -> Result<Vec<walkdir::Error>, FileProjectionError> {
         use FileProjectionError::*;
 
         match self.state {
@@ -150,12 +742,13 @@ impl FileProjection {
             ProjectedUsingFs => Ok(vec![]),
 
             Unprojected => {
+                let start_time = Instant::now();
                 //we will return a fatal error if any directory/file that the user
-                //has specified does not exist.
-                for path in &self.src_paths {
-                    if !path.exists() {
-                        return Err(PathNotFound(path.to_owned()));
-                    }
+                //has specified does not exist, unless missing src_paths are to be skipped.
+                let (mut walk_roots, missing_src_paths): (Vec<_>, Vec<_>) =
+                    self.src_paths.iter().cloned().partition(|path| path.exists());
+                if let (Some(path), MissingRootPolicy::Fail) = (missing_src_paths.first(), self.missing_root_policy) {
+                    return Err(PathNotFound(path.to_owned()));
                 }
 
                 for path in &self.excl_paths {
@@ -164,37 +757,191 @@ impl FileProjection {
                     }
                 }
 
-                let (enumerated_paths, loading_errs): (HashSet<PathBuf, RandomState>, Vec<walkdir::Error>) = self
-                    .src_paths
+                self.validate_no_prefix_overlap()?;
+                //The configured paths are kept, and the walk starts from the canonical src_paths. Overlaps are checked
+                //again after canonicalization, which can reveal overlaps hidden by symlinks.
+                if self.canonicalize {
+                    walk_roots = Self::canonicalize_roots(&walk_roots, PathNotFound)?;
+                    let canonical_excl_paths = Self::canonicalize_roots(&self.excl_paths, ExclPathNotFound)?;
+                    validate_no_prefix_overlap(&walk_roots, &canonical_excl_paths)?;
+                    self.canonical_src_paths = walk_roots.clone();
+                    self.canonical_excl_paths = canonical_excl_paths;
+                }
+
+                //When the parallel_projection feature is enabled, each src_path is walked in its own thread.
+                let skipped = Mutex::new(vec![]);
+                let broken_symlinks = Mutex::new(vec![]);
+
+                #[cfg(feature = "parallel_projection")]
+                let walks = walk_roots
+                    .par_iter()
+                    .map(|src_path| self.walk_src_path(src_path, &skipped, &broken_symlinks, cancel))
+                    .collect::<Vec<_>>();
+
+                #[cfg(not(feature = "parallel_projection"))]
+                let walks = walk_roots
                     .iter()
-                    .flat_map(|src_path| {
-                        WalkDir::new(src_path).into_iter().filter_entry(|entry| {
-                            let src_path = entry.path();
-                            self.contains(src_path) && !self.has_ignore_ext(src_path)
-                        })
-                    })
-                    .filter_map(|dir_entry_res| match dir_entry_res {
-                        Err(e) => Some(Err(e)),
-                        Ok(dir_entry) => {
-                            let src_path = dir_entry.path();
-                            if src_path.is_file() {
-                                Some(Ok(src_path.to_path_buf()))
-                            } else {
-                                None
-                            }
-                        }
-                    })
-                    .partition_result();
+                    .map(|src_path| self.walk_src_path(src_path, &skipped, &broken_symlinks, cancel))
+                    .collect::<Vec<_>>();
+
+                if cancel.load(Ordering::Relaxed) {
+                    self.canonical_src_paths.clear();
+                    self.canonical_excl_paths.clear();
+                    return Err(Cancelled);
+                }
 
-                self.projected_files = enumerated_paths;
+                let mut enumerated_paths = HashSet::new();
+                let mut loading_errs = vec![];
+                for (paths, errs) in walks {
+                    enumerated_paths.extend(paths);
+                    loading_errs.extend(errs);
+                }
+
+                self.skipped = skipped.into_inner().unwrap();
+                self.projected_files = if self.canonicalize {
+                    self.canonicalize_files(enumerated_paths)
+                } else {
+                    enumerated_paths
+                };
+                self.warnings = loading_errs.iter().map(ProjectionWarning::from).collect();
+                self.warnings.extend(broken_symlinks.into_inner().unwrap());
+                self.warnings
+                    .extend(missing_src_paths.iter().cloned().map(ProjectionWarning::MissingSrcPath));
+                self.missing_src_paths = missing_src_paths;
                 self.state = ProjectedUsingFs;
+                self.projection_time = start_time.elapsed();
 
                 Ok(loading_errs)
             }
         }
     }
 
-    /// Enumerate files by filtering a list of paths.
+    //Walk the filesystem from a single src_path, returning all files found and any errors encountered.
+    fn walk_src_path(
+        &self,
+        src_path: &Path,
+        skipped: &Mutex<Vec<(PathBuf, InclusionDecision)>>,
+        broken_symlinks: &Mutex<Vec<ProjectionWarning>>,
+        cancel: &AtomicBool,
+    ) -> (HashSet<PathBuf, RandomState>, Vec<walkdir::Error>) {
+        let skipped_limit = self.skipped_limit.unwrap_or(0);
+
+        let on_skip = |src_path: &Path, decision| {
+            let mut skipped = skipped.lock().unwrap();
+            if skipped.len() < skipped_limit {
+                skipped.push((src_path.to_path_buf(), decision));
+            }
+        };
+        let on_broken_symlink = |src_path: &Path| {
+            let warning = ProjectionWarning::BrokenSymlink(src_path.to_path_buf());
+            broken_symlinks.lock().unwrap().push(warning);
+        };
+
+        self.walk_files(src_path, on_skip, on_broken_symlink, Some(cancel))
+            .partition_result()
+    }
+
+    //Lazily walk the filesystem from a single src_path, yielding all files which pass the projection's filters.
+    //Excluded paths and broken symlinks are reported to the given callbacks. The walk ends early if cancel is set.
+    fn walk_files<'a>(
+        &'a self,
+        src_path: &Path,
+        mut on_skip: impl FnMut(&Path, InclusionDecision) + 'a,
+        mut on_broken_symlink: impl FnMut(&Path) + 'a,
+        cancel: Option<&'a AtomicBool>,
+    ) -> impl Iterator<Item = Result<PathBuf, walkdir::Error>> + 'a {
+        //Walk from the verbatim form of src_path so that files nested deeper than MAX_PATH can be read on Windows.
+        //The paths found are converted back before they are filtered or yielded.
+        let walk = WalkDir::new(verbatim_path(src_path)).same_file_system(self.same_file_system);
+        let walk = match self.max_files_per_directory {
+            Some(_) => walk.sort_by_file_name(),
+            None => walk,
+        };
+
+        //The number of files taken from each directory so far.
+        let mut dir_file_counts = HashMap::<PathBuf, usize>::new();
+
+        walk.into_iter()
+            .filter_entry(move |entry| {
+                let path = strip_verbatim(entry.path());
+                let decision = self.walk_decision(&path).or_else(|| {
+                    if entry.file_type().is_dir() {
+                        None
+                    } else {
+                        self.file_decision(entry.path(), &path)
+                    }
+                });
+                match decision {
+                    Some(decision) => {
+                        on_skip(&path, decision);
+                        false
+                    }
+                    None => true,
+                }
+            })
+            .take_while(move |_| !cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)))
+            .filter_map(move |dir_entry_res| match dir_entry_res {
+                Err(e) => Some(Err(e)),
+                Ok(dir_entry) => {
+                    let fs_path = dir_entry.path();
+                    let src_path = strip_verbatim(fs_path);
+                    if fs_path.is_file() {
+                        if let (Some(max_files), Some(dir)) = (self.max_files_per_directory, src_path.parent()) {
+                            let count = dir_file_counts.entry(dir.to_path_buf()).or_default();
+                            if *count >= max_files {
+                                return None;
+                            }
+                            *count += 1;
+                        }
+                        Some(Ok(src_path.into_owned()))
+                    } else {
+                        if dir_entry.path_is_symlink() && !fs_path.exists() {
+                            on_broken_symlink(&src_path);
+                        }
+                        None
+                    }
+                }
+            })
+    }
+
+    /// Lazily visit the filesystem, yielding the same files as [project_using_fs][Self::project_using_fs]
+    /// without holding them all in memory. This is useful for very large libraries where the files can be processed
+    /// one at a time. The same exclusions are applied, but this does not change the state of the projection, so
+    /// paths skipped and warnings are not recorded.
+    ///
+    /// Any src_path or excl_path which does not exist, and any error encountered while walking the filesystem, is
+    /// yielded as an error and enumeration continues. If [canonicalize_paths][Self::canonicalize_paths] is enabled
+    /// then each file is canonicalized, but files reachable through more than one src_path may be yielded more than
+    /// once.
+    pub fn iter_fs(&self) -> impl Iterator<Item = Result<PathBuf, FileProjectionError>> + '_ {
+        use FileProjectionError::*;
+
+        let missing_excl_paths = self
+            .excl_paths
+            .iter()
+            .filter(|excl_path| !excl_path.exists())
+            .map(|excl_path| Err(ExclPathNotFound(excl_path.clone())));
+
+        let files = self.src_paths.iter().flat_map(move |src_path| {
+            let walk: Box<dyn Iterator<Item = _>> = if src_path.exists() {
+                Box::new(
+                    self.walk_files(src_path, |_, _| (), |_| (), None)
+                        .map(|res| res.map_err(|e| Enumeration(e.to_string()))),
+                )
+            } else {
+                Box::new(std::iter::once(Err(PathNotFound(src_path.clone()))))
+            };
+            walk
+        });
+
+        missing_excl_paths.chain(files).map(move |res| match res {
+            Ok(src_path) if self.canonicalize => Ok(std::fs::canonicalize(&src_path).unwrap_or(src_path)),
+            res => res,
+        })
+    }
+
+    /// Enumerate files by filtering a list of paths. The paths are normalized in the same way as
+    /// [new][Self::new].
     ///
     /// # Panics
     /// This function will panic if either project_using_fs or project_using_list
@@ -210,8 +957,8 @@ impl FileProjection {
             Unprojected => {
                 self.projected_files = list
                     .into_iter()
-                    .filter(|p| self.contains(p))
-                    .map(|x| x.as_ref().to_path_buf())
+                    .map(normalize_path)
+                    .filter(|p| self.raw_includes(p) && !self.raw_excludes(p) && !self.skip_set.contains(p))
                     .collect();
 
                 self.state = ProjectedUsingList;
@@ -219,6 +966,51 @@ impl FileProjection {
         }
     }
 
+    /// Consume the projection and return the set of all enumerated files, without copying it.
+    /// File enumeration must have already taken place.
+    ///
+    /// # Panics
+    /// This function will panic if enumeration has not occurred.
+    pub fn into_projected_files(self) -> HashSet<PathBuf> {
+        match self.state {
+            Unprojected => panic!(
+                "FileProjection::into_projected_files called without have first projected. Call project_using_fs or \
+                 project_using_fs first."
+            ),
+            ProjectedUsingFs | ProjectedUsingList => self.projected_files,
+        }
+    }
+
+    /// Returns true if projection has been done, either from the filesystem or from a list.
+    pub fn has_been_projected(&self) -> bool {
+        self.state != Unprojected
+    }
+
+    /// Returns true if projection has been done using [project_using_fs][Self::project_using_fs].
+    pub fn was_projected_from_fs(&self) -> bool {
+        self.state == ProjectedUsingFs
+    }
+
+    /// Returns true if projection has been done using [project_using_list][Self::project_using_list].
+    pub fn was_projected_from_list(&self) -> bool {
+        self.state == ProjectedUsingList
+    }
+
+    /// The (normalized) paths whose children are included in the projection.
+    pub fn src_paths(&self) -> &[PathBuf] {
+        &self.src_paths
+    }
+
+    /// The (normalized) paths whose children are excluded from the projection.
+    pub fn excl_paths(&self) -> &[PathBuf] {
+        &self.excl_paths
+    }
+
+    /// The (normalized) file extensions which are excluded from the projection.
+    pub fn excl_extensions(&self) -> &[OsString] {
+        &self.excl_exts
+    }
+
     /// Obtain the set of all enumerated files. File enumeration must have already
     /// taken place.
     ///
@@ -226,15 +1018,408 @@ impl FileProjection {
     /// This function will panic if enumeration has not occurred.
     pub fn projected_files(&self) -> &HashSet<PathBuf> {
         match self.state {
-            Unprojected => panic!("FileProjection::projected_files called without have first projected. Call project_using_fs or project_using_fs first."),
-            ProjectedUsingFs |
-            ProjectedUsingList => &self.projected_files,
+            Unprojected => panic!(
+                "FileProjection::projected_files called without have first projected. Call project_using_fs or \
+                 project_using_fs first."
+            ),
+            ProjectedUsingFs | ProjectedUsingList => &self.projected_files,
+        }
+    }
+
+    /// Combine two projections into one whose projected files are the union of the projected files
+    /// of both. The src_paths and excl_paths of both are merged, so that [contains][Self::contains]
+    /// returns true for any path contained by either projection.
+    ///
+    /// Only extensions excluded by both projections are retained as excluded extensions.
+    ///
+    /// # Return values
+    /// Returns Err(NotProjected) if either projection has not been projected.
+    ///
+    /// Returns Err(ProjectionConflict) if an excl_path of one projection overlaps with a src_path of
+    /// the other, as the merged projection would then exclude paths that the other projection contains.
+    pub fn union(&self, other: &Self) -> Result<Self, FileProjectionError> {
+        self.check_projected()?;
+        other.check_projected()?;
+
+        self.check_conflicts(other)?;
+        other.check_conflicts(self)?;
+
+        let src_paths = self
+            .src_paths
+            .iter()
+            .chain(other.src_paths.iter())
+            .cloned()
+            .unique()
+            .collect();
+        let excl_paths = self
+            .excl_paths
+            .iter()
+            .chain(other.excl_paths.iter())
+            .cloned()
+            .unique()
+            .collect();
+        let excl_exts = self
+            .excl_exts
+            .iter()
+            .filter(|ext| {
+                other
+                    .excl_exts
+                    .iter()
+                    .any(|other_ext| other_ext.eq_ignore_ascii_case(ext))
+            })
+            .cloned()
+            .collect();
+
+        let mut ret = Self::with_config(src_paths, excl_paths, excl_exts);
+        ret.canonical_src_paths = self
+            .canonical_src_paths
+            .iter()
+            .chain(&other.canonical_src_paths)
+            .cloned()
+            .unique()
+            .collect();
+        ret.canonical_excl_paths = self
+            .canonical_excl_paths
+            .iter()
+            .chain(&other.canonical_excl_paths)
+            .cloned()
+            .unique()
+            .collect();
+        ret.projected_files = self.projected_files.union(&other.projected_files).cloned().collect();
+        ret.state = self.state.max(other.state);
+        ret.warnings = self.warnings.iter().chain(other.warnings.iter()).cloned().collect();
+        ret.missing_src_paths = self
+            .missing_src_paths
+            .iter()
+            .chain(&other.missing_src_paths)
+            .cloned()
+            .unique()
+            .collect();
+        ret.projection_time = self.projection_time + other.projection_time;
+        Ok(ret)
+    }
+
+    /// Combine two projections into one whose projected files are the intersection of the projected files
+    /// of both. The src_paths of the new projection are the overlapping regions of the src_paths of both,
+    /// and the excl_paths and excluded extensions of both are merged, so that [contains][Self::contains]
+    /// returns true only for paths contained by both projections.
+    ///
+    /// # Return values
+    /// Returns Err(NotProjected) if either projection has not been projected.
+    pub fn intersection(&self, other: &Self) -> Result<Self, FileProjectionError> {
+        self.check_projected()?;
+        other.check_projected()?;
+
+        let src_paths = find_overlapping_regions(&self.src_paths, &other.src_paths);
+        let excl_paths = self
+            .excl_paths
+            .iter()
+            .chain(other.excl_paths.iter())
+            .cloned()
+            .unique()
+            .collect();
+        let excl_exts = self
+            .excl_exts
+            .iter()
+            .chain(other.excl_exts.iter())
+            .cloned()
+            .unique()
+            .collect();
+
+        let mut ret = Self::with_config(src_paths, excl_paths, excl_exts);
+        ret.canonical_src_paths = find_overlapping_regions(&self.canonical_src_paths, &other.canonical_src_paths);
+        ret.canonical_excl_paths = self
+            .canonical_excl_paths
+            .iter()
+            .chain(&other.canonical_excl_paths)
+            .cloned()
+            .unique()
+            .collect();
+        ret.projected_files = self
+            .projected_files
+            .intersection(&other.projected_files)
+            .cloned()
+            .collect();
+        ret.state = self.state.max(other.state);
+        ret.warnings = self.warnings.iter().chain(other.warnings.iter()).cloned().collect();
+        ret.missing_src_paths = self
+            .missing_src_paths
+            .iter()
+            .chain(&other.missing_src_paths)
+            .cloned()
+            .unique()
+            .collect();
+        ret.projection_time = self.projection_time + other.projection_time;
+        Ok(ret)
+    }
+
+    fn check_projected(&self) -> Result<(), FileProjectionError> {
+        match self.state {
+            Unprojected => Err(FileProjectionError::NotProjected),
+            ProjectedUsingFs | ProjectedUsingList => Ok(()),
         }
     }
 
-    fn has_ignore_ext(&self, src_path: &Path) -> bool {
+    //An excl_path of self conflicts with other if it excludes a src_path of other, or if it is
+    //inside a region which other contains.
+    fn check_conflicts(&self, other: &Self) -> Result<(), FileProjectionError> {
+        self.excl_paths
+            .iter()
+            .find_map(|excl_path| {
+                other
+                    .src_paths
+                    .iter()
+                    .find(|src_path| {
+                        src_path.starts_with(excl_path)
+                            || (excl_path.starts_with(src_path) && !other.raw_excludes(excl_path))
+                    })
+                    .map(|src_path| (src_path.to_path_buf(), excl_path.to_path_buf()))
+            })
+            .map(|(src_path, excl_path)| {
+                <Result<(), _>>::Err(FileProjectionError::ProjectionConflict { src_path, excl_path })
+            })
+            .transpose()?;
+
+        Ok(())
+    }
+
+    /// Remove projected files which refer to the same file on disk as another projected file
+    /// (e.g. through symlinks or hard links). All projected files are replaced by their canonical
+    /// paths using [std::fs::canonicalize]. On Unix, files are considered the same if they have the same
+    /// device and inode numbers. On other platforms, files are considered the same if they have the same
+    /// canonical path.
+    ///
+    /// Returns the number of files removed.
+    ///
+    /// # Panics
+    /// This function will panic if enumeration has not occurred.
+    pub fn deduplicate_by_inode(&mut self) -> usize {
+        let count_before = self.projected_files().len();
+
+        let mut seen_identities = HashSet::new();
+        let deduplicated_files = self
+            .projected_files
+            .iter()
+            .sorted()
+            .filter_map(|path| {
+                let canonical_path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                let identity = FileIdentity::of(&canonical_path);
+                seen_identities.insert(identity).then_some(canonical_path)
+            })
+            .collect::<HashSet<_>>();
+
+        self.projected_files = deduplicated_files;
+        count_before - self.projected_files.len()
+    }
+
+    fn canonicalize_roots(
+        roots: &[PathBuf],
+        not_found: impl Fn(PathBuf) -> FileProjectionError,
+    ) -> Result<Vec<PathBuf>, FileProjectionError> {
+        roots
+            .iter()
+            .map(|root| std::fs::canonicalize(root).map_err(|_| not_found(root.clone())))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|roots| roots.into_iter().unique().collect())
+    }
+
+    //Canonicalize the projected files. A file which is a symlink may resolve to a path which is excluded or outside
+    //every src_path, so each canonical path is checked again, and is skipped if it is no longer contained.
+    fn canonicalize_files(&mut self, files: HashSet<PathBuf>) -> HashSet<PathBuf> {
+        let mut errs = vec![];
+        let mut skipped = vec![];
+        let canonical_files = files
+            .into_iter()
+            .filter_map(|path| match std::fs::canonicalize(&path) {
+                Ok(canonical_path) => match self.canonical_decision(&canonical_path) {
+                    Some(decision) => {
+                        skipped.push((canonical_path, decision));
+                        None
+                    }
+                    None => Some(canonical_path),
+                },
+                Err(e) => {
+                    errs.push(FileProjectionError::Canonicalize {
+                        path: path.clone(),
+                        error: e.to_string(),
+                    });
+                    Some(path)
+                }
+            })
+            .collect();
+
+        let skipped_limit = self.skipped_limit.unwrap_or(0).saturating_sub(self.skipped.len());
+        self.skipped.extend(skipped.into_iter().take(skipped_limit));
+        self.canonicalize_errors = errs;
+        canonical_files
+    }
+
+    //Decide whether a canonicalized file is still contained by the projection. Returns None if it is.
+    fn canonical_decision(&self, canonical_path: &Path) -> Option<InclusionDecision> {
+        match self.excluding_path(canonical_path) {
+            Some(excl_path) => Some(InclusionDecision::ExcludedByPath(excl_path.to_path_buf())),
+            None if !self.raw_includes(canonical_path) => Some(InclusionDecision::NotUnderSrcPaths),
+            None => None,
+        }
+    }
+
+    fn ignore_ext(&self, src_path: &Path) -> Option<&OsStr> {
         self.excl_exts
             .iter()
-            .any(|ext| src_path.extension().unwrap_or_default().eq_ignore_ascii_case(ext))
+            .find(|ext| src_path.extension().unwrap_or_default().eq_ignore_ascii_case(ext))
+            .map(OsString::as_os_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::TestTree;
+
+    fn new_projection(src_paths: &[&str], excl_paths: &[&str]) -> FileProjection {
+        FileProjection::new(src_paths, excl_paths, Vec::<&OsStr>::new()).unwrap()
+    }
+
+    #[test]
+    fn contains_does_not_match_sibling_prefixes() {
+        let projection = new_projection(&["/a/foo"], &[]);
+
+        assert!(projection.contains("/a/foo/video.mp4"));
+        assert!(!projection.contains("/a/foobar"));
+        assert!(!projection.contains("/a/foobar/video.mp4"));
+        assert!(!projection.contains("/a/fo"));
+    }
+
+    #[test]
+    fn contains_does_not_match_sibling_prefixes_of_excl_paths() {
+        let projection = new_projection(&["/a"], &["/a/foo"]);
+
+        assert!(!projection.contains("/a/foo"));
+        assert!(!projection.contains("/a/foo/video.mp4"));
+        assert!(projection.contains("/a/foobar/video.mp4"));
+    }
+
+    #[test]
+    fn contains_exact_roots() {
+        let projection = new_projection(&["/a/foo"], &[]);
+
+        assert!(projection.contains("/a/foo"));
+        assert!(!projection.contains("/a"));
+
+        let projection = new_projection(&["/"], &[]);
+        assert!(projection.contains("/"));
+        assert!(projection.contains("/a/foo/video.mp4"));
+    }
+
+    #[test]
+    fn contains_skips_recently_modified_files() {
+        let tree = TestTree::new()
+            .file("old.mp4", 100, UNIX_EPOCH + Duration::from_secs(1000))
+            .file("new.mp4", 100, SystemTime::now());
+        let mut projection = FileProjection::new([tree.root()], Vec::<&Path>::new(), Vec::<&OsStr>::new()).unwrap();
+        assert!(projection.contains(tree.path("new.mp4")));
+
+        projection.set_skip_recently_modified(Duration::from_secs(3600));
+        assert!(projection.contains(tree.path("old.mp4")));
+        assert!(!projection.contains(tree.path("new.mp4")));
+        //Files which cannot be read are still contained, so that they are removed from the cache.
+        assert!(projection.contains(tree.path("deleted.mp4")));
+    }
+
+    #[test]
+    fn contains_applies_custom_filter() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new()
+            .file("small.mp4", 10, mtime)
+            .file("large.mp4", 100, mtime);
+        let mut projection = FileProjection::new([tree.root()], Vec::<&Path>::new(), Vec::<&OsStr>::new()).unwrap();
+
+        projection.set_custom_filter(|_path, metadata| metadata.len() >= 50);
+        assert!(projection.contains(tree.path("large.mp4")));
+        assert!(!projection.contains(tree.path("small.mp4")));
+        assert!(projection.contains(tree.path("deleted.mp4")));
+    }
+
+    #[test]
+    fn skipped_missing_src_paths_are_kept() {
+        let tree = TestTree::new().file("present/a.mp4", 100, UNIX_EPOCH + Duration::from_secs(1000));
+        let (present, missing) = (tree.path("present"), tree.path("missing"));
+        let mut projection =
+            FileProjection::new([&present, &missing], Vec::<&Path>::new(), Vec::<&OsStr>::new()).unwrap();
+        projection.set_missing_root_policy(MissingRootPolicy::Skip);
+        projection.project_using_fs().unwrap();
+
+        assert_eq!(projection.src_paths(), &[present.clone(), missing.clone()]);
+        assert_eq!(
+            projection.warnings(),
+            &[ProjectionWarning::MissingSrcPath(missing.clone())]
+        );
+        assert!(projection.contains(present.join("a.mp4")));
+        assert!(!projection.contains(missing.join("b.mp4")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn canonicalization_keeps_roots_and_rechecks_symlinked_files() {
+        use std::os::unix::fs::symlink;
+
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new()
+            .file("real/a.mp4", 100, mtime)
+            .file("real/skip/b.mp4", 100, mtime)
+            .file("outside/c.mp4", 100, mtime);
+        symlink(tree.path("real"), tree.path("link")).unwrap();
+        symlink(tree.path("outside/c.mp4"), tree.path("real/escaped.mp4")).unwrap();
+        symlink(tree.path("real/skip/b.mp4"), tree.path("real/excluded.mp4")).unwrap();
+        let (link, skip) = (tree.path("link"), tree.path("link/skip"));
+        let mut projection = FileProjection::new([&link], [&skip], Vec::<&OsStr>::new()).unwrap();
+        projection.canonicalize_paths(true);
+        projection.project_using_fs().unwrap();
+
+        let real = std::fs::canonicalize(tree.path("real")).unwrap();
+        assert_eq!(projection.projected_files(), &HashSet::from([real.join("a.mp4")]));
+        assert!(projection.contains(real.join("a.mp4")));
+        assert!(projection.contains(link.join("a.mp4")));
+        assert!(!projection.contains(real.join("skip/b.mp4")));
+        assert_eq!(projection.excl_paths(), &[skip]);
+        assert_eq!(projection.src_paths(), &[link]);
+    }
+
+    #[test]
+    fn excluded_mount_points_are_pruned_without_being_enumerated() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new()
+            .file("a.mp4", 100, mtime)
+            .file("snapshot/b.mp4", 100, mtime)
+            .file("snapshot/deep/c.mp4", 100, mtime);
+        let snapshot = tree.path("snapshot");
+        let mut projection = FileProjection::new([tree.root()], Vec::<&Path>::new(), Vec::<&OsStr>::new()).unwrap();
+        projection.set_same_file_system(true);
+        projection.set_excl_mount_points([&snapshot]);
+        projection.record_skipped(10);
+        projection.project_using_fs().unwrap();
+
+        assert_eq!(projection.projected_files(), &HashSet::from([tree.path("a.mp4")]));
+        //Only the mount point itself is skipped, because nothing under it is visited.
+        let excluded = InclusionDecision::ExcludedByPath(snapshot.clone());
+        assert_eq!(projection.skipped(), &[(snapshot.clone(), excluded.clone())]);
+        assert_eq!(projection.explain(&snapshot.join("deep/c.mp4")), excluded);
+    }
+
+    #[test]
+    fn contains_ignores_trailing_separators() {
+        let with_separator = new_projection(&["/a/foo/"], &["/a/foo/excl/"]);
+        let without_separator = new_projection(&["/a/foo"], &["/a/foo/excl"]);
+
+        for projection in &[with_separator, without_separator] {
+            assert!(projection.contains("/a/foo"));
+            assert!(projection.contains("/a/foo/"));
+            assert!(projection.contains("/a/foo/video.mp4"));
+            assert!(!projection.contains("/a/foobar/"));
+            assert!(!projection.contains("/a/foo/excl/"));
+            assert!(!projection.contains("/a/foo/excl/video.mp4"));
+        }
     }
 }
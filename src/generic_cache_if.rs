@@ -1,15 +1,167 @@
-use std::path::Path;
+use std::{
+    fmt,
+    fs::{self, File},
+    io,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use generic_filesystem_cache::*;
+use log::LevelFilter;
 use vid_dup_finder_lib::*;
 
-use crate::*;
+use crate::{cache_entry::CachedVideoData, cache_stats::StatsRecorder, *};
 
-pub struct GenericCacheIf {}
+/// How to retry creating a hash when it fails because the video could not be read, for instance because
+/// the network share it is stored on is temporarily unavailable. See
+/// [set_load_retry][crate::VideoHashFilesystemCacheBuilder::set_load_retry].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryConfig {
+    /// The maximum number of attempts to create each hash, including the first. The default of 1 means
+    /// hashes are never retried.
+    pub max_attempts: u32,
+
+    /// The delay before the first retry.
+    pub initial_delay: Duration,
+
+    /// The delay is multiplied by this value after each retry, up to a maximum of one hour. This must be a finite
+    /// number of at least 1, otherwise [build][crate::VideoHashFilesystemCacheBuilder::build] returns an error.
+    pub backoff_factor: f64,
+}
+
+//The longest delay between retries, however many attempts are made.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_delay: Duration::from_secs(0),
+            backoff_factor: 2.0,
+        }
+    }
+}
+
+/// The log target and levels used for messages about creating hashes. This allows messages from several caches in
+/// the same process to be told apart, or for some messages to be silenced by setting their level to
+/// [LevelFilter::Off]. See [set_log_config][crate::VideoHashFilesystemCacheBuilder::set_log_config].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    /// The target of all messages. The default is `hash_creation`.
+    pub target: String,
+
+    /// The level of messages when a hash is created. The default is `Info`.
+    pub inserted_level: LevelFilter,
+
+    /// The level of messages when it could not be determined whether a file is a video. The default is `Warn`.
+    pub unsure_is_video_level: LevelFilter,
+
+    /// The level of messages when a video is too short to be hashed. The default is `Warn`.
+    pub too_short_level: LevelFilter,
+
+    /// The level of messages when a video could not be processed. The default is `Warn`.
+    pub processing_error_level: LevelFilter,
+
+    /// The level of messages when creating a hash is retried. The default is `Warn`.
+    pub retry_level: LevelFilter,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            target: "hash_creation".to_string(),
+            inserted_level: LevelFilter::Info,
+            unsure_is_video_level: LevelFilter::Warn,
+            too_short_level: LevelFilter::Warn,
+            processing_error_level: LevelFilter::Warn,
+            retry_level: LevelFilter::Warn,
+        }
+    }
+}
+
+/// Creates a hash and stats from a video file. See [hash_loader][crate::VideoHashFilesystemCacheBuilder::hash_loader].
+///
+/// This is implemented for all functions and closures with the same signature as [load][HashLoader::load], so a
+/// loader which needs its own configuration (such as a different frame-sampling rate) can be a closure which
+/// captures it.
+pub trait HashLoader: Send + Sync {
+    /// Create the hash and stats of the video at ``src_path``.
+    fn load(&self, src_path: &Path) -> Result<(VideoHash, VideoStats), HashCreationErrorKind>;
+}
+
+impl<F> HashLoader for F
+where
+    F: Fn(&Path) -> Result<(VideoHash, VideoStats), HashCreationErrorKind> + Send + Sync,
+{
+    fn load(&self, src_path: &Path) -> Result<(VideoHash, VideoStats), HashCreationErrorKind> {
+        self(src_path)
+    }
+}
+
+impl fmt::Debug for dyn HashLoader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HashLoader")
+    }
+}
+
+pub struct GenericCacheIf {
+    stats: StatsRecorder,
+    retry: RetryConfig,
+    store_stats: bool,
+    log_config: LogConfig,
+    min_duration: Option<f64>,
+    hash_loader: Arc<dyn HashLoader>,
+    video_prober: Arc<dyn VideoProber>,
+}
 
 impl GenericCacheIf {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(
+        retry: RetryConfig,
+        store_stats: bool,
+        log_config: LogConfig,
+        min_duration: Option<f64>,
+        hash_loader: Arc<dyn HashLoader>,
+        video_prober: Arc<dyn VideoProber>,
+    ) -> Self {
+        Self {
+            stats: StatsRecorder::new(),
+            retry,
+            store_stats,
+            log_config,
+            min_duration,
+            hash_loader,
+            video_prober,
+        }
+    }
+
+    //Create hashes using vid_dup_finder_lib.
+    pub fn default_hash_loader(src_path: &Path) -> Result<(VideoHash, VideoStats), HashCreationErrorKind> {
+        VideoHash::from_path_with_stats(src_path)
+    }
+
+    fn log(&self, level: LevelFilter, args: fmt::Arguments) {
+        if let Some(level) = level.to_level() {
+            log!(target: &self.log_config.target, level, "{}", args);
+        }
+    }
+
+    //A file which was deleted while its hash was being created is removed from the cache by the caller.
+    fn is_deleted(src_path: &Path) -> bool {
+        matches!(fs::metadata(src_path), Err(e) if e.kind() == io::ErrorKind::NotFound)
+    }
+
+    //A failure is treated as transient if the file could not even be opened. Errors from videos which
+    //can be opened (e.g. too short, or failed to decode) will not change if retried.
+    fn is_transient(src_path: &Path, e: &HashCreationErrorKind) -> bool {
+        match e {
+            HashCreationErrorKind::VideoLength(_) => false,
+            _ => File::open(src_path).is_err(),
+        }
+    }
+
+    pub fn stats(&self) -> &StatsRecorder {
+        &self.stats
     }
 }
 
@@ -17,30 +169,103 @@ impl CacheInterface for GenericCacheIf {
     type T = CacheEntry;
 
     fn load(&self, src_path: impl AsRef<Path>) -> Self::T {
-        let new_entry = VideoHash::from_path_with_stats(src_path);
+        let src_path = src_path.as_ref();
 
+        //When called from a bulk update, the bulk update's span is entered on the worker thread,
+        //so this span is its child.
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "hash_creation",
+            path = %src_path.display(),
+            duration_ms = tracing::field::Empty,
+            outcome = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        #[cfg(feature = "tracing")]
+        let load_start = Instant::now();
+
+        let mut attempt = 1;
+        let mut delay = self.retry.initial_delay;
+
+        let new_entry = loop {
+            let start = Instant::now();
+            let new_entry = self.hash_loader.load(src_path);
+            //A deleted file will not reappear if retried, and no hash was created for it.
+            if new_entry.is_err() && Self::is_deleted(src_path) {
+                break new_entry;
+            }
+            self.stats.record_hash_time(start.elapsed());
+
+            match &new_entry {
+                Err(e) if attempt < self.retry.max_attempts && Self::is_transient(src_path, e) => {
+                    self.log(
+                        self.log_config.retry_level,
+                        format_args!("Retrying : {} (attempt {}) -- {}", src_path.display(), attempt, e),
+                    );
+                    thread::sleep(delay);
+                    delay = Duration::try_from_secs_f64(delay.as_secs_f64() * self.retry.backoff_factor)
+                        .map_or(MAX_RETRY_DELAY, |next_delay| next_delay.min(MAX_RETRY_DELAY));
+                    attempt += 1;
+                }
+                _ => break new_entry,
+            }
+        };
+
+        //Hashes of videos shorter than the minimum duration are hidden when they are read, using their stats. If the
+        //stats are not stored then the duration is lost, so the video is treated as too short to hash here instead.
+        //The duration and resolution of these videos are kept, so that reports can still include them.
+        let (new_entry, probe) = match (new_entry, self.min_duration) {
+            (Ok((_hash, stats)), Some(min_duration)) if !self.store_stats && stats.duration < min_duration => (
+                Err(HashCreationErrorKind::VideoLength(src_path.to_path_buf())),
+                VideoProbe::from_stats(&stats),
+            ),
+            (Err(e @ HashCreationErrorKind::VideoLength(_)), _) => (Err(e), self.video_prober.probe(src_path)),
+            (new_entry, _) => (new_entry, None),
+        };
+
+        let log_config = &self.log_config;
         match &new_entry {
-            Ok((hash, _stats)) => info!(target: "hash_creation",
-                "inserting : {}",
-                hash.src_path().display()
+            Ok((hash, _stats)) => self.log(
+                log_config.inserted_level,
+                format_args!("inserting : {}", hash.src_path().display()),
             ),
-            Err(HashCreationErrorKind::DetermineVideo { src_path, error }) => warn!(target: "hash_creation",
-                    "not sure if video : {}. Error: {}",
-                    src_path.display(),
-                    error
+            Err(HashCreationErrorKind::DetermineVideo { src_path, error }) => self.log(
+                log_config.unsure_is_video_level,
+                format_args!("not sure if video : {}. Error: {}", src_path.display(), error),
             ),
-            Err(HashCreationErrorKind::VideoLength(src_path)) => warn!(target: "hash_creation",
-                    "Too short : {}",
-                    src_path.display(),
-
+            Err(HashCreationErrorKind::VideoLength(src_path)) => self.log(
+                log_config.too_short_level,
+                format_args!("Too short : {}", src_path.display()),
             ),
-            Err(HashCreationErrorKind::VideoProcessing { src_path, error }) => warn!(target: "hash_creation",
-                    "Proc err  : {} -- {}",
-                    src_path.display(),
-                    error
+            Err(HashCreationErrorKind::VideoProcessing { src_path, error }) => self.log(
+                log_config.processing_error_level,
+                format_args!("Proc err  : {} -- {}", src_path.display(), error),
             ),
         }
 
-        CacheEntry::from(new_entry)
+        #[cfg(feature = "tracing")]
+        {
+            span.record("duration_ms", load_start.elapsed().as_millis() as u64);
+            span.record("outcome", outcome_name(&new_entry));
+        }
+
+        let entry = CacheEntry::from(new_entry).with_probe(probe);
+        if self.store_stats {
+            entry
+        } else {
+            entry.map(|data| CachedVideoData { stats: None, ..data })
+        }
+    }
+}
+
+//A short name for the result of creating a hash, for use in tracing spans.
+#[cfg(feature = "tracing")]
+fn outcome_name(entry: &Result<(VideoHash, VideoStats), HashCreationErrorKind>) -> &'static str {
+    match entry {
+        Ok(_) => "hashed",
+        Err(HashCreationErrorKind::DetermineVideo { .. }) => "not_video",
+        Err(HashCreationErrorKind::VideoLength(_)) => "too_short",
+        Err(HashCreationErrorKind::VideoProcessing { .. }) => "processing_error",
     }
 }
@@ -18,10 +18,11 @@
 //! // Now create a video hash by calling get_update on the cache.
 //! # let vid_file_path = std::env::current_dir().unwrap().join(OsStr::new("../vid_dup_finder_lib/examples/vids/cat.1.mp4"));
 //! let video_hash : VideoHash = match cache.fetch_update(&vid_file_path) {
-//!    Ok(Some(Ok(hash)))  => hash,     // A hash was successfully created/fetched
-//!    Ok(None)            => panic!(), // None is returned when vid_file_path is removed from the filesystem
-//!    Ok(Some(Err(_e)))   => panic!(), // Ok(Some(Err())) is returned when an error occurs while creating a VideoHash
-//!    Err(cache_error)    => panic!(), //"All other Io errors")
+//!    Ok(FetchUpdateResult::Hashed(Ok(hash)))   => hash,     // A hash was successfully created/fetched
+//!    Ok(FetchUpdateResult::Deleted)            => panic!(), // Deleted is returned when vid_file_path is removed from the filesystem
+//!    Ok(FetchUpdateResult::Inaccessible(_e))   => panic!(), // Inaccessible is returned when vid_file_path cannot be read. The cache is unchanged.
+//!    Ok(FetchUpdateResult::Hashed(Err(_e)))    => panic!(), // Hashed(Err()) is returned when an error occurs while creating a VideoHash
+//!    Err(cache_error)                          => panic!(), //"All other Io errors")
 //! };
 //!
 //! // Subsequent calls will fetch the hash from the cache instead of creating it from the filesystem.
@@ -69,21 +70,88 @@
 //! // otherwise changes since the last save will be lost.
 //! cache.save().unwrap()
 //! ```
+//!
+//! # Upgrading from earlier versions
+//! Earlier versions stored the cache using the `generic_filesystem_cache` crate. Cache files written by those
+//! versions are still loaded, and are rewritten in the current format by the next save, after which they can no
+//! longer be read by earlier versions. [FsCacheErrorKind], which was previously defined by `generic_filesystem_cache`,
+//! is now defined in this crate, so code which names it should import it from here instead.
+//!
+//! # Metrics
+//! When the `metrics` cargo feature is enabled, the following metrics are recorded using the
+//! [metrics](https://docs.rs/metrics) facade, so that they can be exported to e.g. Prometheus by any
+//! metrics recorder. Nothing is recorded when the feature is disabled.
+//!
+//! * `vhfc_entries` (gauge): The number of entries in the cache. Updated after each
+//!   [update_using_fs][VideoHashFilesystemCache::update_using_fs] and [save][VideoHashFilesystemCache::save].
+//! * `vhfc_error_entries` (gauge): The number of entries recording that a hash could not be created. Updated at the
+//!   same time as `vhfc_entries`.
+//! * `vhfc_hashes_computed_total` (counter): The number of attempts to create a hash.
+//! * `vhfc_hash_duration_seconds` (histogram): The time taken by each attempt to create a hash.
+//! * `vhfc_cache_hits_total` (counter): The number of lookups which were answered from the cache.
+//! * `vhfc_cache_misses_total` (counter): The number of lookups for which there was no valid cache entry, i.e.
+//!   the path was not cached or (for [fetch_update][VideoHashFilesystemCache::fetch_update]) the file was modified.
+//! * `vhfc_saves_total` (counter): The number of times the cache file was written, including automatic saves.
+//! * `vhfc_save_bytes` (histogram): The size of the cache file written by each save.
 
 #[macro_use]
 extern crate log;
 
+pub(crate) mod base_fs_cache;
 pub(crate) mod cache_entry;
+pub(crate) mod cache_metrics;
+pub(crate) mod cache_snapshot;
+pub(crate) mod cache_stats;
+#[cfg(feature = "csv")]
+pub(crate) mod csv_export;
+pub(crate) mod duplicate_ranking;
 pub(crate) mod errors;
 pub(crate) mod file_projection;
 pub(crate) mod generic_cache_if;
+pub(crate) mod path_normalization;
+pub(crate) mod processing_fs_cache;
+#[cfg(feature = "ron")]
+pub(crate) mod ron_export;
+pub(crate) mod scoped_cache;
+#[cfg(feature = "sqlite")]
+pub(crate) mod sqlite_export;
+pub(crate) mod suspect_entries;
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) mod test_util;
+pub(crate) mod verify;
 pub(crate) mod video_hash_filesystem_cache;
+pub(crate) mod video_hash_filesystem_cache_builder;
+pub(crate) mod video_probe;
 
 //internal exports
 pub(crate) use cache_entry::CacheEntry;
+pub(crate) use path_normalization::{canonicalize_path, normalize_path, strip_verbatim, verbatim_path};
+pub(crate) use processing_fs_cache::{CacheInterface, FetchUpdateOutcome, ProcessingFsCache};
 
 //exports
-pub use crate::video_hash_filesystem_cache::VideoHashFilesystemCache;
-pub use errors::VdfCacheError;
+#[allow(deprecated)]
+pub use crate::video_hash_filesystem_cache::DirectoryEntries;
+pub use crate::video_hash_filesystem_cache::{
+    CompactReport, EntryKind, FetchUpdateResult, HashEntries, ImportReport, NewHashes, SampleReport, UpdateDelta,
+    UpdateOptions, VideoHashFilesystemCache,
+};
+pub use crate::video_hash_filesystem_cache_builder::{UpdateOrder, VideoHashFilesystemCacheBuilder};
+pub use base_fs_cache::{EntryMeta, LoadReport, QuarantinedEntry};
+pub use cache_snapshot::CacheSnapshot;
+pub use cache_stats::{CacheStats, ScanTimings};
+pub use duplicate_ranking::{RankCriterion, RankedEntry};
+pub use errors::{ErrorHandler, FsCacheErrorKind, IoErrorInfo, VdfCacheError};
 pub use file_projection::FileProjection;
 pub use file_projection::FileProjectionError;
+pub use file_projection::InclusionDecision;
+pub use file_projection::MissingRootPolicy;
+pub use file_projection::ProjectionWarning;
+pub use generic_cache_if::HashLoader;
+pub use generic_cache_if::{LogConfig, RetryConfig};
+pub use processing_fs_cache::{CurrentMeta, StoredMeta};
+pub use scoped_cache::ScopedCache;
+pub use suspect_entries::{SuspectChecks, SuspectPredicate, SuspectReason};
+#[cfg(any(test, feature = "test-util"))]
+pub use test_util::{assert_cached, assert_not_cached, FakeLoader, TestTree};
+pub use verify::{FixStrategy, VerifyProblem};
+pub use video_probe::{VideoProbe, VideoProber};
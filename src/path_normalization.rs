@@ -0,0 +1,219 @@
+#[cfg(windows)]
+use std::ffi::OsString;
+use std::{
+    borrow::Cow,
+    env, fs,
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+};
+
+//The current directory, read when a relative path is first normalized.
+static CURRENT_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Make a path absolute (relative to the current directory) and lexically remove any `.` and `..`
+/// components, without visiting the filesystem. This allows paths with different representations
+/// to be compared with each other.
+///
+/// Because symlinks are not resolved, `a/link/..` is normalized to `a`, even if `link` points elsewhere.
+///
+/// The current directory is only read once, the first time a relative path is normalized, so changing it
+/// afterwards does not change how relative paths are resolved.
+pub(crate) fn normalize_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        match CURRENT_DIR.get_or_init(|| env::current_dir().ok()) {
+            Some(current_dir) => current_dir.join(path),
+            None => path.to_path_buf(),
+        }
+    };
+
+    let mut ret = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match ret.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    ret.pop();
+                }
+                //".." at the root is the root.
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => (),
+                _ => ret.push(component),
+            },
+            _ => ret.push(component),
+        }
+    }
+
+    ret
+}
+
+/// Resolve symlinks in a path using the filesystem. If that is not possible (e.g. because the path does not exist),
+/// fall back to [normalize_path].
+pub(crate) fn canonicalize_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    fs::canonicalize(path).unwrap_or_else(|_| normalize_path(path))
+}
+
+/// The form of a normalized path to use when accessing the filesystem. On Windows, absolute paths are converted to
+/// verbatim paths (starting with `\\?\`), which are not limited to `MAX_PATH` (260) characters. This allows files in
+/// deeply nested directories to be read. On other platforms the path is returned unchanged.
+///
+/// Verbatim paths must not be used as cache keys. Use [strip_verbatim] to convert them back.
+pub(crate) fn verbatim_path(path: &Path) -> Cow<'_, Path> {
+    #[cfg(windows)]
+    {
+        use std::path::Prefix::*;
+
+        let mut components = path.components();
+        let mut verbatim = OsString::from(r"\\?\");
+        match components.next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Disk(_) => verbatim.push(prefix.as_os_str()),
+                UNC(server, share) => {
+                    verbatim.push(r"UNC\");
+                    verbatim.push(server);
+                    verbatim.push(r"\");
+                    verbatim.push(share);
+                }
+                //Already verbatim, or a device path.
+                _ => return Cow::Borrowed(path),
+            },
+            _ => return Cow::Borrowed(path),
+        }
+
+        //Verbatim paths are not parsed by Windows, so they must only contain backslashes and no `.` or `..`.
+        let mut is_root = true;
+        for component in components {
+            match component {
+                Component::RootDir => (),
+                Component::Normal(name) => {
+                    verbatim.push(r"\");
+                    verbatim.push(name);
+                    is_root = false;
+                }
+                _ => return Cow::Borrowed(path),
+            }
+        }
+        if is_root {
+            verbatim.push(r"\");
+        }
+
+        Cow::Owned(PathBuf::from(verbatim))
+    }
+
+    #[cfg(not(windows))]
+    Cow::Borrowed(path)
+}
+
+/// Convert a path created by [verbatim_path] (or a path found by walking the filesystem from one) back to its
+/// ordinary form. Other paths are returned unchanged.
+pub(crate) fn strip_verbatim(path: &Path) -> Cow<'_, Path> {
+    #[cfg(windows)]
+    {
+        use std::path::Prefix::*;
+
+        let mut components = path.components();
+        let mut ret = match components.next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                VerbatimDisk(disk) => OsString::from(format!("{}:", disk as char)),
+                VerbatimUNC(server, share) => {
+                    let mut ret = OsString::from(r"\\");
+                    ret.push(server);
+                    ret.push(r"\");
+                    ret.push(share);
+                    ret
+                }
+                _ => return Cow::Borrowed(path),
+            },
+            _ => return Cow::Borrowed(path),
+        };
+        let mut is_root = true;
+        for component in components {
+            if let Component::Normal(name) = component {
+                ret.push(r"\");
+                ret.push(name);
+                is_root = false;
+            }
+        }
+        if is_root {
+            ret.push(r"\");
+        }
+
+        Cow::Owned(PathBuf::from(ret))
+    }
+
+    #[cfg(not(windows))]
+    Cow::Borrowed(path)
+}
+
+#[cfg(all(test, windows))]
+mod windows_tests {
+    use super::*;
+
+    #[test]
+    fn verbatim_round_trips_paths_longer_than_max_path() {
+        let long_path = PathBuf::from(r"C:\videos")
+            .join("a".repeat(100))
+            .join("b".repeat(100))
+            .join("c".repeat(100))
+            .join("video.mp4");
+        assert!(long_path.as_os_str().len() > 260);
+
+        let verbatim = verbatim_path(&long_path);
+        assert!(verbatim.as_os_str().to_string_lossy().starts_with(r"\\?\C:\videos\"));
+        assert_eq!(strip_verbatim(&verbatim), long_path);
+    }
+
+    #[test]
+    fn files_longer_than_max_path_are_projected_with_ordinary_keys() {
+        let root = normalize_path(env::temp_dir().join(format!("vhfc_long_path_{}", std::process::id())));
+        let dir = root.join("a".repeat(100)).join("b".repeat(100)).join("c".repeat(100));
+        let file = dir.join("video.mp4");
+        assert!(file.as_os_str().len() > 260);
+
+        fs::create_dir_all(verbatim_path(&dir)).unwrap();
+        fs::write(verbatim_path(&file), b"video").unwrap();
+
+        let mut projection = crate::FileProjection::new([&root], Vec::<&Path>::new(), Vec::<&str>::new()).unwrap();
+        let errors = projection.project_using_fs().unwrap();
+        let projected_files = projection.projected_files().clone();
+        fs::remove_dir_all(verbatim_path(&root)).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(projected_files.into_iter().collect::<Vec<_>>(), vec![file]);
+    }
+
+    #[test]
+    fn verbatim_round_trips_unc_paths() {
+        let unc_path = PathBuf::from(r"\\server\share\videos\video.mp4");
+
+        let verbatim = verbatim_path(&unc_path);
+        assert_eq!(verbatim.as_os_str(), r"\\?\UNC\server\share\videos\video.mp4");
+        assert_eq!(strip_verbatim(&verbatim), unc_path);
+
+        let unc_root = PathBuf::from(r"\\server\share\");
+        assert_eq!(strip_verbatim(&verbatim_path(&unc_root)), unc_root);
+    }
+
+    #[test]
+    fn strip_verbatim_writes_back_ordinary_keys() {
+        assert_eq!(
+            strip_verbatim(Path::new(r"\\?\C:\videos\video.mp4")),
+            Path::new(r"C:\videos\video.mp4")
+        );
+        assert_eq!(strip_verbatim(Path::new(r"\\?\C:\")), Path::new(r"C:\"));
+        assert_eq!(
+            strip_verbatim(Path::new(r"\\?\UNC\server\share\video.mp4")),
+            Path::new(r"\\server\share\video.mp4")
+        );
+
+        //Keys found by walking from a verbatim path are the same as keys made from the ordinary path.
+        let key = normalize_path(r"C:\videos\..\videos\video.mp4");
+        assert_eq!(strip_verbatim(&verbatim_path(&key)), key);
+
+        //Paths which are not verbatim are left alone.
+        assert!(matches!(strip_verbatim(&key), Cow::Borrowed(_)));
+    }
+}
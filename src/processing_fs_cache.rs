@@ -0,0 +1,419 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
+
+use crate::base_fs_cache::{BaseFsCache, CacheValue, Entries, FsCacheEntry, LoadReport};
+use crate::*;
+
+/// Defines how a value is created from a file on the filesystem, so that
+/// [ProcessingFsCache] can (re)create values when files are added or modified.
+pub trait CacheInterface {
+    type T;
+
+    fn load(&self, src_path: impl AsRef<Path>) -> Self::T;
+}
+
+/// The outcome of [ProcessingFsCache::fetch_update].
+pub enum FetchUpdateOutcome<T> {
+    /// The cached value for the file, which has not been modified.
+    Value(T),
+
+    /// A newly loaded value for the file. ``replaced`` is true if it replaced a value which was out of date.
+    Loaded { value: T, replaced: bool },
+
+    /// The file no longer exists. ``removed`` is true if it was in the cache and has been removed.
+    Deleted { removed: bool },
+
+    /// The file's metadata could not be read. The cache was not changed.
+    Inaccessible(io::Error),
+
+    /// A new value was loaded for the file, but was not accepted (see [reload_if][ProcessingFsCache::reload_if]).
+    /// The cache was not changed.
+    Rejected(T),
+}
+
+/// The metadata stored in the cache for a file when its value was created. See
+/// [set_staleness_fn][crate::VideoHashFilesystemCache::set_staleness_fn].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoredMeta {
+    /// The modification time of the file when its value was created.
+    pub mtime: SystemTime,
+}
+
+/// The current metadata of a file on the filesystem. See
+/// [set_staleness_fn][crate::VideoHashFilesystemCache::set_staleness_fn].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CurrentMeta {
+    /// The current modification time of the file.
+    pub mtime: SystemTime,
+
+    /// The current size of the file in bytes.
+    pub len: u64,
+}
+
+/// Decides whether the cached value for a file must be recreated.
+pub type StalenessFn = Box<dyn Fn(&Path, StoredMeta, CurrentMeta) -> bool + Send + Sync>;
+
+/// A [BaseFsCache] which creates its own values from files on the filesystem, and keeps
+/// them up to date according to the modification time of each file.
+pub struct ProcessingFsCache<I>
+where
+    I: CacheInterface,
+{
+    base: BaseFsCache<I::T>,
+    interface: I,
+    //None to compare modification times.
+    is_stale: RwLock<Option<StalenessFn>>,
+}
+
+impl<I> ProcessingFsCache<I>
+where
+    I: CacheInterface,
+    I::T: CacheValue,
+{
+    pub fn new(
+        cache_save_threshold: u32,
+        cache_path: PathBuf,
+        base_path: Option<&Path>,
+        interface: I,
+        strict_load: bool,
+        lazy_load: bool,
+    ) -> Result<Self, FsCacheErrorKind> {
+        Ok(Self {
+            base: BaseFsCache::new(cache_save_threshold, cache_path, base_path, strict_load, lazy_load)?,
+            interface,
+            is_stale: RwLock::new(None),
+        })
+    }
+
+    pub fn set_staleness_fn(&self, is_stale: StalenessFn) {
+        *self.is_stale.write().unwrap() = Some(is_stale);
+    }
+
+    pub fn set_save_retry(&mut self, retries: u32, backoff: std::time::Duration) {
+        self.base.set_save_retry(retries, backoff)
+    }
+
+    pub fn save(&self) -> Result<(), FsCacheErrorKind> {
+        self.base.save()
+    }
+
+    pub fn move_to(&self, new_path: PathBuf) -> Result<(), FsCacheErrorKind> {
+        self.base.move_to(new_path)
+    }
+
+    pub fn pending_saves(&self) -> u32 {
+        self.base.pending_saves()
+    }
+
+    pub fn flush_without_save(&self) {
+        self.base.flush_without_save()
+    }
+
+    pub fn interface(&self) -> &I {
+        &self.interface
+    }
+
+    pub fn keys(&self) -> Vec<PathBuf> {
+        self.base.keys()
+    }
+
+    pub fn count_contained(&self, keys: &[PathBuf]) -> usize {
+        self.base.count_contained(keys)
+    }
+
+    pub fn keys_where(&self, f: impl Fn(&I::T) -> bool) -> Vec<PathBuf> {
+        self.base.keys_where(f)
+    }
+
+    pub fn entries(&self) -> Vec<(PathBuf, FsCacheEntry<I::T>)> {
+        self.base.entries()
+    }
+
+    pub fn entries_in_order(&self) -> Vec<(PathBuf, FsCacheEntry<I::T>)> {
+        self.base.entries_in_order()
+    }
+
+    pub fn map_keys(&self, f: impl Fn(&Path, I::T) -> Option<(PathBuf, I::T)>) -> Result<usize, FsCacheErrorKind> {
+        self.base.map_keys(f)
+    }
+
+    pub fn rename(&self, old_key: &Path, new_key: PathBuf, value: I::T) -> Result<(), FsCacheErrorKind> {
+        self.base.rename(old_key, new_key, value)
+    }
+
+    pub fn contains_key(&self, key: &Path) -> bool {
+        self.base.contains_key(key)
+    }
+
+    pub fn fetch(&self, key: &Path) -> Result<I::T, FsCacheErrorKind> {
+        self.base.fetch(key)
+    }
+
+    pub fn fetch_entry(&self, key: &Path) -> Result<FsCacheEntry<I::T>, FsCacheErrorKind> {
+        self.base.fetch_entry(key)
+    }
+
+    pub fn insert_many(
+        &self,
+        entries: impl IntoIterator<Item = (PathBuf, FsCacheEntry<I::T>)>,
+    ) -> Result<usize, FsCacheErrorKind> {
+        self.base.insert_many(entries)
+    }
+
+    pub fn modify_values(&self, f: impl Fn(&Path, &mut I::T) -> bool) -> Result<usize, FsCacheErrorKind> {
+        self.base.modify_values(f)
+    }
+
+    pub fn remove(&self, key: &Path) -> Result<(), FsCacheErrorKind> {
+        self.base.remove(key)
+    }
+
+    pub fn remove_many(&self, keys: impl IntoIterator<Item = PathBuf>) -> Result<usize, FsCacheErrorKind> {
+        self.base.remove_many(keys)
+    }
+
+    pub fn drain(&self) -> Result<Entries<I::T>, FsCacheErrorKind> {
+        self.base.drain()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn count_values(&self, f: impl Fn(&I::T) -> bool) -> (usize, usize) {
+        self.base.count_values(f)
+    }
+
+    pub fn load_report(&self) -> &LoadReport {
+        self.base.load_report()
+    }
+
+    pub fn cache_path(&self) -> PathBuf {
+        self.base.cache_path()
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.base.shrink_to_fit()
+    }
+
+    pub fn memory_usage_estimate(&self) -> usize {
+        self.base.memory_usage_estimate()
+    }
+
+    pub fn set_user_data(&self, key: &Path, name: &str, value: String) -> Result<(), FsCacheErrorKind> {
+        self.base.set_user_data(key, name, value)
+    }
+
+    pub fn get_user_data(&self, key: &Path, name: &str) -> Option<String> {
+        self.base.get_user_data(key, name)
+    }
+
+    pub fn all_user_data(&self, key: &Path) -> BTreeMap<String, String> {
+        self.base.all_user_data(key)
+    }
+
+    #[cfg(any(feature = "csv", feature = "ron", feature = "sqlite"))]
+    pub fn user_data_map(&self) -> std::collections::HashMap<PathBuf, BTreeMap<String, String>> {
+        self.base.user_data_map()
+    }
+
+    #[cfg(any(feature = "csv", feature = "ron", feature = "sqlite"))]
+    pub fn extend_user_data(
+        &self,
+        data: impl IntoIterator<Item = (PathBuf, BTreeMap<String, String>)>,
+    ) -> Result<(), FsCacheErrorKind> {
+        self.base.extend_user_data(data)
+    }
+
+    pub fn insert_keyed(&self, key: String, value: I::T) -> Result<(), FsCacheErrorKind> {
+        self.base.insert_keyed(key, value)
+    }
+
+    pub fn fetch_keyed(&self, key: &str) -> Option<I::T> {
+        self.base.fetch_keyed(key)
+    }
+
+    pub fn remove_keyed(&self, key: &str) -> Result<bool, FsCacheErrorKind> {
+        self.base.remove_keyed(key)
+    }
+
+    pub fn keyed_keys(&self) -> Vec<String> {
+        self.base.keyed_keys()
+    }
+
+    pub fn entry_meta(&self, key: &Path) -> Option<EntryMeta> {
+        self.base.entry_meta(key)
+    }
+
+    pub fn touch(&self, key: &Path) {
+        self.base.touch(key)
+    }
+
+    pub fn remove_user_data(&self, key: &Path, name: &str) -> Result<Option<String>, FsCacheErrorKind> {
+        self.base.remove_user_data(key, name)
+    }
+
+    /// If the file at key has not been modified since it was cached, return the cached value.
+    /// If the file no longer exists, or is no longer a regular file, remove it from the cache and return Deleted. This
+    /// includes files which are deleted while their new value is loaded.
+    /// If the file exists but its metadata cannot be read, leave the cache unchanged and return Inaccessible.
+    /// Otherwise load a new value, insert it into the cache and return it.
+    pub fn fetch_update(&self, key: &Path) -> Result<FetchUpdateOutcome<I::T>, FsCacheErrorKind> {
+        self.fetch_update_impl(key, false, |_| true)
+    }
+
+    /// Like [fetch_update][Self::fetch_update], but load a new value even if the cached value is fresh.
+    pub fn reload(&self, key: &Path) -> Result<FetchUpdateOutcome<I::T>, FsCacheErrorKind> {
+        self.fetch_update_impl(key, true, |_| true)
+    }
+
+    /// Like [reload][Self::reload], but the new value is only inserted if ``accept`` returns true for it. Otherwise
+    /// the cached value is kept, and the new value is returned as [Rejected][FetchUpdateOutcome::Rejected].
+    pub fn reload_if(
+        &self,
+        key: &Path,
+        accept: impl Fn(&I::T) -> bool,
+    ) -> Result<FetchUpdateOutcome<I::T>, FsCacheErrorKind> {
+        self.fetch_update_impl(key, true, accept)
+    }
+
+    fn fetch_update_impl(
+        &self,
+        key: &Path,
+        force: bool,
+        accept: impl Fn(&I::T) -> bool,
+    ) -> Result<FetchUpdateOutcome<I::T>, FsCacheErrorKind> {
+        let metadata = match fs::metadata(verbatim_path(key)) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return self.remove_deleted(key),
+            Err(e) => return Ok(FetchUpdateOutcome::Inaccessible(e)),
+        };
+
+        //The file may have been replaced by a directory (or a symlink to one) with the same name.
+        //This is treated the same as deletion so that no value is created from something which is not a file.
+        if !metadata.is_file() {
+            return self.remove_deleted(key);
+        }
+
+        let mtime = match metadata.modified() {
+            Ok(mtime) => mtime,
+            Err(e) => return Ok(FetchUpdateOutcome::Inaccessible(e)),
+        };
+
+        let replaced = match self.base.fetch_entry(key) {
+            Ok(entry) if !force && self.is_fresh(key, &entry, mtime, metadata.len()) => {
+                cache_metrics::record_fetch(true);
+                self.base.touch(key);
+                return Ok(FetchUpdateOutcome::Value(entry.value));
+            }
+            Ok(_) => true,
+            Err(_) => false,
+        };
+
+        cache_metrics::record_fetch(false);
+        let value = self.interface.load(key);
+        if matches!(fs::metadata(verbatim_path(key)), Err(e) if e.kind() == io::ErrorKind::NotFound) {
+            return self.remove_deleted(key);
+        }
+        if !accept(&value) {
+            return Ok(FetchUpdateOutcome::Rejected(value));
+        }
+        self.base.insert(key.to_path_buf(), mtime, value.clone())?;
+        Ok(FetchUpdateOutcome::Loaded { value, replaced })
+    }
+
+    /// If [fetch_update][Self::fetch_update] would load a new value for key, i.e. the file exists but is not cached,
+    /// has been modified or has a placeholder value, return the modification time of the file.
+    pub fn needs_load(&self, key: &Path) -> Option<SystemTime> {
+        let metadata = fs::metadata(verbatim_path(key)).ok().filter(fs::Metadata::is_file)?;
+        let mtime = metadata.modified().ok()?;
+        match self.base.fetch_entry(key) {
+            Ok(entry) if self.is_fresh(key, &entry, mtime, metadata.len()) => None,
+            _ => Some(mtime),
+        }
+    }
+
+    //True if entry can be returned for the file at key, which has the given mtime and len.
+    pub fn is_fresh(&self, key: &Path, entry: &FsCacheEntry<I::T>, mtime: SystemTime, len: u64) -> bool {
+        if entry.value.is_placeholder() {
+            return false;
+        }
+
+        match &*self.is_stale.read().unwrap() {
+            Some(is_stale) => {
+                let stored = StoredMeta { mtime: entry.mtime };
+                let current = CurrentMeta { mtime, len };
+                !is_stale(key, stored, current)
+            }
+            None => entry.mtime == mtime,
+        }
+    }
+
+    fn remove_deleted(&self, key: &Path) -> Result<FetchUpdateOutcome<I::T>, FsCacheErrorKind> {
+        let removed = self.base.contains_key(key);
+        if removed {
+            self.base.remove(key)?;
+        }
+        Ok(FetchUpdateOutcome::Deleted { removed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct ReadToString;
+
+    impl CacheInterface for ReadToString {
+        type T = String;
+
+        fn load(&self, src_path: impl AsRef<Path>) -> String {
+            fs::read_to_string(src_path).unwrap()
+        }
+    }
+
+    struct DeleteWhileLoading;
+
+    impl CacheInterface for DeleteWhileLoading {
+        type T = String;
+
+        fn load(&self, src_path: impl AsRef<Path>) -> String {
+            fs::remove_file(src_path).unwrap();
+            String::new()
+        }
+    }
+
+    #[test]
+    fn files_deleted_while_loading_are_removed() {
+        let tree = TestTree::new();
+        let cache =
+            ProcessingFsCache::new(1000, tree.path("cache.bin"), None, DeleteWhileLoading, true, false).unwrap();
+        let key = tree.path("a.txt");
+        fs::write(&key, "a").unwrap();
+
+        let outcome = cache.fetch_update(&key).unwrap();
+        assert!(matches!(outcome, FetchUpdateOutcome::Deleted { removed: false }));
+        assert!(!cache.contains_key(&key));
+    }
+
+    #[test]
+    fn rejected_reload_keeps_the_cached_value() {
+        let tree = TestTree::new();
+        let cache = ProcessingFsCache::new(1000, tree.path("cache.bin"), None, ReadToString, true, false).unwrap();
+        let key = tree.path("a.txt");
+        fs::write(&key, "good").unwrap();
+        cache.fetch_update(&key).unwrap();
+
+        fs::write(&key, "bad").unwrap();
+        let outcome = cache.reload_if(&key, |value| value != "bad").unwrap();
+        assert!(matches!(outcome, FetchUpdateOutcome::Rejected(value) if value == "bad"));
+        assert_eq!(cache.fetch(&key).unwrap(), "good");
+
+        fs::write(&key, "better").unwrap();
+        let outcome = cache.reload_if(&key, |value| value != "bad").unwrap();
+        assert!(matches!(outcome, FetchUpdateOutcome::Loaded { replaced: true, .. }));
+        assert_eq!(cache.fetch(&key).unwrap(), "better");
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use ron::ser::PrettyConfig;
+
+use crate::{base_fs_cache::FsCacheEntry, *};
+
+//One entry of a RON file: its path, its contents, and its user data, with each value as JSON.
+type RonEntry = (PathBuf, FsCacheEntry<CacheEntry>, BTreeMap<String, String>);
+
+impl VideoHashFilesystemCache {
+    /// Write all entries in the cache to ``writer`` in [RON](https://github.com/ron-rs/ron) format, along with the
+    /// [user data][`VideoHashFilesystemCache::set_user_data`] of each entry. Entries are sorted by path, and the
+    /// output is pretty-printed, so that it is human-readable and produces small diffs when the cache changes. This is
+    /// intended for debugging and for test fixtures.
+    pub fn export_ron(&self, writer: impl Write) -> Result<(), VdfCacheError> {
+        let mut user_data = self.cache.user_data_map();
+        let mut entries = self
+            .cache
+            .entries()
+            .into_iter()
+            .map(|(src_path, entry)| {
+                let data = user_data.remove(&src_path).unwrap_or_default();
+                (src_path, entry, data)
+            })
+            .collect::<Vec<RonEntry>>();
+        entries.sort_by(|(path_1, ..), (path_2, ..)| path_1.cmp(path_2));
+
+        ron::ser::to_writer_pretty(writer, &entries, PrettyConfig::new())?;
+        Ok(())
+    }
+
+    /// Insert all entries written by [export_ron][`VideoHashFilesystemCache::export_ron`] from ``reader``
+    /// into the cache, with their user data, replacing any existing entries for the same paths. Each path is stored
+    /// under the same key as by [fetch_update][`VideoHashFilesystemCache::fetch_update`], so the filesystem is only
+    /// visited to canonicalize paths when [canonicalize_paths][`VideoHashFilesystemCacheBuilder::canonicalize_paths`]
+    /// is enabled.
+    ///
+    /// Returns the number of entries imported. Returns an error if the file could not be parsed, in which case
+    /// nothing is imported.
+    pub fn import_ron(&self, reader: impl Read) -> Result<usize, VdfCacheError> {
+        let rows: Vec<RonEntry> = ron::de::from_reader(reader)?;
+
+        let mut entries = vec![];
+        let mut user_data = vec![];
+        for (src_path, entry, data) in rows {
+            let (key, entry) = self.import_entry(&src_path, entry);
+            user_data.push((key.clone(), data));
+            entries.push((key, entry));
+        }
+
+        let ret = self.cache.insert_many(entries);
+        self.rebuild_key_index();
+
+        let imported = ret.map_err(VdfCacheError::from_cache_write)?;
+        self.cache
+            .extend_user_data(user_data)
+            .map_err(VdfCacheError::from_cache_write)?;
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn user_data_survives_export_and_import() {
+        let tree = TestTree::new().file("a.mp4", 100, UNIX_EPOCH + Duration::from_secs(1000));
+        let cache = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .build()
+            .unwrap();
+        cache.fetch_update(tree.path("a.mp4")).unwrap();
+        cache.set_user_data(tree.path("a.mp4"), "keep", json!(true)).unwrap();
+
+        let mut ron = vec![];
+        cache.export_ron(&mut ron).unwrap();
+        cache.remove(tree.path("a.mp4")).unwrap();
+        assert_eq!(cache.import_ron(ron.as_slice()).unwrap(), 1);
+        assert_eq!(cache.get_user_data(tree.path("a.mp4"), "keep"), Some(json!(true)));
+        cache.flush_without_save();
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::{Path, PathBuf};
+
+use vid_dup_finder_lib::*;
+
+use crate::*;
+
+/// A view of a [VideoHashFilesystemCache] in which all paths are relative to a root directory. Created by
+/// [scoped][`VideoHashFilesystemCache::scoped`].
+///
+/// Paths passed to a ScopedCache are joined to the root before being passed to the underlying cache, and
+/// paths returned by it are relative to the root. Paths which refer to locations outside of the root (because
+/// they are absolute, or because they contain too many `..` components) are rejected with
+/// [PathEscapesRoot][VdfCacheError::PathEscapesRoot].
+pub struct ScopedCache<'a> {
+    cache: &'a VideoHashFilesystemCache,
+    root: PathBuf,
+}
+
+impl<'a> ScopedCache<'a> {
+    pub(crate) fn new(cache: &'a VideoHashFilesystemCache, root: PathBuf) -> Self {
+        Self { cache, root }
+    }
+
+    /// The root that all paths are relative to.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// See [VideoHashFilesystemCache::fetch].
+    pub fn fetch(&self, rel_path: impl AsRef<Path>) -> Result<VideoHash, VdfCacheError> {
+        self.cache.fetch(self.resolve(rel_path)?)
+    }
+
+    /// See [VideoHashFilesystemCache::fetch_update].
+    pub fn fetch_update(&self, rel_path: impl AsRef<Path>) -> Result<FetchUpdateResult, VdfCacheError> {
+        self.cache.fetch_update(self.resolve(rel_path)?)
+    }
+
+    /// See [VideoHashFilesystemCache::remove].
+    pub fn remove(&self, rel_path: impl AsRef<Path>) -> Result<(), VdfCacheError> {
+        self.cache.remove(self.resolve(rel_path)?)
+    }
+
+    /// Get the paths, relative to the root, of all [VideoHashes][VideoHash] stored in the cache under the root.
+    pub fn all_cached_paths(&self) -> Vec<PathBuf> {
+        self.cache
+            .all_cached_paths()
+            .into_iter()
+            .filter_map(|src_path| src_path.strip_prefix(&self.root).ok().map(Path::to_path_buf))
+            .collect()
+    }
+
+    //Convert a path relative to the root into the path used by the underlying cache.
+    fn resolve(&self, rel_path: impl AsRef<Path>) -> Result<PathBuf, VdfCacheError> {
+        let rel_path = rel_path.as_ref();
+        let path = normalize_path(self.root.join(rel_path));
+
+        if rel_path.is_absolute() || !path.starts_with(&self.root) {
+            return Err(VdfCacheError::PathEscapesRoot {
+                root: self.root.clone(),
+                path: rel_path.to_path_buf(),
+            });
+        }
+
+        Ok(path)
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, types::Type, Connection, Row};
+
+use crate::{
+    base_fs_cache::{self, FsCacheEntry},
+    cache_entry::{CacheEntry, CachedVideoData, EntryState},
+    video_hash_filesystem_cache::{user_data_from_json, user_data_to_json},
+    *,
+};
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS cache (
+    path          TEXT PRIMARY KEY,
+    hash_bytes    BLOB,
+    duration_secs REAL,
+    error         TEXT,
+    created_at    INTEGER,
+    mtime         INTEGER,
+    user_data     TEXT
+)";
+
+impl VideoHashFilesystemCache {
+    /// Write all entries in the cache to the table `cache` in the SQLite database at `db_path`,
+    /// creating the database and table if they do not already exist. Existing rows with the same
+    /// path are replaced. Metadata-only entries are not written.
+    ///
+    /// The table has the following columns:
+    ///    * `path`: The path of the video. Paths which are not valid UTF-8 are not exported.
+    ///    * `hash_bytes`: The serialized [VideoHash][vid_dup_finder_lib::VideoHash], or NULL for error entries.
+    ///    * `duration_secs`: The duration of the video, or NULL for error entries and entries without stats.
+    ///    * `error`: The error that occurred while creating the hash (as JSON), or NULL for successful entries.
+    ///    * `created_at`: When the entry was created, in nanoseconds since the Unix epoch. See
+    ///      [entry_metadata][`VideoHashFilesystemCache::entry_metadata`].
+    ///    * `mtime`: The modification time of the video when its hash was created, in nanoseconds since the Unix
+    ///      epoch.
+    ///    * `user_data`: The [user data][`VideoHashFilesystemCache::set_user_data`] of the entry as a JSON object of
+    ///      values by name, or NULL if it has none.
+    ///
+    /// Stats other than the duration are not exported.
+    ///
+    /// Returns the number of rows written.
+    pub fn export_sqlite(&self, db_path: impl AsRef<Path>) -> Result<usize, VdfCacheError> {
+        let mut conn = Connection::open(db_path)?;
+        conn.execute(CREATE_TABLE, params![])?;
+        let user_data = self.cache.user_data_map();
+
+        let tx = conn.transaction()?;
+        let mut count = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO cache
+                     (path, hash_bytes, duration_secs, error, created_at, mtime, user_data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+
+            for (src_path, FsCacheEntry { mtime, value }) in self.cache.entries() {
+                let path_str = match src_path.to_str() {
+                    Some(path_str) => path_str,
+                    None => {
+                        warn!("Not exporting non UTF-8 path: {}", src_path.display());
+                        continue;
+                    }
+                };
+
+                let created_at = self
+                    .cache
+                    .entry_meta(&src_path)
+                    .map(|meta| nanos_since_epoch(meta.created_at))
+                    .unwrap_or_default();
+                let mtime = nanos_since_epoch(mtime);
+
+                let to_sql_err = |e: bincode::Error| rusqlite::Error::ToSqlConversionFailure(e);
+                let user_data = user_data.get(&src_path).map(user_data_to_json);
+
+                match value.0 {
+                    EntryState::Hashed(CachedVideoData { hash, stats }) => {
+                        let hash_bytes = bincode::serialize(&hash).map_err(to_sql_err)?;
+                        stmt.execute(params![
+                            path_str,
+                            hash_bytes,
+                            stats.map(|stats| stats.duration),
+                            Option::<String>::None,
+                            created_at,
+                            mtime,
+                            user_data
+                        ])?;
+                    }
+                    EntryState::Failed(e) | EntryState::FailedWithProbe(e, _) => {
+                        let error = serde_json::to_string(&e)
+                            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                        stmt.execute(params![
+                            path_str,
+                            Option::<Vec<u8>>::None,
+                            Option::<f64>::None,
+                            error,
+                            created_at,
+                            mtime,
+                            user_data
+                        ])?;
+                    }
+                    //The schema has no columns for probes, and they are recreated by the next update anyway.
+                    EntryState::MetadataOnly(_) => continue,
+                }
+                count += 1;
+            }
+        }
+        tx.commit()?;
+
+        Ok(count)
+    }
+
+    /// Insert all rows of the table `cache` in the SQLite database at `db_path` into the cache, with their user data,
+    /// replacing any existing entries for the same paths. The table must have the schema written by
+    /// [export_sqlite][`VideoHashFilesystemCache::export_sqlite`]. Imported entries are given new creation times and
+    /// have no stats. Each path is stored under the same key as by
+    /// [fetch_update][`VideoHashFilesystemCache::fetch_update`], so the filesystem is only visited to canonicalize
+    /// paths when [canonicalize_paths][`VideoHashFilesystemCacheBuilder::canonicalize_paths`] is enabled.
+    ///
+    /// Rows which cannot be decoded are logged and skipped, and do not prevent the remaining rows from being imported.
+    /// Returns an error if the table could not be read.
+    ///
+    /// Returns the number of entries imported.
+    pub fn import_sqlite(&self, db_path: impl AsRef<Path>) -> Result<usize, VdfCacheError> {
+        let conn = Connection::open(db_path)?;
+        let mut stmt = conn.prepare("SELECT rowid, path, hash_bytes, error, mtime, user_data FROM cache")?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get::<_, i64>(0)?, parse_row(row))))?;
+
+        let mut entries = vec![];
+        let mut user_data = vec![];
+        for row in rows {
+            match row? {
+                (_, Ok((src_path, entry, data))) => {
+                    let (key, entry) = self.import_entry(Path::new(&src_path), entry);
+                    user_data.push((key.clone(), data));
+                    entries.push((key, entry));
+                }
+                (rowid, Err(e)) => warn!("Not importing row {} of SQLite: {}", rowid, e),
+            }
+        }
+
+        let ret = self.cache.insert_many(entries);
+        self.rebuild_key_index();
+
+        let imported = ret.map_err(VdfCacheError::from_cache_write)?;
+        self.cache
+            .extend_user_data(user_data)
+            .map_err(VdfCacheError::from_cache_write)?;
+        Ok(imported)
+    }
+}
+
+//SQLite integers are signed, so times after 2262 are stored as the latest representable time.
+fn nanos_since_epoch(time: SystemTime) -> i64 {
+    i64::try_from(base_fs_cache::nanos_since_epoch(time)).unwrap_or(i64::MAX)
+}
+
+//Decode a row selected by import_sqlite into its path, entry and user data.
+fn parse_row(row: &Row) -> rusqlite::Result<(String, FsCacheEntry<CacheEntry>, BTreeMap<String, String>)> {
+    let src_path: String = row.get(1)?;
+    let hash_bytes: Option<Vec<u8>> = row.get(2)?;
+    let error: Option<String> = row.get(3)?;
+    let mtime: i64 = row.get(4)?;
+    let user_data = match row.get::<_, Option<String>>(5)? {
+        Some(json) => user_data_from_json(&json)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(5, Type::Text, Box::new(e)))?,
+        None => BTreeMap::new(),
+    };
+
+    let value = match (error, hash_bytes) {
+        (Some(error), _) => {
+            let e = serde_json::from_str(&error)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, Type::Text, Box::new(e)))?;
+            CacheEntry(EntryState::Failed(e))
+        }
+        (None, Some(hash_bytes)) => {
+            let hash = bincode::deserialize(&hash_bytes)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Blob, e))?;
+            CacheEntry(EntryState::Hashed(CachedVideoData { hash, stats: None }))
+        }
+        (None, None) => return Err(rusqlite::Error::InvalidColumnType(2, "hash_bytes".into(), Type::Null)),
+    };
+
+    let mtime = UNIX_EPOCH + Duration::from_nanos(mtime.max(0) as u64);
+
+    Ok((src_path, FsCacheEntry { mtime, value }, user_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_keep_their_mtime_and_export_their_creation_time() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new().file("a.mp4", 1, mtime);
+        let cache = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .build()
+            .unwrap();
+        cache.fetch_update(tree.path("a.mp4")).unwrap();
+        let created_at = cache.entry_metadata(tree.path("a.mp4")).unwrap().created_at;
+
+        assert_eq!(cache.export_sqlite(tree.path("cache.db")).unwrap(), 1);
+        let conn = Connection::open(tree.path("cache.db")).unwrap();
+        let (exported_created_at, exported_mtime): (i64, i64) = conn
+            .query_row("SELECT created_at, mtime FROM cache", params![], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(exported_created_at, nanos_since_epoch(created_at));
+        assert_eq!(exported_mtime, nanos_since_epoch(mtime));
+
+        //The imported entry is fresh, so the file is not hashed again.
+        cache.remove(tree.path("a.mp4")).unwrap();
+        assert_eq!(cache.import_sqlite(tree.path("cache.db")).unwrap(), 1);
+        assert_eq!(cache.stats().hashes_created, 1);
+        cache.fetch_update(tree.path("a.mp4")).unwrap();
+        assert_eq!(cache.stats().hashes_created, 1);
+        cache.flush_without_save();
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+use vid_dup_finder_lib::*;
+
+use crate::{
+    cache_entry::{CachedVideoData, EntryState},
+    *,
+};
+
+/// Why a cached hash was reported by [find_suspect_entries][`VideoHashFilesystemCache::find_suspect_entries`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SuspectReason {
+    /// Every value in the hash is zero, as when every frame was decoded as black. A hash like this matches every
+    /// other degenerate hash, so it is likely to produce false duplicates.
+    DegenerateHash,
+
+    /// The stored duration is zero, negative or not a number, even though a hash was created from the video.
+    InvalidDuration,
+
+    /// The stored width or height is zero, even though a hash was created from the video.
+    ZeroResolution,
+
+    /// The stored file size is zero, even though a hash was created from the file.
+    EmptyFile,
+
+    /// A [SuspectPredicate] reported a problem, with its description.
+    Custom(String),
+}
+
+/// The built-in sanity checks applied by
+/// [find_suspect_entries][`VideoHashFilesystemCache::find_suspect_entries`]. All checks are enabled by default. The
+/// checks of stats only apply to entries whose [VideoStats] were stored. See
+/// [suspect_checks][`VideoHashFilesystemCacheBuilder::suspect_checks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SuspectChecks {
+    /// Report [DegenerateHash][SuspectReason::DegenerateHash].
+    pub degenerate_hash: bool,
+
+    /// Report [InvalidDuration][SuspectReason::InvalidDuration].
+    pub invalid_duration: bool,
+
+    /// Report [ZeroResolution][SuspectReason::ZeroResolution].
+    pub zero_resolution: bool,
+
+    /// Report [EmptyFile][SuspectReason::EmptyFile].
+    pub empty_file: bool,
+}
+
+impl Default for SuspectChecks {
+    fn default() -> Self {
+        Self {
+            degenerate_hash: true,
+            invalid_duration: true,
+            zero_resolution: true,
+            empty_file: true,
+        }
+    }
+}
+
+/// An additional sanity check applied to each cached hash by
+/// [find_suspect_entries][`VideoHashFilesystemCache::find_suspect_entries`]. See
+/// [suspect_predicate][crate::VideoHashFilesystemCacheBuilder::suspect_predicate].
+///
+/// This is implemented for all functions and closures with the same signature as [check][SuspectPredicate::check].
+pub trait SuspectPredicate: Send + Sync {
+    /// Check the hash of the video at ``src_path``, and its stats if they were stored. Returns a description of the
+    /// problem if the hash should be created again, or None if it looks valid.
+    fn check(&self, src_path: &Path, hash: &VideoHash, stats: Option<&VideoStats>) -> Option<String>;
+}
+
+impl<F> SuspectPredicate for F
+where
+    F: Fn(&Path, &VideoHash, Option<&VideoStats>) -> Option<String> + Send + Sync,
+{
+    fn check(&self, src_path: &Path, hash: &VideoHash, stats: Option<&VideoStats>) -> Option<String> {
+        self(src_path, hash, stats)
+    }
+}
+
+impl fmt::Debug for dyn SuspectPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SuspectPredicate")
+    }
+}
+
+impl VideoHashFilesystemCache {
+    /// Apply sanity checks to every cached hash and its stats, and return the entries which look corrupt, e.g.
+    /// because of a bug in an old version of the hashing code or faulty memory. The built-in checks are chosen with
+    /// [suspect_checks][`VideoHashFilesystemCacheBuilder::suspect_checks`], and further checks can be added with
+    /// [suspect_predicate][`VideoHashFilesystemCacheBuilder::suspect_predicate`].
+    ///
+    /// An entry is listed once for each check that it fails, sorted by path. Entries for which hash creation failed
+    /// and metadata-only entries are not checked. Neither the cache nor the filesystem is modified or visited. The
+    /// entries can then be hashed again with [repair][`VideoHashFilesystemCache::repair`].
+    pub fn find_suspect_entries(&self) -> Vec<(PathBuf, SuspectReason)> {
+        let mut entries = self.cache.entries();
+        entries.sort_by(|(path_1, _), (path_2, _)| path_1.cmp(path_2));
+
+        entries
+            .into_iter()
+            .flat_map(|(src_path, entry)| {
+                let reasons = match &entry.value.0 {
+                    EntryState::Hashed(CachedVideoData { hash, stats }) => self.suspect_reasons(&src_path, hash, stats),
+                    EntryState::Failed(_) | EntryState::FailedWithProbe(..) | EntryState::MetadataOnly(_) => vec![],
+                };
+                reasons.into_iter().map(move |reason| (src_path.clone(), reason))
+            })
+            .collect()
+    }
+
+    /// Create new hashes for ``paths``, even if their files have not been modified since they were cached, e.g. to
+    /// repair the entries found by [find_suspect_entries][`VideoHashFilesystemCache::find_suspect_entries`]. Only the
+    /// given paths are visited. Paths which are not in the cache are ignored. Each entry keeps its user data and
+    /// creation time.
+    ///
+    /// Returns the outcome for each repaired path, in the same form as
+    /// [fetch_update][`VideoHashFilesystemCache::fetch_update`]. Returns an error if it was not possible to read or
+    /// write the cache.
+    pub fn repair(
+        &self,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Result<Vec<(PathBuf, FetchUpdateResult)>, VdfCacheError> {
+        let _update_guard = self.lock_updates();
+
+        paths
+            .into_iter()
+            .map(|path| self.key(path))
+            .filter(|key| self.cache.contains_key(key))
+            .map(|key| {
+                let result = self.fetch_update_forced(&key)?;
+                Ok((key, result))
+            })
+            .collect()
+    }
+
+    fn suspect_reasons(&self, src_path: &Path, hash: &VideoHash, stats: &Option<VideoStats>) -> Vec<SuspectReason> {
+        let checks = self.suspect_checks;
+        let mut reasons = vec![];
+
+        if checks.degenerate_hash && is_degenerate(hash) {
+            reasons.push(SuspectReason::DegenerateHash);
+        }
+        if let Some(stats) = stats {
+            let (width, height) = stats.resolution;
+            if checks.invalid_duration && !(stats.duration.is_finite() && stats.duration > 0.0) {
+                reasons.push(SuspectReason::InvalidDuration);
+            }
+            if checks.zero_resolution && (width == 0 || height == 0) {
+                reasons.push(SuspectReason::ZeroResolution);
+            }
+            if checks.empty_file && stats.size == 0 {
+                reasons.push(SuspectReason::EmptyFile);
+            }
+        }
+
+        reasons.extend(
+            self.suspect_predicates
+                .iter()
+                .filter_map(|predicate| predicate.check(src_path, hash, stats.as_ref()))
+                .map(SuspectReason::Custom),
+        );
+
+        reasons
+    }
+}
+
+//VideoHash does not expose its values, so read them from its serialized form instead. Everything other than the
+//path is part of the hash.
+fn is_degenerate(hash: &VideoHash) -> bool {
+    let mut value = match serde_json::to_value(hash) {
+        Ok(value) => value,
+        Err(e) => {
+            debug!("Failed to serialize hash of {}: {}", hash.src_path().display(), e);
+            return false;
+        }
+    };
+    if let Some(fields) = value.as_object_mut() {
+        fields.remove("src_path");
+    }
+
+    let mut numbers = vec![];
+    collect_numbers(&value, &mut numbers);
+    !numbers.is_empty() && numbers.iter().all(|number| number.as_f64() == Some(0.0))
+}
+
+fn collect_numbers<'a>(value: &'a Value, numbers: &mut Vec<&'a serde_json::Number>) {
+    match value {
+        Value::Number(number) => numbers.push(number),
+        Value::Array(values) => values.iter().for_each(|value| collect_numbers(value, numbers)),
+        Value::Object(fields) => fields.values().for_each(|value| collect_numbers(value, numbers)),
+        Value::Null | Value::Bool(_) | Value::String(_) => (),
+    }
+}
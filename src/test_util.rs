@@ -0,0 +1,178 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use vid_dup_finder_lib::*;
+
+use crate::*;
+
+/// A replacement for creating hashes from videos, for testing applications which use the cache without needing real
+/// videos or ffmpeg. Pass [FakeLoader::load] to [hash_loader][`VideoHashFilesystemCacheBuilder::hash_loader`].
+///
+/// [VideoHash] cannot be created without decoding a video, so instead every file is recorded as a deterministic
+/// failure which depends only on the file:
+/// * An empty file is [VideoLength][HashCreationErrorKind::VideoLength] (too short).
+/// * Any other file is [DetermineVideo][HashCreationErrorKind::DetermineVideo], whose error message contains the
+///   size of the file.
+/// * A file which cannot be opened is [VideoProcessing][HashCreationErrorKind::VideoProcessing]. This is retried
+///   if [set_load_retry][`VideoHashFilesystemCacheBuilder::set_load_retry`] is used.
+///
+/// Failures are cached in the same way as hashes, so the cache behaves as it would with real videos: entries are
+/// recreated when their file is modified, and removed when it is deleted. The number of times files were loaded can
+/// be checked with [stats][`VideoHashFilesystemCache::stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FakeLoader;
+
+impl FakeLoader {
+    pub fn load(src_path: &Path) -> Result<(VideoHash, VideoStats), HashCreationErrorKind> {
+        let src_path = src_path.to_path_buf();
+        match fs::metadata(&src_path) {
+            Ok(metadata) if metadata.len() == 0 => Err(HashCreationErrorKind::VideoLength(src_path)),
+            Ok(metadata) => Err(HashCreationErrorKind::DetermineVideo {
+                src_path,
+                error: format!("fake video of {} bytes", metadata.len()),
+            }),
+            Err(e) => Err(HashCreationErrorKind::VideoProcessing {
+                src_path,
+                error: e.to_string(),
+            }),
+        }
+    }
+}
+
+/// A directory tree of files in a new temporary directory, which is deleted when the tree is dropped.
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::time::{Duration, UNIX_EPOCH};
+/// use video_hash_filesystem_cache::*;
+///
+/// let tree = TestTree::new()
+///     .file("a/b.mp4", 100, UNIX_EPOCH + Duration::from_secs(1000))
+///     .file("a/c.mp4", 0, UNIX_EPOCH + Duration::from_secs(1000));
+///
+/// let cache = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+///     .hash_loader(FakeLoader::load)
+///     .build()
+///     .unwrap();
+/// cache.fetch_update(tree.path("a/b.mp4")).unwrap();
+/// assert_cached(&cache, tree.path("a/b.mp4"));
+///
+/// tree.remove("a/b.mp4");
+/// cache.fetch_update(tree.path("a/b.mp4")).unwrap();
+/// assert_not_cached(&cache, tree.path("a/b.mp4"));
+/// ```
+#[derive(Debug)]
+pub struct TestTree {
+    root: PathBuf,
+}
+
+impl TestTree {
+    /// Create an empty tree.
+    ///
+    /// # Panics
+    /// Panics if the temporary directory cannot be created.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let root = env::temp_dir().join(format!(
+            "vhfc_test_tree_{}_{}_{}",
+            process::id(),
+            nanos,
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&root).expect("failed to create test tree");
+
+        Self { root }
+    }
+
+    /// Add a file of `size` bytes at `rel_path`, creating any parent directories, and set its modification
+    /// time to `mtime`. An existing file is replaced.
+    ///
+    /// # Panics
+    /// Panics if the file cannot be created.
+    pub fn file(self, rel_path: impl AsRef<Path>, size: u64, mtime: SystemTime) -> Self {
+        let path = self.path(rel_path);
+        fs::create_dir_all(path.parent().unwrap()).expect("failed to create directory in test tree");
+        let file = fs::File::create(&path).expect("failed to create file in test tree");
+        file.set_len(size).expect("failed to set size of file in test tree");
+        file.set_modified(mtime)
+            .expect("failed to set mtime of file in test tree");
+        self
+    }
+
+    /// Set the modification time of the existing file at `rel_path`, simulating a modification.
+    ///
+    /// # Panics
+    /// Panics if the file does not exist.
+    pub fn touch(&self, rel_path: impl AsRef<Path>, mtime: SystemTime) {
+        fs::File::options()
+            .write(true)
+            .open(self.path(rel_path))
+            .and_then(|file| file.set_modified(mtime))
+            .expect("failed to set mtime of file in test tree");
+    }
+
+    /// Remove the file or directory at `rel_path`, simulating a deletion.
+    ///
+    /// # Panics
+    /// Panics if nothing exists at `rel_path`.
+    pub fn remove(&self, rel_path: impl AsRef<Path>) {
+        let path = self.path(rel_path);
+        if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .expect("failed to remove path from test tree");
+    }
+
+    /// The root directory of the tree.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The absolute path of `rel_path` within the tree.
+    pub fn path(&self, rel_path: impl AsRef<Path>) -> PathBuf {
+        self.root.join(rel_path)
+    }
+}
+
+impl Drop for TestTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Assert that the cache has an entry for `src_path` (either a hash or a cached failure).
+///
+/// # Panics
+/// Panics if the cache has no entry for `src_path`.
+#[track_caller]
+pub fn assert_cached(cache: &VideoHashFilesystemCache, src_path: impl AsRef<Path>) {
+    let src_path = src_path.as_ref();
+    if let Err(VdfCacheError::NotCached(_)) = cache.fetch(src_path) {
+        panic!("expected {} to be cached", src_path.display());
+    }
+}
+
+/// Assert that the cache has no entry for `src_path`.
+///
+/// # Panics
+/// Panics if the cache has an entry for `src_path`.
+#[track_caller]
+pub fn assert_not_cached(cache: &VideoHashFilesystemCache, src_path: impl AsRef<Path>) {
+    let src_path = src_path.as_ref();
+    if !matches!(cache.fetch(src_path), Err(VdfCacheError::NotCached(_))) {
+        panic!("expected {} not to be cached", src_path.display());
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use vid_dup_finder_lib::*;
+
+use crate::{
+    cache_entry::{CachedVideoData, EntryState},
+    *,
+};
+
+/// An inconsistency found in the cache by [verify][`VideoHashFilesystemCache::verify`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VerifyProblem {
+    /// The path of the entry is not absolute, or contains `.` or `..` components, so it will never be
+    /// found by lookups. This can happen when entries are imported from elsewhere.
+    KeyNotNormalized(PathBuf),
+
+    /// The path stored inside a [VideoHash] does not match the path of its entry, for instance because
+    /// the entry was imported from elsewhere or moved by [rename][`VideoHashFilesystemCache::rename`].
+    HashPathMismatch { key: PathBuf, hash_path: PathBuf },
+
+    /// The path stored inside a [HashCreationErrorKind] does not match the path of its entry.
+    ErrorPathMismatch { key: PathBuf, error_path: PathBuf },
+}
+
+/// How [fix_path_inconsistencies][`VideoHashFilesystemCache::fix_path_inconsistencies`] repairs an entry whose
+/// [VideoHash] contains a different path from the path of the entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FixStrategy {
+    /// Move the entry to the path stored in its hash. If an entry already exists at that path, the entry created
+    /// from the most recently modified file is kept (see [map_keys][`VideoHashFilesystemCache::map_keys`]). This
+    /// also moves back entries which were moved by [rename][`VideoHashFilesystemCache::rename`] or
+    /// [map_keys][`VideoHashFilesystemCache::map_keys`], because their hashes keep their original paths.
+    RekeyToHashPath,
+
+    /// Keep the path of the entry, and create a new hash from the file at that path. If the file no longer exists
+    /// the entry is removed. The entry is only replaced once the new hash has been created, so it is kept unchanged
+    /// if the file cannot be accessed or hashed.
+    RehashAtKey,
+}
+
+impl VideoHashFilesystemCache {
+    /// Check every entry in the cache for internal inconsistencies, and return a list of all problems found.
+    /// Neither the cache nor the filesystem is modified or visited, so this is a safe way to audit a cache
+    /// before relying on it.
+    pub fn verify(&self) -> Result<Vec<VerifyProblem>, VdfCacheError> {
+        let mut problems = vec![];
+
+        let mut entries = self.cache.entries();
+        entries.sort_by(|(path_1, _), (path_2, _)| path_1.cmp(path_2));
+
+        for (key, entry) in entries {
+            if !key.is_absolute() || key != normalize_path(&key) {
+                problems.push(VerifyProblem::KeyNotNormalized(key.clone()));
+            }
+
+            match &entry.value.0 {
+                //Checked by check_path_consistency below.
+                EntryState::Hashed(_) => (),
+                EntryState::MetadataOnly(_) => (),
+                EntryState::Failed(e) | EntryState::FailedWithProbe(e, _) => {
+                    let error_path = error_path(e);
+                    if error_path != key {
+                        problems.push(VerifyProblem::ErrorPathMismatch {
+                            key: key.clone(),
+                            error_path: error_path.to_path_buf(),
+                        });
+                    }
+                }
+            }
+        }
+
+        problems.extend(
+            self.check_path_consistency()?
+                .into_iter()
+                .map(|(key, hash_path)| VerifyProblem::HashPathMismatch { key, hash_path }),
+        );
+        problems.sort_by(|problem_1, problem_2| problem_key(problem_1).cmp(problem_key(problem_2)));
+
+        Ok(problems)
+    }
+
+    /// Find every hash in the cache whose [VideoHash] contains a different path from the path of its entry,
+    /// and return ``(cache_key, hash_src_path)`` for each of them, sorted by ``cache_key``. The filesystem is
+    /// not visited. These inconsistencies are also reported by [verify][`VideoHashFilesystemCache::verify`], and can
+    /// be repaired with [fix_path_inconsistencies][`VideoHashFilesystemCache::fix_path_inconsistencies`].
+    pub fn check_path_consistency(&self) -> Result<Vec<(PathBuf, PathBuf)>, VdfCacheError> {
+        let mut ret = self
+            .cache
+            .entries()
+            .into_iter()
+            .filter_map(|(key, entry)| match entry.value.0 {
+                EntryState::Hashed(CachedVideoData { hash, stats: _stats }) if hash.src_path() != key => {
+                    Some((key, hash.src_path().to_path_buf()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        ret.sort();
+
+        Ok(ret)
+    }
+
+    /// Repair every inconsistency found by [check_path_consistency][`VideoHashFilesystemCache::check_path_consistency`]
+    /// using ``strategy``.
+    ///
+    /// Returns the number of entries which were moved (for [RekeyToHashPath][FixStrategy::RekeyToHashPath]) or
+    /// hashed again (for [RehashAtKey][FixStrategy::RehashAtKey]).
+    pub fn fix_path_inconsistencies(&self, strategy: FixStrategy) -> Result<usize, VdfCacheError> {
+        let inconsistencies = self.check_path_consistency()?;
+
+        match strategy {
+            FixStrategy::RekeyToHashPath => {
+                let new_keys = inconsistencies.into_iter().collect::<HashMap<_, _>>();
+                self.map_keys(|key| Some(new_keys.get(key).cloned().unwrap_or_else(|| key.to_path_buf())))
+            }
+            FixStrategy::RehashAtKey => {
+                let _update_guard = self.lock_updates();
+
+                let mut rehashed = 0;
+                for (key, _hash_path) in &inconsistencies {
+                    let outcome = self
+                        .cache
+                        .reload_if(key, |entry| matches!(entry.0, EntryState::Hashed(_)))
+                        .map_err(VdfCacheError::from_cache_write)?;
+
+                    match outcome {
+                        FetchUpdateOutcome::Loaded { .. } => rehashed += 1,
+                        FetchUpdateOutcome::Deleted { .. } => self.forget_keys([key]),
+                        FetchUpdateOutcome::Inaccessible(e) => {
+                            warn!(
+                                "Keeping the entry for {}, as it could not be accessed: {}",
+                                key.display(),
+                                e
+                            )
+                        }
+                        FetchUpdateOutcome::Rejected(_) => {
+                            warn!(
+                                "Keeping the entry for {}, as a new hash could not be created",
+                                key.display()
+                            )
+                        }
+                        FetchUpdateOutcome::Value(_) => (),
+                    }
+                }
+                Ok(rehashed)
+            }
+        }
+    }
+}
+
+fn problem_key(problem: &VerifyProblem) -> &Path {
+    match problem {
+        VerifyProblem::KeyNotNormalized(key) => key,
+        VerifyProblem::HashPathMismatch { key, .. } => key,
+        VerifyProblem::ErrorPathMismatch { key, .. } => key,
+    }
+}
+
+fn error_path(e: &HashCreationErrorKind) -> &Path {
+    match e {
+        HashCreationErrorKind::DetermineVideo { src_path, .. } => src_path,
+        HashCreationErrorKind::VideoLength(src_path) => src_path,
+        HashCreationErrorKind::VideoProcessing { src_path, .. } => src_path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn verify_is_clean_after_map_keys() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new()
+            .file("old/a.mp4", 100, mtime)
+            .file("old/b.mp4", 0, mtime);
+        let cache = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .build()
+            .unwrap();
+        cache
+            .update_subset(&[tree.path("old/a.mp4"), tree.path("old/b.mp4")])
+            .unwrap();
+
+        let old_root = tree.path("old");
+        let new_root = tree.path("new");
+        let moved = cache
+            .map_keys(|src_path| Some(new_root.join(src_path.strip_prefix(&old_root).unwrap())))
+            .unwrap();
+
+        assert_eq!(moved, 2);
+        assert_cached(&cache, tree.path("new/a.mp4"));
+        assert_cached(&cache, tree.path("new/b.mp4"));
+        assert_eq!(cache.verify().unwrap(), vec![]);
+        cache.flush_without_save();
+    }
+}
@@ -1,34 +1,338 @@
 use std::{
-    collections::{hash_map::RandomState, HashSet},
+    cmp::Reverse,
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    convert::TryFrom,
+    fs,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use generic_filesystem_cache::*;
+use itertools::Itertools;
 #[cfg(feature = "parallel_loading")]
 use rayon::prelude::*;
 use vid_dup_finder_lib::*;
 
-use super::{cache_entry::CachedVideoData, generic_cache_if::GenericCacheIf};
+use super::{
+    base_fs_cache::FsCacheEntry,
+    cache_entry::{CachedVideoData, EntryState},
+    generic_cache_if::GenericCacheIf,
+    video_hash_filesystem_cache_builder::UpdateOrder,
+};
 use crate::*;
+
+/// Paths and their hashes, or the errors recorded when their hashes could not be created. Returned by
+/// [iter_by_directory][`VideoHashFilesystemCache::iter_by_directory`] and
+/// [sample][`VideoHashFilesystemCache::sample`].
+pub type HashEntries = Vec<(PathBuf, Result<VideoHash, HashCreationErrorKind>)>;
+
+/// The former name of [HashEntries].
+#[deprecated(note = "renamed to HashEntries")]
+pub type DirectoryEntries = HashEntries;
+
+/// Paths and the hashes which were created for them. Returned by
+/// [update_using_fs_collect_hashes][`VideoHashFilesystemCache::update_using_fs_collect_hashes`].
+pub type NewHashes = Vec<(PathBuf, VideoHash)>;
+
+/// The changes made to the cache by [update_using_fs_tracked][`VideoHashFilesystemCache::update_using_fs_tracked`]
+/// and [update_using_fs_with_options][`VideoHashFilesystemCache::update_using_fs_with_options`]. Paths are in no
+/// particular order.
+#[derive(Debug, Default)]
+pub struct UpdateDelta {
+    /// Paths for which a hash was created that were not previously in the cache.
+    pub added: Vec<PathBuf>,
+
+    /// Paths that have been deleted from the filesystem (or are no longer regular files), whose entries have been
+    /// removed from the cache.
+    pub removed: Vec<PathBuf>,
+
+    /// Paths whose hash was created again because the file was modified.
+    pub refreshed: Vec<PathBuf>,
+
+    /// Files which were detected as moved, as ``(old_path, new_path)``. Their entries were moved to the new path
+    /// instead of being removed and created again. These paths are not listed in ``added`` or ``removed``. See
+    /// [detect_moves][UpdateOptions::detect_moves].
+    pub moved: Vec<(PathBuf, PathBuf)>,
+
+    /// Paths which were not hashed because they exceed the limits in [UpdateOptions], with their duration and
+    /// resolution. A metadata-only entry is stored for each of these paths, replacing any existing entry. See
+    /// [fetch_any][`VideoHashFilesystemCache::fetch_any`].
+    pub skipped: Vec<(PathBuf, VideoProbe)>,
+
+    /// Paths for which a hash could not be created or which could not be accessed, with the error that occurred.
+    /// These are the same errors that [update_using_fs][`VideoHashFilesystemCache::update_using_fs`] returns.
+    /// Empty if the cache has an [error_handler][`VideoHashFilesystemCacheBuilder::error_handler`].
+    pub errors: Vec<(PathBuf, VdfCacheError)>,
+
+    /// The number of errors which were passed to the [error_handler][`VideoHashFilesystemCacheBuilder::error_handler`]
+    /// instead of being listed in ``errors``.
+    pub errors_handled: usize,
+
+    /// The number of files which were hashed by this update, whether or not a hash could be created.
+    pub hashed: usize,
+
+    /// The number of paths which were not checked because the time budget ran out. See [UpdateOptions].
+    pub remaining: usize,
+
+    /// True if the update stopped before checking every path because the time budget ran out.
+    pub budget_exhausted: bool,
+
+    /// The time taken by the update, not including the time taken to project the files.
+    pub elapsed: Duration,
+}
+
+impl UpdateDelta {
+    fn into_errors(self) -> Vec<VdfCacheError> {
+        self.errors.into_iter().map(|(_path, e)| e).collect()
+    }
+}
+
+/// The outcome of [update_sample][`VideoHashFilesystemCache::update_sample`].
+#[derive(Debug, Clone, Default)]
+pub struct SampleReport {
+    /// The number of projected files which were selected for the sample.
+    pub sampled: usize,
+
+    /// The number of sampled files which now have a hash in the cache.
+    pub succeeded: usize,
+
+    /// The number of sampled files for which a hash could not be created, or which could not be accessed.
+    pub failed: usize,
+
+    /// The number of sampled files (whether they succeeded or failed) which were already cached and had not been
+    /// modified, so were not hashed again.
+    pub already_cached: usize,
+
+    /// The average time taken to hash each file, measured as the time taken by the update divided by the number of
+    /// files hashed. When hashes are created in parallel this is less than the time taken by each hash. This is zero
+    /// if no files were hashed.
+    pub average_duration: Duration,
+
+    /// An estimate of the time a full update would take, i.e. ``average_duration`` multiplied by the number of
+    /// projected files which were not already cached when the sample was taken.
+    pub estimated_total: Duration,
+}
+
+/// The outcome of [import_csv][`VideoHashFilesystemCache::import_csv`].
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    /// The number of entries inserted into the cache.
+    pub imported: usize,
+
+    /// The rows which could not be imported, with their row number and the error that occurred. The rows were
+    /// skipped, and did not prevent the remaining rows from being imported.
+    pub failed_rows: Vec<(usize, VdfCacheError)>,
+}
+
+/// The sizes of a cache before and after [compact][`VideoHashFilesystemCache::compact`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompactReport {
+    /// The estimated memory used by the cache before it was compacted. See
+    /// [memory_usage_estimate][`VideoHashFilesystemCache::memory_usage_estimate`].
+    pub memory_before: usize,
+
+    /// The estimated memory used by the cache after it was compacted.
+    pub memory_after: usize,
+
+    /// The size of the cache file in bytes before it was compacted, or None if it had not been saved yet.
+    pub file_size_before: Option<u64>,
+
+    /// The size of the cache file in bytes after it was compacted.
+    pub file_size_after: u64,
+}
+
+/// Options for a single update of the cache. See
+/// [update_using_fs_with_options][`VideoHashFilesystemCache::update_using_fs_with_options`].
+///
+/// When ``max_duration`` or ``max_resolution`` is set, each file which needs a new hash is first probed to read its
+/// duration and resolution, which is much faster than creating the hash. Files which exceed the limits are listed in
+/// [skipped][UpdateDelta::skipped] instead of being hashed, and their probes are stored in the cache as metadata-only
+/// entries, so repeated updates do not probe unchanged files again. Files which cannot be probed are hashed as normal.
+/// See [video_prober][`VideoHashFilesystemCacheBuilder::video_prober`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UpdateOptions {
+    /// If set, no more paths are checked once the update has been running for this long. Hashes which are already
+    /// being created are allowed to finish, so the update may take a little longer than the budget. The paths which
+    /// were not checked will be checked by the next update. The default is no limit.
+    pub time_budget: Option<Duration>,
+
+    /// If set, videos which are longer than this are not hashed. The default is no limit.
+    pub max_duration: Option<Duration>,
+
+    /// If set, videos whose width or height is larger than this (width, height) are not hashed. The default is no
+    /// limit.
+    pub max_resolution: Option<(u32, u32)>,
+
+    /// If true, cached files which have been deleted are matched with uncached files which have the same size and
+    /// modification time, before any hashes are created. Each match is treated as a moved file: its entry is moved to
+    /// the new path in the same way as [rename][`VideoHashFilesystemCache::rename`], instead of being removed while
+    /// the same video is hashed again. Moves are listed in [moved][UpdateDelta::moved]. The default is false.
+    ///
+    /// A pair is only matched if no other deleted or uncached file has the same size and modification time, to avoid
+    /// false matches. The size of a cached file is only known if its [VideoStats] were stored, so entries without
+    /// stats and entries for which hash creation failed are never matched.
+    pub detect_moves: bool,
+}
+
+impl UpdateOptions {
+    fn probes_files(&self) -> bool {
+        self.max_duration.is_some() || self.max_resolution.is_some()
+    }
+
+    fn exceeded_by(&self, probe: &VideoProbe) -> bool {
+        let too_long = self
+            .max_duration
+            .is_some_and(|max_duration| probe.duration > max_duration);
+        let too_large = self.max_resolution.is_some_and(|(max_width, max_height)| {
+            let (width, height) = probe.resolution;
+            width > max_width || height > max_height
+        });
+        too_long || too_large
+    }
+}
+
+//A change made to the cache while updating a single path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UpdateChange {
+    Added,
+    Refreshed,
+    Removed,
+    Skipped(VideoProbe),
+}
+
+/// The outcome of [fetch_update][`VideoHashFilesystemCache::fetch_update`].
+#[derive(Debug)]
+pub enum FetchUpdateResult {
+    /// The file exists. Contains its hash (either cached or newly created), or the error which occurred
+    /// while creating the hash.
+    Hashed(Result<VideoHash, HashCreationErrorKind>),
+
+    /// The file no longer exists on the filesystem and has been removed from the cache. This is also
+    /// returned if the path still exists but is no longer a regular file, e.g. because the file was replaced
+    /// by a directory or by a symlink to a directory.
+    Deleted,
+
+    /// The file exists but its metadata could not be read, for instance because of its permissions
+    /// or because it is temporarily locked. Any existing cache entry is preserved.
+    Inaccessible(std::io::Error),
+}
+
+/// The contents of a cache entry. Returned by [fetch_any][`VideoHashFilesystemCache::fetch_any`] and
+/// [drain][`VideoHashFilesystemCache::drain`].
+#[derive(Debug, Clone)]
+pub enum EntryKind {
+    /// A hash was created. Also contains the [VideoStats] of the video, if stats were being stored when the hash was
+    /// created.
+    Hashed(VideoHash, Option<VideoStats>),
+
+    /// The hash could not be created, for the given reason. Also contains the duration and resolution of the video, if
+    /// they could be read, e.g. for videos which are too short to hash or shorter than the
+    /// [minimum duration][`VideoHashFilesystemCacheBuilder::min_video_duration_secs`].
+    Failed(HashCreationErrorKind, Option<VideoProbe>),
+
+    /// The video was not hashed because it exceeded the limits of an update, but its duration and resolution could
+    /// be read. See [UpdateOptions]. The video is hashed by the next update without limits.
+    MetadataOnly(VideoProbe),
+}
+
+impl EntryKind {
+    fn from_state(state: EntryState) -> EntryKind {
+        match state {
+            EntryState::Hashed(CachedVideoData { hash, stats }) => EntryKind::Hashed(hash, stats),
+            EntryState::Failed(error) => EntryKind::Failed(error, None),
+            EntryState::FailedWithProbe(error, probe) => EntryKind::Failed(error, Some(probe)),
+            EntryState::MetadataOnly(probe) => EntryKind::MetadataOnly(probe),
+        }
+    }
+
+    /// Apply ``f`` to the hash and stats of an entry for which a hash was created, in the same way as
+    /// [Result::map]. Other entries are returned unchanged.
+    pub fn map(self, f: impl FnOnce(VideoHash, Option<VideoStats>) -> (VideoHash, Option<VideoStats>)) -> EntryKind {
+        match self {
+            EntryKind::Hashed(hash, stats) => {
+                let (hash, stats) = f(hash, stats);
+                EntryKind::Hashed(hash, stats)
+            }
+            kind => kind,
+        }
+    }
+
+    /// Apply ``f`` to the error of an entry for which hash creation failed, in the same way as [Result::map_err].
+    /// Other entries are returned unchanged.
+    pub fn map_err(self, f: impl FnOnce(HashCreationErrorKind) -> HashCreationErrorKind) -> EntryKind {
+        match self {
+            EntryKind::Failed(e, probe) => EntryKind::Failed(f(e), probe),
+            kind => kind,
+        }
+    }
+}
+
 /// A disk-backed cache for hashes of videos on the filesystem.
 /// This is a utility struct for long term storage of [VideoHashes][vid_dup_finder_lib::VideoHash].
 /// The cache tracks modification times of the underlying video files, and will automatically
 /// recalculate hashes based on this.
 ///
-/// Cache entries are created and retrieved by calling [fetch_update][`VideoHashFilesystemCache::fetch_update`] with the path to a video
-/// on disk. If there is no entry in the cache, or the modification time of the video is newer then
+/// Cache entries are created and retrieved by calling [fetch_update][`VideoHashFilesystemCache::fetch_update`] with
+/// the path to a video on disk. If there is no entry in the cache, or the modification time of the video is newer then
 /// the cache will create a video hash for the underlying file. If the video is already cached then
 /// the cache will supply its cached data
 ///
 /// Hashes can be obtained from the cache without visiting the underlying video on the filesystem with
 /// [fetch][`VideoHashFilesystemCache::fetch`].
 ///
-/// To update all hashes within a given directory (or set of directories) use [update_using_fs][`VideoHashFilesystemCache::update_using_fs`]
+/// To update all hashes within a given directory (or set of directories) use
+/// [update_using_fs][`VideoHashFilesystemCache::update_using_fs`]
+///
+/// # Paths
+/// Relative paths passed to this struct are made absolute using the current directory, and `.` and `..`
+/// components are removed, so the same file is always stored under the same key however it is referred to
+/// (symlinks are not resolved). The current directory is read once, when the first relative path is used, so
+/// changing it afterwards does not change which files relative paths refer to. Keys which were saved without being
+/// normalized (e.g. by an older version of this crate) are normalized when the cache is loaded.
+///
+/// If [canonicalize_paths][`VideoHashFilesystemCacheBuilder::canonicalize_paths`] is enabled, then symlinks are
+/// also resolved for paths which exist on the filesystem. Every path passed to the cache is then looked up on the
+/// filesystem, including by methods which otherwise only read the cache. If
+/// [case_insensitive_keys][`VideoHashFilesystemCacheBuilder::case_insensitive_keys`] is enabled, then paths which
+/// differ only by case refer to the same entry.
 ///
 /// # A note on interior mutability
-/// All methods on this struct and its [underlying implementation][generic_filesystem_cache::ProcessingFsCache] are use
+/// All methods on this struct and its underlying implementation use
 /// interior mutability allow for operations to occur in parallel.
-pub struct VideoHashFilesystemCache(ProcessingFsCache<GenericCacheIf>);
+///
+/// # Cloning
+/// Cloning a cache is cheap, and returns another handle to the same cache rather than an independent copy.
+/// Modifications made through any handle are visible through all of the others, and calling
+/// [save][`VideoHashFilesystemCache::save`] on any handle saves everything. This allows one cache to be shared by
+/// several threads or structs without wrapping it in an `Arc`.
+///
+/// The cache is [Send] and [Sync], so handles can be moved to and shared between threads.
+#[derive(Clone)]
+pub struct VideoHashFilesystemCache {
+    pub(crate) cache: Arc<ProcessingFsCache<GenericCacheIf>>,
+    pub(crate) update_order: UpdateOrder,
+    pub(crate) last_timings: Arc<Mutex<ScanTimings>>,
+    //Held for the duration of each bulk update, so that updates with overlapping paths do not interfere.
+    pub(crate) update_lock: Arc<Mutex<()>>,
+    pub(crate) canonicalize_paths: bool,
+    //When keys are case-insensitive, maps the case-folded form of each key to the spelling first used for it.
+    pub(crate) case_folded_keys: Option<Arc<RwLock<HashMap<String, PathBuf>>>>,
+    pub(crate) min_video_duration: Option<f64>,
+    pub(crate) video_prober: Arc<dyn VideoProber>,
+    pub(crate) error_handler: Option<Arc<dyn ErrorHandler>>,
+    pub(crate) suspect_checks: SuspectChecks,
+    pub(crate) suspect_predicates: Vec<Arc<dyn SuspectPredicate>>,
+    #[cfg(feature = "parallel_loading")]
+    pub(crate) max_parallel_hashes: usize,
+    //A dedicated pool used when the number of parallel hashes is limited. Otherwise the global pool is used.
+    #[cfg(feature = "parallel_loading")]
+    pub(crate) thread_pool: Option<Arc<rayon::ThreadPool>>,
+    //The options this cache was built with, for creating other caches with the same options.
+    pub(crate) options: VideoHashFilesystemCacheBuilder,
+}
 
 impl VideoHashFilesystemCache {
     /// Load a VideoHash cache from disk the specified path. If no cache exists at cache_path
@@ -39,134 +343,2142 @@ impl VideoHashFilesystemCache {
     ///
     /// Note: The cache does not automatically save its contents when it goes out of scope. You must manually
     /// call [save][`VideoHashFilesystemCache::save`] after you have made the last modification to the chache contents.
+    /// In debug builds, a warning is logged if the cache is dropped with unsaved modifications.
     ///
-    /// Returns an error if it was not possible to load the cache or create a new one.
+    /// Any missing parent directories of cache_path are created. Returns an error if it was not possible to load the
+    /// cache or create a new one, if cache_path is a directory, or if the cache could not be saved to cache_path
+    /// (for instance due to its permissions).
+    ///
+    /// To create a cache with non-default options, use [VideoHashFilesystemCacheBuilder].
     pub fn new(cache_save_thresold: u32, cache_path: PathBuf) -> Result<Self, VdfCacheError> {
-        let interface = GenericCacheIf::new();
+        VideoHashFilesystemCacheBuilder::new(cache_save_thresold, cache_path).build()
+    }
+
+    /// Open the cache at ``overlay_path`` as an overlay on top of the cache at ``base_path``. This allows
+    /// experiments (such as hashing some videos again with a different [HashLoader]) without disturbing an existing
+    /// cache. If ``overlay_path`` does not exist then the cache is loaded from ``base_path``, but all changes are
+    /// saved to ``overlay_path`` and the file at ``base_path`` is never modified.
+    ///
+    /// See [overlay_base][`VideoHashFilesystemCacheBuilder::overlay_base`], which also allows other options to be set.
+    pub fn open_overlay(
+        cache_save_threshold: u32,
+        base_path: PathBuf,
+        overlay_path: PathBuf,
+    ) -> Result<Self, VdfCacheError> {
+        VideoHashFilesystemCacheBuilder::new(cache_save_threshold, overlay_path)
+            .overlay_base(base_path)
+            .build()
+    }
+
+    /// Get the hash of a single video, using the cache at ``cache_path`` (which is created if it does
+    /// not exist). This opens the cache, calls [fetch_update][`VideoHashFilesystemCache::fetch_update`] for
+    /// ``video_path``, saves the cache and returns the hash. It is intended for short scripts which only need one
+    /// hash, but still want to avoid recreating it on every run.
+    ///
+    /// Returns an error if the cache could not be loaded or saved, if ``video_path`` does not exist or cannot be
+    /// accessed, or if the hash could not be created.
+    pub fn hash_one(cache_path: PathBuf, video_path: impl AsRef<Path>) -> Result<VideoHash, VdfCacheError> {
+        let video_path = video_path.as_ref();
+        let cache = Self::new(u32::MAX, cache_path)?;
+        let result = cache.fetch_update(video_path)?;
+        cache.save()?;
 
-        let ret = ProcessingFsCache::new(cache_save_thresold, cache_path, interface)?;
-        Ok(Self(ret))
+        match result {
+            FetchUpdateResult::Hashed(hash) => Ok(hash?),
+            FetchUpdateResult::Deleted => Err(VdfCacheError::FileInaccessible {
+                path: video_path.to_path_buf(),
+                src: std::io::Error::from(std::io::ErrorKind::NotFound).into(),
+            }),
+            FetchUpdateResult::Inaccessible(src) => Err(VdfCacheError::FileInaccessible {
+                path: video_path.to_path_buf(),
+                src: src.into(),
+            }),
+        }
     }
 
     /// Fetch the hash for the video file at the given source path. If the cache does not already contain a hash
-    /// will not create one. This method does not read ``src_path`` on the filesystem.
+    /// will not create one. ``src_path`` is not read and no hashes are created. The filesystem is only visited to
+    /// canonicalize ``src_path`` when
+    /// [canonicalize_paths][`VideoHashFilesystemCacheBuilder::canonicalize_paths`] is enabled.
     ///
-    /// Returns an error if the cache has no entry for `src_path` .
+    /// Returns [NotCached][VdfCacheError::NotCached] if the cache has no entry for `src_path`, or
+    /// [CachedFailure][VdfCacheError::CachedFailure] if the entry records that the hash could not be created.
     pub fn fetch(&self, src_path: impl AsRef<Path>) -> Result<VideoHash, VdfCacheError> {
+        let src_path = src_path.as_ref();
         match self.fetch_entry(src_path)?.0 {
-            Ok(CachedVideoData { hash, stats: _stats }) => Ok(hash),
-            Err(e) => Err(VdfCacheError::from(e)),
+            EntryState::Hashed(CachedVideoData { hash, stats: _stats }) => Ok(hash),
+            EntryState::Failed(error) | EntryState::FailedWithProbe(error, _) => Err(VdfCacheError::CachedFailure {
+                path: src_path.to_path_buf(),
+                error,
+            }),
+            EntryState::MetadataOnly(_) => Err(VdfCacheError::NotHashed(src_path.to_path_buf())),
+        }
+    }
+
+    /// Fetch the entry for ``src_path``, whether it contains a hash, the error that occurred while creating the hash,
+    /// or only the metadata of the video. This allows reports to include videos which were not hashed. Like
+    /// [fetch][`VideoHashFilesystemCache::fetch`], ``src_path`` is not read and no hashes are created.
+    ///
+    /// Returns [NotCached][VdfCacheError::NotCached] if the cache has no entry for `src_path`.
+    pub fn fetch_any(&self, src_path: impl AsRef<Path>) -> Result<EntryKind, VdfCacheError> {
+        Ok(EntryKind::from_state(self.fetch_entry(src_path)?.0))
+    }
+
+    /// Fetch the hash for ``src_path`` from the cache as bytes, for storing outside of the cache. The bytes can be
+    /// converted back into a [VideoHash] with [decode_hash_bytes][`VideoHashFilesystemCache::decode_hash_bytes`].
+    ///
+    /// Returns the same errors as [fetch][`VideoHashFilesystemCache::fetch`].
+    pub fn fetch_hash_bytes(&self, src_path: impl AsRef<Path>) -> Result<Vec<u8>, VdfCacheError> {
+        let hash = self.fetch(src_path)?;
+        bincode::serialize(&hash).map_err(|e| VdfCacheError::HashBytes(e.to_string()))
+    }
+
+    /// Convert bytes returned by [fetch_hash_bytes][`VideoHashFilesystemCache::fetch_hash_bytes`] back into a
+    /// [VideoHash].
+    ///
+    /// Returns [HashBytes][VdfCacheError::HashBytes] if the bytes do not contain a valid hash.
+    pub fn decode_hash_bytes(bytes: &[u8]) -> Result<VideoHash, VdfCacheError> {
+        bincode::deserialize(bytes).map_err(|e| VdfCacheError::HashBytes(e.to_string()))
+    }
+
+    /// Remove the entry for ``src_path`` from the cache, along with any user data. This method does not read
+    /// ``src_path`` on the filesystem.
+    ///
+    /// Returns [NotCached][VdfCacheError::NotCached] if the cache has no entry for `src_path`.
+    pub fn remove(&self, src_path: impl AsRef<Path>) -> Result<(), VdfCacheError> {
+        let key = self.key(src_path);
+        let ret = self.cache.remove(&key).map_err(VdfCacheError::from_cache_write);
+        self.forget_keys(&[key]);
+        ret
+    }
+
+    /// Remove the entries for all of ``src_paths`` from the cache, along with any user data, in a single operation.
+    /// This is more efficient than calling [remove][`VideoHashFilesystemCache::remove`] for each path. Paths which
+    /// are not in the cache are ignored. This method does not read ``src_paths`` on the filesystem.
+    ///
+    /// Each removed entry counts as one modification towards automatically saving the cache.
+    ///
+    /// Returns the number of entries removed.
+    pub fn remove_many(&self, src_paths: impl IntoIterator<Item = impl AsRef<Path>>) -> Result<usize, VdfCacheError> {
+        let keys = src_paths
+            .into_iter()
+            .map(|src_path| self.key(src_path))
+            .collect::<Vec<_>>();
+        let ret = self.cache.remove_many(keys.iter().cloned());
+        self.forget_keys(&keys);
+        ret.map_err(VdfCacheError::from_cache_write)
+    }
+
+    /// Remove every entry from the cache, along with all user data, and return the removed entries in order of their
+    /// path. Every entry is returned exactly as it was stored, including failed and metadata-only entries, and the
+    /// [minimum video duration][`VideoHashFilesystemCacheBuilder::min_video_duration_secs`] is not applied. This is
+    /// useful for pipelines which must process every entry exactly once. The filesystem is not visited.
+    ///
+    /// Like any other modification, the removal counts towards the save threshold, and is not saved until the cache
+    /// is saved.
+    pub fn drain(&self) -> Result<Vec<(PathBuf, EntryKind)>, VdfCacheError> {
+        let drained = self.cache.drain().map_err(VdfCacheError::from_cache_write)?;
+        self.rebuild_key_index();
+        let mut drained = drained
+            .into_iter()
+            .map(|(src_path, entry)| (src_path, EntryKind::from_state(entry.value.0)))
+            .collect::<Vec<_>>();
+        drained.sort_by(|(path_1, _), (path_2, _)| path_1.cmp(path_2));
+
+        Ok(drained)
+    }
+
+    /// Partition the entries of this cache into two new caches, saved at ``path_a`` and ``path_b``. Each entry (along
+    /// with its user data) is copied to the first cache if ``predicate`` returns true for its path and hash, and to
+    /// the second cache otherwise. Entries for which hash creation failed are passed to ``predicate`` as errors.
+    /// Metadata-only entries are always copied to the second cache.
+    ///
+    /// The new caches are created with the same options as this cache, and both are saved to disk before being
+    /// returned. This cache is unchanged. The filesystem is not visited, other than to write the new cache files.
+    ///
+    /// Both caches are first saved to temporary files next to ``path_a`` and ``path_b``, and are only moved into
+    /// place once both have been saved. If either cache cannot be created or saved, then neither file is left behind.
+    ///
+    /// Returns an error if ``path_a`` and ``path_b`` are the same, if either is the path of this cache, if a file
+    /// already exists at ``path_a`` or ``path_b``, or if either cache could not be created or saved.
+    pub fn split(
+        &self,
+        predicate: impl Fn(&Path, Result<&VideoHash, &HashCreationErrorKind>) -> bool,
+        path_a: PathBuf,
+        path_b: PathBuf,
+    ) -> Result<(VideoHashFilesystemCache, VideoHashFilesystemCache), VdfCacheError> {
+        if normalize_path(&path_a) == normalize_path(&path_b) {
+            let e = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "both caches would be saved to one file",
+            );
+            return Err(FsCacheErrorKind::io(&path_a, e).into());
+        }
+        let own_path = normalize_path(self.cache.cache_path());
+        for cache_path in [&path_a, &path_b] {
+            if normalize_path(cache_path) == own_path || cache_path.exists() {
+                let e = std::io::Error::from(std::io::ErrorKind::AlreadyExists);
+                return Err(FsCacheErrorKind::io(cache_path, e).into());
+            }
+        }
+
+        let (entries_a, entries_b): (Vec<_>, Vec<_>) =
+            self.cache.entries().into_iter().partition(|(src_path, entry)| {
+                let result = entry.value.result();
+                result.is_some_and(|result| predicate(src_path, result.map(|data| &data.hash)))
+            });
+
+        let (tmp_path_a, tmp_path_b) = (split_tmp_path(&path_a), split_tmp_path(&path_b));
+        let split = self.copy_to(tmp_path_a.clone(), entries_a).and_then(|cache_a| {
+            let cache_b = self.copy_to(tmp_path_b.clone(), entries_b)?;
+            cache_a.move_to(path_a.clone())?;
+            if let Err(e) = cache_b.move_to(path_b) {
+                let _ = fs::remove_file(&path_a);
+                return Err(e);
+            }
+            Ok((cache_a, cache_b))
+        });
+
+        if split.is_err() {
+            for tmp_path in [tmp_path_a, tmp_path_b] {
+                let _ = fs::remove_file(tmp_path);
+            }
+        }
+        split
+    }
+
+    //Create a cache at cache_path with the same options as this cache, containing entries and their user data.
+    fn copy_to(
+        &self,
+        cache_path: PathBuf,
+        entries: Vec<(PathBuf, FsCacheEntry<CacheEntry>)>,
+    ) -> Result<VideoHashFilesystemCache, VdfCacheError> {
+        let copy = self.options.clone().cache_path(cache_path).build()?;
+
+        let keys = entries.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+        copy.cache
+            .insert_many(entries)
+            .map_err(VdfCacheError::from_cache_write)?;
+        for key in keys {
+            for (name, value) in self.cache.all_user_data(&key) {
+                copy.cache
+                    .set_user_data(&key, &name, value)
+                    .map_err(VdfCacheError::from_cache_write)?;
+            }
         }
+
+        copy.rebuild_key_index();
+        copy.save()?;
+        Ok(copy)
     }
 
-    #[doc(hidden)]
-    /// Utility function specifically for the example video_dup_finder GUI app. Returns additional information
-    /// used to help guide manual deduplication.
+    /// Take an immutable copy of the contents of the cache, which does not change while it is held. This allows
+    /// a long search or report to see a consistent set of entries while other threads update the cache. See
+    /// [CacheSnapshot].
+    ///
+    /// Every entry is copied while holding the read lock once, so the memory used is proportional to the number of
+    /// entries. User data is not included. The filesystem is not visited.
+    pub fn snapshot(&self) -> CacheSnapshot {
+        let entries = self
+            .cache
+            .entries()
+            .into_iter()
+            .map(|(src_path, entry)| {
+                let entry = self.apply_min_duration(&src_path, entry.value);
+                (src_path, entry)
+            })
+            .collect();
+        let case_folded_keys = self
+            .case_folded_keys
+            .as_ref()
+            .map(|index| index.read().unwrap().clone());
+
+        CacheSnapshot::new(entries, self.canonicalize_paths, case_folded_keys)
+    }
+
+    /// Get a view of the cache in which paths are relative to ``root``. This is useful when an application stores
+    /// paths relative to the root of a video library, so that only the root needs to change if the library moves.
+    /// See [ScopedCache].
+    pub fn scoped(&self, root: impl AsRef<Path>) -> ScopedCache<'_> {
+        ScopedCache::new(self, self.key(root))
+    }
+
+    /// Get the [VideoStats] (file size, duration and resolution) that were recorded when the hash for
+    /// `src_path` was created. This allows reports to be built from the cache without decoding videos again.
+    ///
+    /// Returns [NotCached][VdfCacheError::NotCached] if the path is not in the cache, or
+    /// [CachedFailure][VdfCacheError::CachedFailure] if its hash could not be created.
+    /// Returns [StatsNotStored][VdfCacheError::StatsNotStored] if stats were not being stored when the hash was
+    /// created. See [store_stats][`VideoHashFilesystemCacheBuilder::store_stats`].
     pub fn fetch_stats(&self, src_path: impl AsRef<Path>) -> Result<VideoStats, VdfCacheError> {
+        let src_path = src_path.as_ref();
         match self.fetch_entry(src_path)?.0 {
-            Ok(CachedVideoData { hash: _hash, stats }) => Ok(stats),
-            Err(e) => Err(VdfCacheError::from(e)),
+            EntryState::Hashed(CachedVideoData { hash: _hash, stats }) => {
+                stats.ok_or_else(|| VdfCacheError::StatsNotStored(src_path.to_path_buf()))
+            }
+            EntryState::Failed(error) | EntryState::FailedWithProbe(error, _) => Err(VdfCacheError::CachedFailure {
+                path: src_path.to_path_buf(),
+                error,
+            }),
+            EntryState::MetadataOnly(_) => Err(VdfCacheError::NotHashed(src_path.to_path_buf())),
         }
     }
 
+    /// Remove the stored [VideoStats] from every entry in the cache, so that the next save produces a smaller
+    /// cache file. The hashes themselves are kept. This is useful after disabling
+    /// [store_stats][`VideoHashFilesystemCacheBuilder::store_stats`] for an existing cache, which would otherwise
+    /// only drop stats as each file is rehashed.
+    ///
+    /// Returns the number of entries whose stats were removed.
+    pub fn discard_stats(&self) -> Result<usize, VdfCacheError> {
+        self.cache
+            .modify_values(|_src_path, entry| match &mut entry.0 {
+                EntryState::Hashed(data) => data.stats.take().is_some(),
+                _ => false,
+            })
+            .map_err(VdfCacheError::from_cache_write)
+    }
+
+    /// Get the path and [VideoStats] of every hash in the cache for which stats were stored.
+    ///
+    /// # Example
+    /// List all cached videos with a resolution below 720p.
+    /// ```rust,no_run
+    /// # use std::path::PathBuf;
+    /// use video_hash_filesystem_cache::*;
+    ///
+    /// let cache = VideoHashFilesystemCache::new(100, PathBuf::from("cache.bin")).unwrap();
+    /// for (path, stats) in cache.all_stats() {
+    ///     if stats.resolution.1 < 720 {
+    ///         println!("{}: {}x{}", path.display(), stats.resolution.0, stats.resolution.1);
+    ///     }
+    /// }
+    /// ```
+    pub fn all_stats(&self) -> Vec<(PathBuf, VideoStats)> {
+        self.snapshot().all_stats()
+    }
+
+    /// Get the path and [VideoStats] of the ``n`` longest videos in the cache, longest first. Videos with equal
+    /// durations are ordered by path. Only videos whose hashes were created successfully and whose stats were stored
+    /// are considered. No hashes are created, and the filesystem is not visited.
+    pub fn top_n_by_duration(&self, n: usize) -> Vec<(PathBuf, VideoStats)> {
+        self.snapshot().top_n_by_duration(n)
+    }
+
+    /// The same as [top_n_by_duration][`VideoHashFilesystemCache::top_n_by_duration`], but returns the ``n`` shortest
+    /// videos, shortest first.
+    pub fn bottom_n_by_duration(&self, n: usize) -> Vec<(PathBuf, VideoStats)> {
+        self.snapshot().bottom_n_by_duration(n)
+    }
+
     /// Get the paths of all [VideoHashes][VideoHash] stored in the cache.
     pub fn all_cached_paths(&self) -> Vec<PathBuf> {
-        self.0
-            .keys()
+        //Only the paths are copied, so there is no need to take a snapshot of every entry.
+        let min_duration = self.min_video_duration.unwrap_or(f64::NEG_INFINITY);
+        self.cache
+            .keys_where(|entry| matches!(entry.0, EntryState::Hashed(_)) && !entry.is_shorter_than(min_duration))
+    }
+
+    /// Group all entries in the cache (including entries for which hash creation failed) by the directory
+    /// containing them. Directories are returned in sorted order, and the entries within each directory are
+    /// sorted by path. This is useful for processing videos one directory at a time, for instance when
+    /// producing directory-level reports. The filesystem is not visited.
+    pub fn iter_by_directory(&self) -> impl Iterator<Item = (PathBuf, HashEntries)> + '_ {
+        self.snapshot().iter_by_directory()
+    }
+
+    /// Get a random sample of ``n`` entries from the cache (including entries for which hash creation failed, but
+    /// not metadata-only entries), sorted by path. If the cache has fewer than ``n`` entries, then all of them are
+    /// returned. This is useful for quick validation checks and benchmarks which do not need to process the entire
+    /// cache. The filesystem is not visited.
+    ///
+    /// The sample is chosen using a random number generator seeded with ``seed``, so the same entries are returned
+    /// each time for the same seed and cache contents, including with other versions of this crate and platforms.
+    pub fn sample(&self, n: usize, seed: u64) -> HashEntries {
+        self.snapshot().sample(n, seed)
+    }
+
+    /// Get all entries of the cache in the order they were first added, oldest first, e.g. to process videos in the
+    /// order they were scanned. Entries for which hash creation failed are returned as errors, and metadata-only
+    /// entries are skipped. The filesystem is not visited.
+    ///
+    /// The order is saved with the cache, so it is the same after the cache is reloaded, and does not depend on the
+    /// clock. An entry keeps its position when it is replaced because its file was modified, or when it is renamed.
+    /// Fetching an entry only changes its access time, so it does not change the order; evicting entries by access time
+    /// would remove them without reordering the rest. Entries loaded from cache files written before the order was
+    /// stored are placed after all other entries, ordered by the creation time in
+    /// [entry_metadata][`VideoHashFilesystemCache::entry_metadata`] and then by path.
+    pub fn entries_in_order(&self) -> HashEntries {
+        self.cache
+            .entries_in_order()
             .into_iter()
-            .filter(|src_path| self.fetch(src_path).is_ok())
+            .filter_map(|(src_path, entry)| {
+                let entry = self.apply_min_duration(&src_path, entry.value);
+                Some((src_path, entry.into_result()?.map(|data| data.hash)))
+            })
             .collect()
     }
 
-    /// If ``src_path`` has not been modified since it was cached, then return the cached hash.
-    /// If ``src_path`` has been deleted, then remove it from the cache and return None.
-    /// Otherwise create a new hash, insert it into the cache, and return it.
+    /// Get the paths of all entries in the cache (including entries for which hash creation failed)
+    /// which are not a child of any of ``known_roots``. These are typically files which still exist, but
+    /// which have been moved outside of the directories that are being cached, so they will never be updated
+    /// by [update_using_fs][`VideoHashFilesystemCache::update_using_fs`]. The filesystem is only visited to
+    /// canonicalize ``known_roots`` when
+    /// [canonicalize_paths][`VideoHashFilesystemCacheBuilder::canonicalize_paths`] is enabled.
+    pub fn orphans(&self, known_roots: &[PathBuf]) -> Vec<PathBuf> {
+        let known_roots = known_roots.iter().map(|root| self.key(root)).collect::<Vec<_>>();
+
+        let orphans = self
+            .cache
+            .keys()
+            .into_iter()
+            .filter(|src_path| !known_roots.iter().any(|root| src_path.starts_with(root)))
+            .sorted()
+            .collect::<Vec<_>>();
+
+        if !orphans.is_empty() {
+            warn!("{} cached paths are outside of all known roots", orphans.len());
+        }
+
+        orphans
+    }
+
+    /// Get the paths of all cached videos whose hashes match any of the ``reference`` hashes within
+    /// the given ``tolerance``. This is useful for finding copies of known unwanted videos, where
+    /// the reference hashes come from outside of the cache. The filesystem is not visited.
+    ///
+    /// ``tolerance`` has the same meaning as in [search_with_references][vid_dup_finder_lib::search_with_references].
+    pub fn flag_matching(&self, reference: &[VideoHash], tolerance: f64) -> Vec<PathBuf> {
+        self.snapshot().flag_matching(reference, tolerance)
+    }
+
+    /// The same as [flag_matching][`VideoHashFilesystemCache::flag_matching`], but compares hashes using ``distance``
+    /// instead of the standard distance used by [vid_dup_finder_lib]. A cached video is flagged if
+    /// ``distance(reference_hash, cached_hash)`` is no greater than ``max_distance`` for any of the ``reference``
+    /// hashes. The filesystem is not visited.
     ///
-    /// Returns an error if it was not possible to generate a hash from `src_path`.
-    pub fn fetch_update(
+    /// Every cached hash is compared with every reference hash, so this is slower than
+    /// [flag_matching][`VideoHashFilesystemCache::flag_matching`] when there are many reference hashes.
+    pub fn flag_matching_by(
         &self,
-        src_path: impl AsRef<Path>,
-    ) -> Result<Option<Result<VideoHash, HashCreationErrorKind>>, VdfCacheError> {
-        match self.0.fetch_update(&src_path.as_ref().to_path_buf()) {
-            Ok(None) => Ok(None),
-            Ok(Some(entry)) => match entry.0 {
-                Ok(entry) => Ok(Some(Ok(entry.hash))),
-                Err(hash_creation_err) => Ok(Some(Err(hash_creation_err))),
-            },
-            Err(cache_error) => Err(VdfCacheError::from(cache_error)),
+        reference: &[VideoHash],
+        max_distance: f64,
+        distance: impl Fn(&VideoHash, &VideoHash) -> f64,
+    ) -> Vec<PathBuf> {
+        self.snapshot().flag_matching_by(reference, max_distance, distance)
+    }
+
+    /// Group the paths of all cached videos whose hashes have identical values, ignoring the path of the video stored
+    /// in each hash. This is a cheap way of finding exact duplicates (such as copies of the same file) before doing a
+    /// full search with a nonzero tolerance. Only groups of two or more paths are returned. The filesystem is not
+    /// visited.
+    pub fn exact_hash_groups(&self) -> Vec<Vec<PathBuf>> {
+        self.exact_hash_groups_filtered(None, false)
+    }
+
+    /// The same as [exact_hash_groups][`VideoHashFilesystemCache::exact_hash_groups`], but only considers videos
+    /// that are a child of ``prefix`` (if given). If ``require_equal_duration`` is true, then each group is split
+    /// so that all videos within it have exactly the same duration, which avoids grouping unrelated short clips
+    /// with degenerate hashes. Videos whose [VideoStats] are not stored are then excluded.
+    pub fn exact_hash_groups_filtered(&self, prefix: Option<&Path>, require_equal_duration: bool) -> Vec<Vec<PathBuf>> {
+        self.snapshot()
+            .exact_hash_groups_filtered(prefix, require_equal_duration)
+    }
+
+    /// Rewrite the path of every entry in the cache in a single pass, for instance after moving a
+    /// collection of videos to a new mount point. Each path is replaced with ``f(path)``, and entries for
+    /// which ``f`` returns None are removed. The returned paths are normalized in the same way as all other
+    /// paths passed to the cache (see [Paths](#paths)). The filesystem is only visited to canonicalize the returned
+    /// paths when [canonicalize_paths][`VideoHashFilesystemCacheBuilder::canonicalize_paths`] is enabled.
+    ///
+    /// As with [rename][`VideoHashFilesystemCache::rename`], the path stored in the error of each moved entry for
+    /// which hash creation failed is changed to its new path, and each [VideoHash] keeps the path of the file it was
+    /// created from.
+    ///
+    /// If two or more entries are mapped to the same path, only the entry created from the most recently
+    /// modified file is kept. If their modification times are equal, the entry whose original path sorts first is kept.
+    ///
+    /// Returns the number of entries which were renamed or removed.
+    pub fn map_keys(&self, f: impl Fn(&Path) -> Option<PathBuf>) -> Result<usize, VdfCacheError> {
+        self.rekey_entries(|src_path| Some(self.key(f(src_path)?)))
+    }
+
+    //Normalize any keys which were saved without being normalized, so that they can be found. Nothing is done (and
+    //nothing needs to be saved) if every key is already normalized.
+    pub(crate) fn normalize_loaded_keys(&self) -> Result<usize, VdfCacheError> {
+        let is_normalized = |key: &Path| key.is_absolute() && normalize_path(key) == key;
+        if self.cache.keys().iter().all(|key| is_normalized(key)) {
+            return Ok(0);
         }
+
+        self.rekey_entries(|src_path| {
+            if is_normalized(src_path) {
+                Some(src_path.to_path_buf())
+            } else {
+                Some(self.key(src_path))
+            }
+        })
     }
 
-    /// Save the cache to disk.
+    //Move each entry to the key returned by new_key, changing the path stored inside any error to match. Entries
+    //for which None is returned are removed.
+    fn rekey_entries(&self, new_key: impl Fn(&Path) -> Option<PathBuf>) -> Result<usize, VdfCacheError> {
+        let ret = self.cache.map_keys(|src_path, entry| {
+            let new_key = new_key(src_path)?;
+            if new_key == src_path {
+                return Some((new_key, entry));
+            }
+            Some((new_key.clone(), entry.with_src_path(&new_key)))
+        });
+        self.rebuild_key_index();
+
+        ret.map_err(VdfCacheError::from_cache_write)
+    }
+
+    /// Move the entry for ``old`` to ``new``, for instance after a file has been renamed, so that its hash does not
+    /// need to be created again. User data and entry metadata are moved with the entry, and for entries where hash
+    /// creation failed the path stored in the error is changed to ``new``. The [VideoHash] keeps the path of the
+    /// file it was created from, so the entry is reported by
+    /// [check_path_consistency][`VideoHashFilesystemCache::check_path_consistency`] until it is hashed again. The
+    /// filesystem is only visited to canonicalize ``old`` and ``new`` when
+    /// [canonicalize_paths][`VideoHashFilesystemCacheBuilder::canonicalize_paths`] is enabled. Use
+    /// [rename_verified][`VideoHashFilesystemCache::rename_verified`] to check that the file at ``new`` is the same
+    /// video.
     ///
-    ///Returns an error if it was not possible to write the cache to disk.
-    pub fn save(&self) -> Result<(), VdfCacheError> {
-        self.0.save().map_err(VdfCacheError::from)
+    /// This is the same as using [map_keys][`VideoHashFilesystemCache::map_keys`] to change a single path, except
+    /// that an existing entry is never replaced.
+    ///
+    /// Returns [NotCached][VdfCacheError::NotCached] if ``old`` is not in the cache, and
+    /// [AlreadyCached][VdfCacheError::AlreadyCached] if ``new`` is already in the cache.
+    ///
+    /// If an update of the cache is in progress, this waits for it to finish, so that the entry is not moved while
+    /// the update is detecting moved files.
+    pub fn rename(&self, old: impl AsRef<Path>, new: impl AsRef<Path>) -> Result<(), VdfCacheError> {
+        let _update_guard = self.lock_updates();
+        let old_key = self.key(old);
+        let entry = self.fetch_fs_entry(&old_key)?;
+        self.rename_entry(old_key, self.key(new), entry.value)
     }
 
-    /// For all files on the filesystem matching ``file_projection``, update the cache for all new or modified files.
-    /// Also, remove items from the cache if they no longer exist in the underlying filesystem.
+    /// The same as [rename][`VideoHashFilesystemCache::rename`], but first checks that the file at ``new`` has the
+    /// modification time recorded for ``old``, and the same size if its [VideoStats] were stored. If a staleness
+    /// function has been set with [set_staleness_fn][`VideoHashFilesystemCache::set_staleness_fn`] then it is used
+    /// instead of comparing modification times.
     ///
-    /// # Return values
-    /// This function will return ``Err`` if any fatal error occurs. Otherwise, it returns a group
-    /// of nonfatal errors, typically a list of paths for which a [`VideoHash`] could not be generated.
+    /// Returns [RenamedFileDiffers][VdfCacheError::RenamedFileDiffers] if the file does not match, or
+    /// [FileInaccessible][VdfCacheError::FileInaccessible] if it cannot be read.
+    pub fn rename_verified(&self, old: impl AsRef<Path>, new: impl AsRef<Path>) -> Result<(), VdfCacheError> {
+        let _update_guard = self.lock_updates();
+        let old_key = self.key(old);
+        let new_key = self.key(new);
+        let entry = self.fetch_fs_entry(&old_key)?;
+
+        let metadata = fs::metadata(verbatim_path(&new_key));
+        let metadata = metadata.and_then(|metadata| Ok((metadata.modified()?, metadata.len())));
+        let (mtime, len) = metadata.map_err(|src| VdfCacheError::FileInaccessible {
+            path: new_key.clone(),
+            src: src.into(),
+        })?;
+
+        let same_size = match &entry.value.0 {
+            EntryState::Hashed(CachedVideoData { stats: Some(stats), .. }) => stats.size == len,
+            _ => true,
+        };
+        if !same_size || !self.cache.is_fresh(&new_key, &entry, mtime, len) {
+            return Err(VdfCacheError::RenamedFileDiffers {
+                old: old_key,
+                new: new_key,
+            });
+        }
+
+        self.rename_entry(old_key, new_key, entry.value)
+    }
+
+    //The entry for key, without counting the fetch as an access.
+    fn fetch_fs_entry(&self, key: &Path) -> Result<FsCacheEntry<CacheEntry>, VdfCacheError> {
+        self.cache.fetch_entry(key).map_err(VdfCacheError::from)
+    }
+
+    fn rename_entry(&self, old_key: PathBuf, new_key: PathBuf, value: CacheEntry) -> Result<(), VdfCacheError> {
+        if new_key == old_key {
+            return Ok(());
+        }
+
+        let value = value.with_src_path(&new_key);
+        match self.cache.rename(&old_key, new_key.clone(), value) {
+            Ok(()) => {
+                self.move_key(&old_key, &new_key);
+                Ok(())
+            }
+            Err(FsCacheErrorKind::KeyExists(_)) => Err(VdfCacheError::AlreadyCached(new_key)),
+            Err(e) => Err(VdfCacheError::from_cache_write(e)),
+        }
+    }
+
+    /// Attach a user-defined value called ``name`` to the cache entry for ``src_path``, replacing any existing
+    /// value with the same name. This can be used to record information such as decisions made during deduplication.
     ///
-    /// ## Fatal errors
-    ///    * Unable to read any of the starting directories in ``file_projection``
-    ///    * Any Io error when reading/writing to the cache file itself.
+    /// User data is saved in the cache file along with the entry, and is kept when the hash is recreated because the
+    /// file was modified. It is removed when the entry is removed from the cache, e.g. because the file was deleted.
     ///
-    /// ## Nonfatal errors
-    ///    * Failure to create a hash from any individual file.
-    ///    * Failure to remove an item from the cache (This is unlikely and should only occur if
-    ///      calling this function more than once at the same time with overlapping paths)
+    /// Returns [NotCached][VdfCacheError::NotCached] if ``src_path`` is not in the cache.
+    pub fn set_user_data(
+        &self,
+        src_path: impl AsRef<Path>,
+        name: &str,
+        value: serde_json::Value,
+    ) -> Result<(), VdfCacheError> {
+        self.cache
+            .set_user_data(&self.key(src_path), name, value.to_string())
+            .map_err(VdfCacheError::from_cache_write)
+    }
+
+    /// Get the user-defined value called ``name`` for ``src_path``, if one has been set with
+    /// [set_user_data][`VideoHashFilesystemCache::set_user_data`].
+    pub fn get_user_data(&self, src_path: impl AsRef<Path>, name: &str) -> Option<serde_json::Value> {
+        let value = self.cache.get_user_data(&self.key(src_path), name)?;
+        serde_json::from_str(&value).ok()
+    }
+
+    /// Remove the user-defined value called ``name`` for ``src_path``, returning it if it existed.
+    pub fn remove_user_data(
+        &self,
+        src_path: impl AsRef<Path>,
+        name: &str,
+    ) -> Result<Option<serde_json::Value>, VdfCacheError> {
+        let value = self
+            .cache
+            .remove_user_data(&self.key(src_path), name)
+            .map_err(VdfCacheError::from_cache_write)?;
+        Ok(value.and_then(|value| serde_json::from_str(&value).ok()))
+    }
+
+    /// Store ``hash`` under an arbitrary ``key`` instead of a path, for videos which are not files on the filesystem
+    /// (for instance videos in a content-addressed store). Any hash already stored under ``key`` is replaced. The hash
+    /// must be created by the caller, e.g. from a temporary copy of the video.
     ///
-    /// # Parallelism
-    /// To speed up loading there is a cargo feature to allow hashes to be created from videos in parallel.
-    /// Parallel loading is much faster than sequential loading but be aware that since Ffmpeg is already multithreaded
-    /// this can use up a lot of CPU time.
-    pub fn update_using_fs(&self, file_projection: &FileProjection) -> Result<Vec<VdfCacheError>, VdfCacheError> {
-        let mut errs_ret = vec![];
+    /// Hashes stored by key are kept separately from entries stored by path, and have no modification time, so they
+    /// are never checked against the filesystem. Methods which take or return paths (such as
+    /// [update_using_fs][`VideoHashFilesystemCache::update_using_fs`], [drain][`VideoHashFilesystemCache::drain`] and
+    /// [snapshot][`VideoHashFilesystemCache::snapshot`]) do not see them, and they are only removed by
+    /// [remove_by_key][`VideoHashFilesystemCache::remove_by_key`].
+    ///
+    /// Counts as one modification towards automatically saving the cache.
+    pub fn insert_by_key(&self, key: impl Into<String>, hash: VideoHash) -> Result<(), VdfCacheError> {
+        let entry = CacheEntry(EntryState::Hashed(CachedVideoData { hash, stats: None }));
+        self.cache
+            .insert_keyed(key.into(), entry)
+            .map_err(VdfCacheError::from_cache_write)
+    }
 
-        let cached_paths_in_projection = self
-            .all_cached_paths()
-            .into_iter()
-            .filter(|src_path| file_projection.contains(src_path));
+    /// Fetch the hash stored under ``key`` by [insert_by_key][`VideoHashFilesystemCache::insert_by_key`].
+    ///
+    /// Returns [KeyNotCached][VdfCacheError::KeyNotCached] if no hash is stored under ``key``.
+    pub fn fetch_by_key(&self, key: &str) -> Result<VideoHash, VdfCacheError> {
+        match self.cache.fetch_keyed(key) {
+            Some(CacheEntry(EntryState::Hashed(CachedVideoData { hash, stats: _stats }))) => Ok(hash),
+            _ => Err(VdfCacheError::KeyNotCached(key.to_string())),
+        }
+    }
 
-        let all_update_paths_iter = cached_paths_in_projection.chain(file_projection.projected_files().iter().cloned());
+    /// Remove the hash stored under ``key`` by [insert_by_key][`VideoHashFilesystemCache::insert_by_key`]. Returns
+    /// true if a hash was removed.
+    pub fn remove_by_key(&self, key: &str) -> Result<bool, VdfCacheError> {
+        self.cache.remove_keyed(key).map_err(VdfCacheError::from_cache_write)
+    }
 
-        let all_update_paths = all_update_paths_iter
-            .map(PathBuf::from)
-            .collect::<HashSet<_, RandomState>>();
+    /// Get every key under which a hash has been stored by
+    /// [insert_by_key][`VideoHashFilesystemCache::insert_by_key`], in sorted order.
+    pub fn all_keys(&self) -> Vec<String> {
+        self.cache.keyed_keys().into_iter().sorted().collect()
+    }
 
-        //Delete those items which have disappeared from the filesystem,
-        // and add what's new.
-        #[cfg(feature = "parallel_loading")]
-        errs_ret.par_extend(
-            all_update_paths
-                .par_iter()
-                .filter_map(|path| match self.fetch_update(&path) {
-                    Ok(Some(Err(e))) => Some(VdfCacheError::from(e)),
-                    Err(e) => Some(e),
-                    _ => None,
-                }),
-        );
+    /// When the entry for ``src_path`` was created and last accessed, or None if the cache has no entry for
+    /// ``src_path``. An entry is accessed when it is returned by [fetch][`VideoHashFilesystemCache::fetch`],
+    /// [fetch_stats][`VideoHashFilesystemCache::fetch_stats`] or
+    /// [fetch_update][`VideoHashFilesystemCache::fetch_update`], or when its hash is created. Calling this method
+    /// does not count as an access.
+    ///
+    /// Accessing an entry does not count as a modification towards automatically saving the cache, so access times
+    /// are only written to disk when the cache is saved for another reason. Entries loaded from cache files written
+    /// before these times were stored are treated as if they were created when the cache was loaded.
+    pub fn entry_metadata(&self, src_path: impl AsRef<Path>) -> Option<EntryMeta> {
+        self.cache.entry_meta(&self.key(src_path))
+    }
 
-        #[cfg(not(feature = "parallel_loading"))]
-        errs_ret.extend(
-            all_update_paths
-                .iter()
-                .filter_map(|path| match self.fetch_update(&path) {
-                    Ok(Some(Err(e))) => Some(VdfCacheError::from(e)),
-                    Err(e) => Some(e),
-                    _ => None,
-                }),
-        );
-        Ok(errs_ret)
+    /// If ``src_path`` has not been modified since it was cached, then return the cached hash.
+    /// If ``src_path`` has been deleted or is no longer a regular file, then remove it from the cache and return
+    /// [Deleted][FetchUpdateResult::Deleted].
+    /// If ``src_path`` exists but cannot be accessed (e.g. due to its permissions), then leave the cache unchanged and
+    /// return [Inaccessible][FetchUpdateResult::Inaccessible].
+    /// Otherwise create a new hash, insert it into the cache, and return it.
+    ///
+    /// Returns an error if it was not possible to read or write the cache.
+    pub fn fetch_update(&self, src_path: impl AsRef<Path>) -> Result<FetchUpdateResult, VdfCacheError> {
+        self.fetch_update_tracked(src_path, false)
+            .map(|(result, _change)| result)
     }
 
-    fn fetch_entry(&self, src_path: impl AsRef<Path>) -> Result<CacheEntry, VdfCacheError> {
-        self.0
-            .fetch(src_path.as_ref().to_path_buf())
-            .map_err(VdfCacheError::from)
+    //fetch_update, but create a new hash even if the cached hash is fresh.
+    pub(crate) fn fetch_update_forced(&self, src_path: impl AsRef<Path>) -> Result<FetchUpdateResult, VdfCacheError> {
+        self.fetch_update_tracked(src_path, true)
+            .map(|(result, _change)| result)
+    }
+
+    //fetch_update, also returning how the cache was changed. If force is true, a new hash is created even if the
+    //cached hash is fresh.
+    fn fetch_update_tracked(
+        &self,
+        src_path: impl AsRef<Path>,
+        force: bool,
+    ) -> Result<(FetchUpdateResult, Option<UpdateChange>), VdfCacheError> {
+        let key = self.insert_key(src_path);
+
+        let hashed = |entry: CacheEntry| match self.apply_min_duration(&key, entry).0 {
+            EntryState::Hashed(data) => FetchUpdateResult::Hashed(Ok(data.hash)),
+            EntryState::Failed(e) | EntryState::FailedWithProbe(e, _) => FetchUpdateResult::Hashed(Err(e)),
+            //Metadata-only entries are always replaced by fetch_update, and hashes are never loaded as one.
+            EntryState::MetadataOnly(_) => unreachable!("fetch_update returned a metadata-only entry"),
+        };
+
+        let outcome = if force {
+            self.cache.reload(&key)
+        } else {
+            self.cache.fetch_update(&key)
+        };
+
+        match outcome {
+            Ok(FetchUpdateOutcome::Value(entry)) => Ok((hashed(entry), None)),
+            Ok(FetchUpdateOutcome::Loaded { value, replaced }) => {
+                let change = if replaced {
+                    UpdateChange::Refreshed
+                } else {
+                    UpdateChange::Added
+                };
+                Ok((hashed(value), Some(change)))
+            }
+            Ok(FetchUpdateOutcome::Deleted { removed }) => {
+                self.forget_keys(&[key]);
+                Ok((FetchUpdateResult::Deleted, removed.then_some(UpdateChange::Removed)))
+            }
+            Ok(FetchUpdateOutcome::Inaccessible(e)) => Ok((FetchUpdateResult::Inaccessible(e), None)),
+            //Every new hash is accepted.
+            Ok(FetchUpdateOutcome::Rejected(_)) => unreachable!("fetch_update rejected a new hash"),
+            Err(cache_error) => Err(VdfCacheError::from_cache_write(cache_error)),
+        }
+    }
+
+    /// Problems found while loading the cache file. If [strict_load][`VideoHashFilesystemCacheBuilder::strict_load`]
+    /// was disabled, entries which could not be decoded were skipped, and are listed here so that they can be logged.
+    /// Otherwise this is always empty, because the cache fails to load instead. Skipped entries are no longer in the
+    /// cache, so they are hashed again by the next call to
+    /// [update_using_fs][`VideoHashFilesystemCache::update_using_fs`] or
+    /// [fetch_update][`VideoHashFilesystemCache::fetch_update`], and are removed from the cache file by the next save.
+    pub fn load_report(&self) -> &LoadReport {
+        self.cache.load_report()
+    }
+
+    /// Replace the check used by [fetch_update][`VideoHashFilesystemCache::fetch_update`] and
+    /// [update_using_fs][`VideoHashFilesystemCache::update_using_fs`] to decide whether a cached hash is out of date.
+    /// ``is_stale`` is called with the path of each cached file, the metadata stored when its hash was created, and its
+    /// current metadata, and returns true if the hash must be created again.
+    ///
+    /// This allows the cache to be used with filesystems whose metadata is unreliable, such as cloud sync services
+    /// which rewrite modification times. By default a hash is recreated whenever the modification time of its file
+    /// has changed.
+    ///
+    /// This applies to all clones of the cache.
+    ///
+    /// # Example
+    /// Only recreate a hash when the modification time of its file moves forwards, for a sync service which sometimes
+    /// resets modification times to an earlier value.
+    /// ```rust,no_run
+    /// # use std::path::PathBuf;
+    /// use video_hash_filesystem_cache::*;
+    ///
+    /// let cache = VideoHashFilesystemCache::new(100, PathBuf::from("cache.bin")).unwrap();
+    /// cache.set_staleness_fn(|_path, stored, current| current.mtime > stored.mtime);
+    /// ```
+    pub fn set_staleness_fn(&self, is_stale: impl Fn(&Path, StoredMeta, CurrentMeta) -> bool + Send + Sync + 'static) {
+        self.cache.set_staleness_fn(Box::new(is_stale))
+    }
+
+    /// Save the cache to disk.
+    ///
+    /// It is safe to call this from another thread while [update_using_fs][`Self::update_using_fs`]
+    /// is running. The saved file contains a consistent snapshot of the cache at some point during the update.
+    ///
+    ///Returns an error if it was not possible to write the cache to disk.
+    pub fn save(&self) -> Result<(), VdfCacheError> {
+        #[cfg(feature = "metrics")]
+        self.record_entry_metrics();
+
+        self.cache.save().map_err(VdfCacheError::from_cache_write)
+    }
+
+    /// Move the cache file to ``new_path``, for instance onto a different disk. The cache is saved to ``new_path``
+    /// and then read back to check that the new file is valid. Only then is the old cache file deleted, and all
+    /// future saves are made to ``new_path``.
+    ///
+    /// If the cache cannot be saved to or read back from ``new_path``, or the old cache file cannot be deleted, then
+    /// the file at ``new_path`` is deleted, the old cache file is left intact, the cache continues to save to its old
+    /// location, and an error is returned. Modifications are only counted as saved once the move has succeeded.
+    pub fn move_to(&self, new_path: PathBuf) -> Result<(), VdfCacheError> {
+        self.cache.move_to(new_path).map_err(VdfCacheError::from_cache_write)
+    }
+
+    /// The number of modifications (insertions, updates and removals) made to the cache since it was
+    /// last saved. When this reaches the `cache_save_threshold` passed to [new][`VideoHashFilesystemCache::new`],
+    /// the cache saves itself and the count returns to zero. Fetching an entry only updates its access time (see
+    /// [entry_metadata][`VideoHashFilesystemCache::entry_metadata`]), which is not counted here, so access times
+    /// are only written to disk with the next save.
+    ///
+    /// Callers can use this to decide whether to [save][`VideoHashFilesystemCache::save`] early, e.g. before a
+    /// long-running or risky operation.
+    pub fn pending_saves(&self) -> u32 {
+        self.cache.pending_saves()
+    }
+
+    /// Reset the count returned by [pending_saves][`VideoHashFilesystemCache::pending_saves`] to zero
+    /// without writing the cache to disk. Unsaved modifications remain in memory, but the next
+    /// automatic save is postponed until another `cache_save_threshold` modifications have been made.
+    pub fn flush_without_save(&self) {
+        self.cache.flush_without_save()
+    }
+
+    /// Get the time spent in each stage of the last call to
+    /// [update_using_fs][`VideoHashFilesystemCache::update_using_fs`], including the time spent projecting the
+    /// [FileProjection] that was passed to it. All times are zero if update_using_fs has not been called.
+    pub fn last_timings(&self) -> ScanTimings {
+        *self.last_timings.lock().unwrap()
+    }
+
+    /// Release any excess memory held by the cache, for instance after removing many entries. This is useful
+    /// for long-running processes.
+    pub fn shrink_to_fit(&self) {
+        self.cache.shrink_to_fit()
+    }
+
+    /// A rough estimate of the number of bytes of memory used by the contents of the cache.
+    pub fn memory_usage_estimate(&self) -> usize {
+        self.cache.memory_usage_estimate()
+    }
+
+    /// Reclaim space after many entries have been removed, for instance after pruning a large part of the cache.
+    /// Excess memory is released in the same way as [shrink_to_fit][`VideoHashFilesystemCache::shrink_to_fit`], and
+    /// the cache is then saved. Every save writes the cache file from scratch, so the saved file contains only the
+    /// current entries.
+    ///
+    /// Returns the memory used and the size of the cache file before and after compaction, e.g. for logging.
+    ///
+    /// Returns an error if it was not possible to write the cache to disk.
+    pub fn compact(&self) -> Result<CompactReport, VdfCacheError> {
+        let cache_path = self.cache.cache_path();
+        let file_size = || fs::metadata(&cache_path).map(|metadata| metadata.len()).ok();
+
+        let memory_before = self.memory_usage_estimate();
+        let file_size_before = file_size();
+
+        self.shrink_to_fit();
+        self.save()?;
+
+        let report = CompactReport {
+            memory_before,
+            memory_after: self.memory_usage_estimate(),
+            file_size_before,
+            file_size_after: file_size().unwrap_or_default(),
+        };
+        debug!("Compacted cache at {}: {:?}", cache_path.display(), report);
+
+        Ok(report)
+    }
+
+    /// Get statistics about the time spent creating hashes since the cache was created, or since
+    /// the last call to [reset_stats][`VideoHashFilesystemCache::reset_stats`].
+    pub fn stats(&self) -> CacheStats {
+        self.cache.interface().stats().stats()
+    }
+
+    /// Reset all statistics returned by [stats][`VideoHashFilesystemCache::stats`] to zero.
+    pub fn reset_stats(&self) {
+        self.cache.interface().stats().reset()
+    }
+
+    /// For all files on the filesystem matching ``file_projection``, update the cache for all new or modified files.
+    /// Also, remove items from the cache if they no longer exist in the underlying filesystem.
+    ///
+    /// # Return values
+    /// This function will return ``Err`` if any fatal error occurs. Otherwise, it returns a group
+    /// of nonfatal errors, typically a list of paths for which a [`VideoHash`] could not be generated.
+    ///
+    /// ## Fatal errors
+    ///    * Unable to read any of the starting directories in ``file_projection``
+    ///    * Any Io error when reading/writing to the cache file itself. This is returned as
+    ///      [SaveFailed][VdfCacheError::SaveFailed], and the update stops as soon as possible afterwards.
+    ///      Hashes created before the failure are kept in memory, so they can be saved later with
+    ///      [save][`VideoHashFilesystemCache::save`].
+    ///
+    /// ## Nonfatal errors
+    ///    * Failure to create a hash from any individual file.
+    ///    * Failure to access any individual file. The cached entries for inaccessible files are preserved.
+    ///    * Failure to remove an item from the cache (This is unlikely and should only occur if
+    ///      the item is removed by another thread at the same time)
+    ///
+    /// If the cache has an [error_handler][`VideoHashFilesystemCacheBuilder::error_handler`], nonfatal errors are
+    /// passed to it instead, and the returned list is empty.
+    ///
+    /// # Concurrent updates
+    /// Only one update runs at a time. If this function (or
+    /// [update_using_fs_tracked][`VideoHashFilesystemCache::update_using_fs_tracked`] or
+    /// [update_using_projection][`VideoHashFilesystemCache::update_using_projection`]) is called while another
+    /// update of the same cache is in progress, including through a clone of the cache, it waits for that update to
+    /// finish before starting. Each update then sees the changes made by the previous one, so overlapping projections
+    /// can safely be updated from several threads. [rename][`VideoHashFilesystemCache::rename`] and
+    /// [rename_verified][`VideoHashFilesystemCache::rename_verified`] also wait. Other methods, such as
+    /// [fetch][`VideoHashFilesystemCache::fetch`], do not.
+    ///
+    /// # Ordering
+    /// Files are processed in the order set by [update_order][`VideoHashFilesystemCacheBuilder::update_order`].
+    /// When parallel loading is enabled, files are dispatched to worker threads in that order, so
+    /// the order in which they complete is approximate.
+    ///
+    /// # Parallelism
+    /// To speed up loading there is a cargo feature to allow hashes to be created from videos in parallel.
+    /// Parallel loading is much faster than sequential loading but be aware that since Ffmpeg is already multithreaded
+    /// this can use up a lot of CPU time. The number of hashes created at the same time can be limited with
+    /// [max_parallel_hashes][`VideoHashFilesystemCacheBuilder::max_parallel_hashes`].
+    ///
+    /// # Tracing
+    /// When the `tracing` cargo feature is enabled, the update runs inside an `update_using_fs` span, and each
+    /// hash is created inside a child `hash_creation` span recording the path, duration and outcome, including when
+    /// hashes are created on other threads. Saves and removals are also recorded as events. Messages are still sent
+    /// to `log` as usual.
+    pub fn update_using_fs(&self, file_projection: &FileProjection) -> Result<Vec<VdfCacheError>, VdfCacheError> {
+        let _update_guard = self.lock_updates();
+        let start_time = Instant::now();
+
+        let mut all_update_paths = self.cached_paths_in(file_projection);
+        all_update_paths.extend(file_projection.projected_files().iter().cloned());
+
+        self.update_paths(
+            all_update_paths,
+            file_projection.projection_time(),
+            start_time,
+            UpdateOptions::default(),
+        )
+        .map(UpdateDelta::into_errors)
+    }
+
+    /// The same as [update_using_fs][`VideoHashFilesystemCache::update_using_fs`], but also returns which paths were
+    /// added to, refreshed in or removed from the cache, so that applications can update their own state
+    /// incrementally. See [UpdateDelta].
+    pub fn update_using_fs_tracked(&self, file_projection: &FileProjection) -> Result<UpdateDelta, VdfCacheError> {
+        let _update_guard = self.lock_updates();
+        let start_time = Instant::now();
+
+        let mut all_update_paths = self.cached_paths_in(file_projection);
+        all_update_paths.extend(file_projection.projected_files().iter().cloned());
+
+        self.update_paths(
+            all_update_paths,
+            file_projection.projection_time(),
+            start_time,
+            UpdateOptions::default(),
+        )
+    }
+
+    /// The same as [update_using_fs][`VideoHashFilesystemCache::update_using_fs`], but also returns the hashes which
+    /// were created by the update, i.e. the hashes of files which were added to the cache or which were modified.
+    /// Hashes which were already cached are not returned. This allows other structures (such as a search index) to
+    /// be updated without fetching every hash from the cache again.
+    ///
+    /// Returns the new hashes in no particular order, along with the same errors as
+    /// [update_using_fs][`VideoHashFilesystemCache::update_using_fs`].
+    pub fn update_using_fs_collect_hashes(
+        &self,
+        file_projection: &FileProjection,
+    ) -> Result<(NewHashes, Vec<VdfCacheError>), VdfCacheError> {
+        let _update_guard = self.lock_updates();
+        let start_time = Instant::now();
+
+        let mut all_update_paths = self.cached_paths_in(file_projection);
+        all_update_paths.extend(file_projection.projected_files().iter().cloned());
+
+        //The hashes are collected as they are created, so that they cannot be changed by other threads before they
+        //are returned.
+        let (delta, new_hashes) = self.update_paths_collecting(
+            all_update_paths,
+            file_projection.projection_time(),
+            start_time,
+            UpdateOptions::default(),
+        )?;
+
+        Ok((new_hashes, delta.into_errors()))
+    }
+
+    /// The same as [update_using_fs_tracked][`VideoHashFilesystemCache::update_using_fs_tracked`], but using the
+    /// given [UpdateOptions]. This allows a large update to be spread over several runs, for instance when it must
+    /// finish before a backup starts, or very long videos to be left out. The returned [UpdateDelta] records whether
+    /// the time budget ran out, how many paths were not checked, and which paths were skipped for exceeding the
+    /// limits.
+    pub fn update_using_fs_with_options(
+        &self,
+        file_projection: &FileProjection,
+        options: UpdateOptions,
+    ) -> Result<UpdateDelta, VdfCacheError> {
+        let _update_guard = self.lock_updates();
+        let start_time = Instant::now();
+
+        let mut all_update_paths = self.cached_paths_in(file_projection);
+        all_update_paths.extend(file_projection.projected_files().iter().cloned());
+
+        self.update_paths(all_update_paths, file_projection.projection_time(), start_time, options)
+    }
+
+    /// The same as [update_using_fs][`VideoHashFilesystemCache::update_using_fs`], but consumes the projection
+    /// so that its projected files can be used without being copied. This saves memory and time for very large
+    /// projections which are not needed after the update.
+    pub fn update_using_projection(
+        &self,
+        file_projection: FileProjection,
+    ) -> Result<Vec<VdfCacheError>, VdfCacheError> {
+        let _update_guard = self.lock_updates();
+        let start_time = Instant::now();
+
+        let mut all_update_paths = self.cached_paths_in(&file_projection);
+        let projection_time = file_projection.projection_time();
+        all_update_paths.extend(file_projection.into_projected_files());
+
+        self.update_paths(all_update_paths, projection_time, start_time, UpdateOptions::default())
+            .map(UpdateDelta::into_errors)
+    }
+
+    /// Update exactly the given paths, in the same way as [fetch_update][`VideoHashFilesystemCache::fetch_update`].
+    /// Other cached paths are not checked, even if they have been deleted. This is faster than building a
+    /// [FileProjection] when the caller already knows which files have changed, for instance from a filesystem
+    /// watcher.
+    ///
+    /// Returns the same errors as [update_using_fs][`VideoHashFilesystemCache::update_using_fs`], along with the path
+    /// that each nonfatal error occurred for.
+    pub fn update_subset(
+        &self,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Result<Vec<(PathBuf, VdfCacheError)>, VdfCacheError> {
+        let _update_guard = self.lock_updates();
+        let start_time = Instant::now();
+
+        let update_paths = paths.into_iter().map(|path| path.as_ref().to_path_buf()).collect();
+
+        self.update_paths(
+            update_paths,
+            Duration::from_secs(0),
+            start_time,
+            UpdateOptions::default(),
+        )
+        .map(|delta| delta.errors)
+    }
+
+    /// Hash a sample of the files in a projection, to estimate how many files can be hashed and how long a full
+    /// update would take before committing to it. Each projected file is selected with probability ``fraction``
+    /// (between 0 and 1), using a hash of its path and ``seed``. The hashes are stored in the cache as normal.
+    ///
+    /// The same files are selected each time for the same ``seed``, and raising ``fraction`` only adds files to the
+    /// sample, so the sample grows predictably. Unlike [update_using_fs][`VideoHashFilesystemCache::update_using_fs`],
+    /// cached files which are not in the projection are not removed.
+    ///
+    /// Returns an error if ``fraction`` is not between 0 and 1, or if the cache could not be saved.
+    pub fn update_sample(
+        &self,
+        file_projection: &FileProjection,
+        fraction: f64,
+        seed: u64,
+    ) -> Result<SampleReport, VdfCacheError> {
+        if !(0.0..=1.0).contains(&fraction) {
+            let e = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "sample fraction must be between 0 and 1",
+            );
+            return Err(FsCacheErrorKind::io(self.cache.cache_path(), e).into());
+        }
+
+        let _update_guard = self.lock_updates();
+        let start_time = Instant::now();
+
+        let projected_files = file_projection.projected_files();
+        let sample_paths = projected_files
+            .iter()
+            .filter(|src_path| sample_position(src_path, seed) < fraction)
+            .cloned()
+            .collect::<HashSet<_>>();
+        let uncached_count = projected_files.len() - self.cache.count_contained(&self.keys_of(projected_files));
+        let sampled = sample_paths.len();
+
+        let delta = self.update_paths(
+            sample_paths,
+            file_projection.projection_time(),
+            start_time,
+            UpdateOptions::default(),
+        )?;
+
+        //Every file which was not hashed again was already cached.
+        let hashed = delta.hashed;
+        let average_duration = match u32::try_from(hashed) {
+            Ok(0) | Err(_) => Duration::from_secs(0),
+            Ok(hashed) => delta.elapsed / hashed,
+        };
+        let failed = delta.errors.len() + delta.errors_handled;
+        let removed = delta.removed.len();
+
+        Ok(SampleReport {
+            sampled,
+            succeeded: sampled.saturating_sub(failed + removed),
+            failed,
+            already_cached: sampled.saturating_sub(hashed.saturating_add(removed)),
+            average_duration,
+            estimated_total: average_duration.mul_f64(uncached_count as f64),
+        })
+    }
+
+    /// The number of hashes that [update_using_fs][`VideoHashFilesystemCache::update_using_fs`] creates at the same
+    /// time. See [max_parallel_hashes][`VideoHashFilesystemCacheBuilder::max_parallel_hashes`].
+    pub fn parallel_hashes_count(&self) -> usize {
+        #[cfg(feature = "parallel_loading")]
+        match (&self.thread_pool, self.max_parallel_hashes) {
+            (_, 1) => 1,
+            (Some(pool), _) => pool.current_num_threads(),
+            (None, _) => rayon::current_num_threads(),
+        }
+
+        #[cfg(not(feature = "parallel_loading"))]
+        1
+    }
+
+    //The paths of all hashes in the cache which are contained by the projection. The projection may visit the
+    //filesystem, so it is only checked once the paths have been copied and the cache is unlocked.
+    fn cached_paths_in(&self, file_projection: &FileProjection) -> HashSet<PathBuf, RandomState> {
+        self.all_cached_paths()
+            .into_iter()
+            .filter(|src_path| file_projection.contains(src_path))
+            .collect()
+    }
+
+    fn update_paths(
+        &self,
+        all_update_paths: HashSet<PathBuf, RandomState>,
+        projection_time: Duration,
+        start_time: Instant,
+        options: UpdateOptions,
+    ) -> Result<UpdateDelta, VdfCacheError> {
+        self.update_paths_collecting(all_update_paths, projection_time, start_time, options)
+            .map(|(delta, _new_hashes)| delta)
+    }
+
+    //update_paths, also returning the hashes which were created.
+    fn update_paths_collecting(
+        &self,
+        all_update_paths: HashSet<PathBuf, RandomState>,
+        projection_time: Duration,
+        start_time: Instant,
+        options: UpdateOptions,
+    ) -> Result<(UpdateDelta, NewHashes), VdfCacheError> {
+        let mut changes = vec![];
+
+        #[cfg(feature = "tracing")]
+        let update_span = tracing::info_span!(
+            "update_using_fs",
+            paths = all_update_paths.len(),
+            projection_ms = projection_time.as_millis() as u64,
+            errors = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = update_span.enter();
+
+        let mut all_update_paths = all_update_paths;
+        let moved = if options.detect_moves {
+            self.move_renamed_files(&mut all_update_paths)
+        } else {
+            vec![]
+        };
+
+        let all_update_paths = self.sort_update_paths(all_update_paths);
+        let hashing_start_time = Instant::now();
+
+        //Delete those items which have disappeared from the filesystem,
+        // and add what's new. par_bridge hands out paths in order, so that
+        // the requested update order is respected.
+        //If the cache cannot be saved (e.g. its drive has been removed), stop creating
+        // hashes that cannot be saved either.
+        let save_failed = AtomicBool::new(false);
+        //Once the time budget has run out no more paths are started, but paths which are being hashed are finished.
+        let remaining = AtomicUsize::new(0);
+        let errors_handled = AtomicUsize::new(0);
+        let hashed = AtomicUsize::new(0);
+        //The time spent by all threads updating paths, and the part of it spent creating hashes, in nanoseconds.
+        let busy_nanos = AtomicU64::new(0);
+        let hashing_nanos = AtomicU64::new(0);
+        let update_one = |path: &PathBuf| {
+            if save_failed.load(Ordering::SeqCst) {
+                return None;
+            }
+            if options
+                .time_budget
+                .is_some_and(|time_budget| start_time.elapsed() >= time_budget)
+            {
+                remaining.fetch_add(1, Ordering::SeqCst);
+                return None;
+            }
+            let update_start_time = Instant::now();
+            //Rayon workers do not inherit the current span, so enter it explicitly to make each
+            //file's span a child of the bulk update.
+            #[cfg(feature = "tracing")]
+            let change = update_span.in_scope(|| self.update_one(path, options, &hashed, &hashing_nanos));
+            #[cfg(not(feature = "tracing"))]
+            let change = self.update_one(path, options, &hashed, &hashing_nanos);
+            busy_nanos.fetch_add(duration_nanos(update_start_time.elapsed()), Ordering::SeqCst);
+            if let Some(Err(VdfCacheError::SaveFailed { .. })) = change {
+                save_failed.store(true, Ordering::SeqCst);
+            }
+            //Save failures are fatal, so are always returned.
+            let change = match (change, &self.error_handler) {
+                (Some(Err(e)), Some(error_handler)) if !matches!(e, VdfCacheError::SaveFailed { .. }) => {
+                    errors_handled.fetch_add(1, Ordering::SeqCst);
+                    error_handler.handle(e);
+                    None
+                }
+                (change, _) => change,
+            };
+            change.map(|change| (path.clone(), change))
+        };
+
+        #[cfg(feature = "parallel_loading")]
+        match (&self.thread_pool, self.max_parallel_hashes) {
+            (_, 1) => changes.extend(all_update_paths.iter().filter_map(update_one)),
+            (Some(pool), _) => {
+                pool.install(|| changes.par_extend(all_update_paths.iter().par_bridge().filter_map(update_one)))
+            }
+            (None, _) => changes.par_extend(all_update_paths.iter().par_bridge().filter_map(update_one)),
+        }
+
+        #[cfg(not(feature = "parallel_loading"))]
+        changes.extend(all_update_paths.iter().filter_map(update_one));
+
+        let mut delta = UpdateDelta {
+            moved,
+            ..UpdateDelta::default()
+        };
+        let mut new_hashes = vec![];
+        for (path, change) in changes {
+            match change {
+                Ok((UpdateChange::Added, hash)) => {
+                    new_hashes.extend(hash.map(|hash| (path.clone(), hash)));
+                    delta.added.push(path)
+                }
+                Ok((UpdateChange::Refreshed, hash)) => {
+                    new_hashes.extend(hash.map(|hash| (path.clone(), hash)));
+                    delta.refreshed.push(path)
+                }
+                Ok((UpdateChange::Removed, _hash)) => delta.removed.push(path),
+                Ok((UpdateChange::Skipped(probe), _hash)) => delta.skipped.push((path, probe)),
+                Err(e) => delta.errors.push((path, e)),
+            }
+        }
+        delta.remaining = remaining.into_inner();
+        delta.errors_handled = errors_handled.into_inner();
+        delta.hashed = hashed.into_inner();
+        delta.budget_exhausted = delta.remaining > 0;
+        delta.elapsed = start_time.elapsed();
+        if delta.budget_exhausted {
+            info!(
+                "Time budget for update exhausted with {} paths not checked",
+                delta.remaining
+            );
+        }
+
+        #[cfg(feature = "tracing")]
+        update_span.record("errors", delta.errors.len());
+
+        #[cfg(feature = "metrics")]
+        self.record_entry_metrics();
+
+        //Divide the elapsed time of the update between hashing and reading metadata in proportion to the time
+        //spent on each by all threads.
+        let update_time = hashing_start_time.elapsed();
+        let hashing_time = match busy_nanos.into_inner() {
+            0 => Duration::from_secs(0),
+            busy_nanos => update_time.mul_f64((hashing_nanos.into_inner() as f64 / busy_nanos as f64).min(1.0)),
+        };
+        *self.last_timings.lock().unwrap() = ScanTimings {
+            projection: projection_time,
+            hashing: hashing_time,
+            io: (hashing_start_time - start_time) + update_time.saturating_sub(hashing_time),
+        };
+
+        match delta
+            .errors
+            .iter()
+            .position(|(_path, e)| matches!(e, VdfCacheError::SaveFailed { .. }))
+        {
+            Some(idx) => Err(delta.errors.swap_remove(idx).1),
+            None => Ok((delta, new_hashes)),
+        }
+    }
+
+    //Update a single path as part of a bulk update, returning how the cache was changed (with the new hash, if one
+    //was created) or any nonfatal error, or None if the cache already contained a hash for the path. If the file was
+    //hashed, whether or not a hash could be created, ``hashed`` is incremented and the time taken is added to
+    //``hashing_nanos``.
+    fn update_one(
+        &self,
+        path: &Path,
+        options: UpdateOptions,
+        hashed: &AtomicUsize,
+        hashing_nanos: &AtomicU64,
+    ) -> Option<Result<(UpdateChange, Option<VideoHash>), VdfCacheError>> {
+        if let Some(skipped) = self.skip_exceeding_limits(path, options) {
+            return Some(skipped.map(|probe| (UpdateChange::Skipped(probe), None)));
+        }
+
+        let fetch_start_time = Instant::now();
+        let tracked = self.fetch_update_tracked(path, false);
+        if let Ok((FetchUpdateResult::Hashed(_), Some(_change))) = &tracked {
+            hashed.fetch_add(1, Ordering::SeqCst);
+            hashing_nanos.fetch_add(duration_nanos(fetch_start_time.elapsed()), Ordering::SeqCst);
+        }
+
+        match tracked {
+            Ok((FetchUpdateResult::Hashed(Err(e)), _change)) => Some(Err(VdfCacheError::from(e))),
+            Ok((FetchUpdateResult::Inaccessible(src), _change)) => Some(Err(VdfCacheError::FileInaccessible {
+                path: path.to_path_buf(),
+                src: src.into(),
+            })),
+            Ok((FetchUpdateResult::Hashed(Ok(hash)), change)) => change.map(|change| Ok((change, Some(hash)))),
+            Ok((_result, change)) => change.map(|change| Ok((change, None))),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    //If the options limit which files are hashed and path needs a new hash, probe it. If it exceeds the limits then
+    //store a metadata-only entry for it and return the probe.
+    fn skip_exceeding_limits(&self, path: &Path, options: UpdateOptions) -> Option<Result<VideoProbe, VdfCacheError>> {
+        if !options.probes_files() {
+            return None;
+        }
+
+        let key = self.key(path);
+        let mtime = self.cache.needs_load(&key)?;
+
+        //A file which was skipped before is not probed again until it is modified.
+        let stored_probe = match self.cache.fetch_entry(&key) {
+            Ok(FsCacheEntry {
+                mtime: stored_mtime,
+                value: CacheEntry(EntryState::MetadataOnly(probe)),
+            }) if stored_mtime == mtime => Some(probe),
+            _ => None,
+        };
+        let probe = match stored_probe {
+            Some(probe) => probe,
+            None => self.video_prober.probe(&key)?,
+        };
+        if !options.exceeded_by(&probe) {
+            return None;
+        }
+
+        if stored_probe.is_none() {
+            let key = self.insert_key(&key);
+            let entry = FsCacheEntry {
+                mtime,
+                value: CacheEntry(EntryState::MetadataOnly(probe)),
+            };
+            if let Err(e) = self.cache.insert_many(std::iter::once((key, entry))) {
+                return Some(Err(VdfCacheError::from_cache_write(e)));
+            }
+        }
+        Some(Ok(probe))
+    }
+
+    //Find cached files which have been deleted and uncached files with the same size and mtime, move the entry of each
+    //unique pair to its new path, and remove both paths from the update. Returns the (old, new) paths of each move.
+    //Must be called while holding the update lock. A new path which has been cached since it was checked (e.g. by
+    //fetch_update, which does not take the update lock) is skipped, as the cache checks and renames atomically.
+    fn move_renamed_files(&self, paths: &mut HashSet<PathBuf>) -> Vec<(PathBuf, PathBuf)> {
+        let mut deleted = HashMap::<(u64, SystemTime), Vec<(PathBuf, FsCacheEntry<CacheEntry>)>>::new();
+        let mut uncached = HashMap::<(u64, SystemTime), Vec<PathBuf>>::new();
+
+        for path in paths.iter() {
+            let key = self.key(path);
+            match self.cache.fetch_entry(&key) {
+                Ok(entry) => {
+                    if fs::symlink_metadata(verbatim_path(&key)).is_ok() {
+                        continue;
+                    }
+                    if let EntryState::Hashed(CachedVideoData { stats: Some(stats), .. }) = &entry.value.0 {
+                        deleted
+                            .entry((stats.size, entry.mtime))
+                            .or_default()
+                            .push((path.clone(), entry));
+                    }
+                }
+                Err(_) => {
+                    let metadata = fs::metadata(verbatim_path(&key)).ok().filter(fs::Metadata::is_file);
+                    let size_and_mtime =
+                        metadata.and_then(|metadata| Some((metadata.len(), metadata.modified().ok()?)));
+                    if let Some(size_and_mtime) = size_and_mtime {
+                        uncached.entry(size_and_mtime).or_default().push(path.clone());
+                    }
+                }
+            }
+        }
+
+        let mut moved = vec![];
+        for (size_and_mtime, mut old) in deleted {
+            let new = match uncached.get(&size_and_mtime) {
+                Some(new) if old.len() == 1 && new.len() == 1 => &new[0],
+                _ => continue,
+            };
+            let (old_path, entry) = old.remove(0);
+
+            match self.rename_entry(self.key(&old_path), self.key(new), entry.value) {
+                Ok(()) => {
+                    debug!("Detected move from {} to {}", old_path.display(), new.display());
+                    paths.remove(&old_path);
+                    paths.remove(new);
+                    moved.push((old_path, new.clone()));
+                }
+                Err(VdfCacheError::AlreadyCached(_)) => {
+                    debug!(
+                        "Not moving {} to {}, which is already cached",
+                        old_path.display(),
+                        new.display()
+                    )
+                }
+                Err(e) => warn!("Failed to move {} to {}: {}", old_path.display(), new.display(), e),
+            }
+        }
+
+        moved
+    }
+
+    fn sort_update_paths(&self, paths: HashSet<PathBuf>) -> Vec<PathBuf> {
+        let mut paths = paths.into_iter().collect::<Vec<_>>();
+
+        let modified = |path: &Path| fs::metadata(verbatim_path(path)).and_then(|m| m.modified()).ok();
+
+        //Files with no available mtime are always placed last.
+        match self.update_order {
+            UpdateOrder::Unordered => (),
+            UpdateOrder::Alphabetical => paths.sort(),
+            UpdateOrder::ModifiedNewestFirst => paths.sort_by_cached_key(|path| {
+                let mtime = modified(path);
+                (mtime.is_none(), Reverse(mtime))
+            }),
+            UpdateOrder::ModifiedOldestFirst => paths.sort_by_cached_key(|path| {
+                let mtime = modified(path);
+                (mtime.is_none(), mtime)
+            }),
+        }
+
+        paths
+    }
+
+    fn fetch_entry(&self, src_path: impl AsRef<Path>) -> Result<CacheEntry, VdfCacheError> {
+        let key = self.key(src_path);
+        let entry = self.cache.fetch(&key);
+        cache_metrics::record_fetch(entry.is_ok());
+        if entry.is_ok() {
+            self.cache.touch(&key);
+        }
+
+        entry
+            .map(|entry| self.apply_min_duration(&key, entry))
+            .map_err(VdfCacheError::from)
+    }
+
+    //Show a hash of a video shorter than the minimum duration as too short to hash. The hash itself stays in the
+    //cache, so that it is shown again if the minimum is lowered.
+    fn apply_min_duration(&self, src_path: &Path, entry: CacheEntry) -> CacheEntry {
+        match self.min_video_duration {
+            Some(min_duration) => entry.with_min_duration(src_path, min_duration),
+            None => entry,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record_entry_metrics(&self) {
+        let (total, errors) = self
+            .cache
+            .count_values(|entry| matches!(entry.0, EntryState::Failed(_) | EntryState::FailedWithProbe(..)));
+        cache_metrics::record_entries(total, errors);
+    }
+
+    //The key under which src_path is stored in the cache. See "Paths" in the struct documentation.
+    pub(crate) fn key(&self, src_path: impl AsRef<Path>) -> PathBuf {
+        let key = self.unfolded_key(src_path);
+
+        //If the same file has already been seen with a different case, use the spelling from then.
+        match &self.case_folded_keys {
+            Some(index) => index.read().unwrap().get(&fold_case(&key)).cloned().unwrap_or(key),
+            None => key,
+        }
+    }
+
+    //The key under which src_path is to be inserted into the cache. If no spelling of the key which differs only by
+    //case has been seen, this spelling is recorded, so that later spellings are mapped to it. The lookup and the
+    //record are made under the same lock, so that two threads inserting different spellings agree on one of them.
+    fn insert_key(&self, src_path: impl AsRef<Path>) -> PathBuf {
+        let key = self.unfolded_key(src_path);
+
+        match &self.case_folded_keys {
+            Some(index) => index.write().unwrap().entry(fold_case(&key)).or_insert(key).clone(),
+            None => key,
+        }
+    }
+
+    //The keys of many paths, as given by key, looking up their spellings under one lock.
+    fn keys_of(&self, src_paths: impl IntoIterator<Item = impl AsRef<Path>>) -> Vec<PathBuf> {
+        let keys = src_paths.into_iter().map(|src_path| self.unfolded_key(src_path));
+
+        match &self.case_folded_keys {
+            Some(index) => {
+                let index = index.read().unwrap();
+                keys.map(|key| index.get(&fold_case(&key)).cloned().unwrap_or(key))
+                    .collect()
+            }
+            None => keys.collect(),
+        }
+    }
+
+    fn unfolded_key(&self, src_path: impl AsRef<Path>) -> PathBuf {
+        if self.canonicalize_paths {
+            canonicalize_path(src_path)
+        } else {
+            normalize_path(src_path)
+        }
+    }
+
+    //Hold the update lock. Every change to the cache is made under the cache's own locks, so an update which panicked
+    //left the cache consistent, and a poisoned lock can be used as normal.
+    pub(crate) fn lock_updates(&self) -> MutexGuard<'_, ()> {
+        self.update_lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    //Forget the spellings of keys which have been removed from the cache, so that the file is stored under the
+    //spelling used when it is next inserted.
+    pub(crate) fn forget_keys<'a>(&self, keys: impl IntoIterator<Item = &'a PathBuf>) {
+        if let Some(index) = &self.case_folded_keys {
+            let mut index = index.write().unwrap();
+            for key in keys {
+                let folded_key = fold_case(key);
+                if index.get(&folded_key) == Some(key) && !self.cache.contains_key(key) {
+                    index.remove(&folded_key);
+                }
+            }
+        }
+    }
+
+    //Update the index of case-folded keys after the entry at old_key has been moved to new_key.
+    fn move_key(&self, old_key: &Path, new_key: &Path) {
+        if let Some(index) = &self.case_folded_keys {
+            let mut index = index.write().unwrap();
+            let folded_old_key = fold_case(old_key);
+            if index.get(&folded_old_key).map(PathBuf::as_path) == Some(old_key) {
+                index.remove(&folded_old_key);
+            }
+            index.entry(fold_case(new_key)).or_insert_with(|| new_key.to_path_buf());
+        }
+    }
+
+    //The key under which an imported entry for src_path is stored, with the path inside any error changed to match.
+    #[cfg(any(feature = "csv", feature = "ron", feature = "sqlite"))]
+    pub(crate) fn import_entry(
+        &self,
+        src_path: &Path,
+        entry: FsCacheEntry<CacheEntry>,
+    ) -> (PathBuf, FsCacheEntry<CacheEntry>) {
+        let key = self.insert_key(src_path);
+        let value = entry.value.with_src_path(&key);
+        (key, FsCacheEntry { value, ..entry })
+    }
+
+    //Recreate the index of case-folded keys from the keys in the cache, after keys have been changed in bulk.
+    pub(crate) fn rebuild_key_index(&self) {
+        if let Some(index) = &self.case_folded_keys {
+            let mut index = index.write().unwrap();
+            index.clear();
+            for key in self.cache.keys() {
+                index.entry(fold_case(&key)).or_insert(key);
+            }
+        }
+    }
+}
+
+//Sharing the cache between threads is part of its API, so fail to compile if it ever stops being Send + Sync.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<VideoHashFilesystemCache>();
+    assert_send_sync::<ScopedCache<'static>>();
+};
+
+//Forgetting to save the cache silently loses data, so catch this during development. Release builds
+//are unaffected. Only the last handle to the cache checks, because modifications made through the other
+//handles can still be saved until then.
+impl Drop for VideoHashFilesystemCache {
+    fn drop(&mut self) {
+        let pending_saves = self.pending_saves();
+        if cfg!(debug_assertions) && pending_saves > 0 && Arc::strong_count(&self.cache) == 1 {
+            warn!(
+                "Cache at {} dropped with {} unsaved modifications, which have been lost. Call save() before \
+                 dropping the cache, or flush_without_save() to discard modifications deliberately.",
+                self.cache.cache_path().display(),
+                pending_saves
+            );
+        }
+    }
+}
+
+//A position in [0, 1) for src_path which does not change between runs, for selecting a sample of paths. A path is
+//in a sample of fraction f if its position is less than f. This uses FNV-1a, because the hashers in std may change.
+fn sample_position(src_path: &Path, seed: u64) -> f64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64 ^ seed;
+    for byte in src_path.to_string_lossy().bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    (hash >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+fn duration_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+//User data as a JSON object of its values by name, for exports.
+#[cfg(any(feature = "csv", feature = "sqlite"))]
+pub(crate) fn user_data_to_json(user_data: &std::collections::BTreeMap<String, String>) -> String {
+    let values = user_data
+        .iter()
+        .map(|(name, value)| {
+            let value = serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.clone()));
+            (name.clone(), value)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    serde_json::Value::Object(values).to_string()
+}
+
+//The inverse of user_data_to_json.
+#[cfg(any(feature = "csv", feature = "sqlite"))]
+pub(crate) fn user_data_from_json(json: &str) -> serde_json::Result<std::collections::BTreeMap<String, String>> {
+    let values: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json)?;
+    Ok(values
+        .into_iter()
+        .map(|(name, value)| (name, value.to_string()))
+        .collect())
+}
+
+//The temporary file to which a cache created by split is saved before being moved to cache_path.
+fn split_tmp_path(cache_path: &Path) -> PathBuf {
+    let mut tmp_path = cache_path.to_path_buf().into_os_string();
+    tmp_path.push(".split");
+    PathBuf::from(tmp_path)
+}
+
+pub(crate) fn fold_case(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        path::Component,
+        thread,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::*;
+
+    //The same path, relative to the current directory.
+    fn relative_path(path: &Path) -> PathBuf {
+        let normal_components = |path: &Path| {
+            path.components()
+                .filter(|component| matches!(component, Component::Normal(_)))
+                .map(|component| component.as_os_str().to_os_string())
+                .collect::<Vec<_>>()
+        };
+
+        let current_dir = env::current_dir().unwrap();
+        let parents = normal_components(&current_dir).into_iter().map(|_| "..".into());
+        parents.chain(normal_components(path)).collect()
+    }
+
+    fn fake_cache(tree: &TestTree) -> VideoHashFilesystemCache {
+        VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .build()
+            .unwrap()
+    }
+
+    fn case_insensitive_cache(tree: &TestTree) -> VideoHashFilesystemCache {
+        VideoHashFilesystemCacheBuilder::new(1000, tree.path("cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .case_insensitive_keys(true)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn concurrent_spellings_share_one_entry() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let names = (0..50).map(|i| format!("video{}.mp4", i)).collect::<Vec<_>>();
+        //The tests run on case-sensitive filesystems, so create a file for each spelling.
+        let tree = names.iter().fold(TestTree::new(), |tree, name| {
+            tree.file(name, 100, mtime).file(name.to_uppercase(), 100, mtime)
+        });
+        let cache = case_insensitive_cache(&tree);
+
+        thread::scope(|scope| {
+            for uppercase in [false, true] {
+                let (cache, tree, names) = (&cache, &tree, &names);
+                scope.spawn(move || {
+                    for name in names {
+                        let name = if uppercase { name.to_uppercase() } else { name.clone() };
+                        cache.fetch_update(tree.path(name)).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(cache.cache.keys().len(), names.len());
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn removed_spellings_are_forgotten() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new()
+            .file("Movie.mp4", 100, mtime)
+            .file("movie.mp4", 100, mtime);
+        let cache = case_insensitive_cache(&tree);
+
+        cache.fetch_update(tree.path("Movie.mp4")).unwrap();
+        cache.remove(tree.path("movie.mp4")).unwrap();
+        cache.fetch_update(tree.path("movie.mp4")).unwrap();
+
+        assert_eq!(cache.cache.keys(), vec![tree.path("movie.mp4")]);
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn relative_and_absolute_paths_share_one_entry() {
+        let tree = TestTree::new().file("videos/a.mp4", 100, UNIX_EPOCH + Duration::from_secs(1000));
+        let cache = fake_cache(&tree);
+        let relative = relative_path(&tree.path("videos/a.mp4"));
+        assert!(relative.is_relative());
+
+        cache.fetch_update(&relative).unwrap();
+        cache
+            .fetch_update(tree.root().join("videos/./../videos/a.mp4"))
+            .unwrap();
+
+        assert_eq!(cache.cache.keys(), vec![tree.path("videos/a.mp4")]);
+        assert_cached(&cache, tree.path("videos/a.mp4"));
+        assert_cached(&cache, &relative);
+        cache.remove(&relative).unwrap();
+        assert!(cache.cache.keys().is_empty());
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn canonicalized_relative_and_absolute_paths_share_one_entry() {
+        let tree = TestTree::new().file("videos/cat.mp4", 100, UNIX_EPOCH + Duration::from_secs(1000));
+        let cache = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .canonicalize_paths(true)
+            .build()
+            .unwrap();
+        let relative = Path::new(".")
+            .join(relative_path(&tree.path("videos")))
+            .join("../videos/cat.mp4");
+
+        cache.fetch_update(&relative).unwrap();
+        cache.fetch_update(tree.path("videos/cat.mp4")).unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(tree.path("videos"), tree.path("link")).unwrap();
+            cache.fetch_update(tree.path("link/cat.mp4")).unwrap();
+        }
+
+        let canonical_path = fs::canonicalize(tree.path("videos/cat.mp4")).unwrap();
+        assert_eq!(cache.cache.keys(), vec![canonical_path]);
+        assert_cached(&cache, &relative);
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn unnormalized_keys_are_normalized_when_loaded() {
+        let tree = TestTree::new().file("videos/a.mp4", 100, UNIX_EPOCH + Duration::from_secs(1000));
+        let cache = fake_cache(&tree);
+        cache.fetch_update(tree.path("videos/a.mp4")).unwrap();
+
+        //Keys saved by older versions were not normalized.
+        let unnormalized_key = tree.root().join("videos/../videos/a.mp4");
+        cache
+            .cache
+            .map_keys(|_, entry| Some((unnormalized_key.clone(), entry.with_src_path(&unnormalized_key))))
+            .unwrap();
+        cache.save().unwrap();
+        drop(cache);
+
+        let cache = fake_cache(&tree);
+        assert_eq!(cache.cache.keys(), vec![tree.path("videos/a.mp4")]);
+        assert_eq!(cache.verify().unwrap(), vec![]);
+        assert_eq!(cache.pending_saves(), 1);
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn rename_updates_spellings() {
+        let tree = TestTree::new().file("Old.mp4", 100, UNIX_EPOCH + Duration::from_secs(1000));
+        let cache = case_insensitive_cache(&tree);
+        cache.fetch_update(tree.path("Old.mp4")).unwrap();
+
+        cache.rename(tree.path("Old.mp4"), tree.path("New.mp4")).unwrap();
+
+        assert_eq!(cache.key(tree.path("NEW.MP4")), tree.path("New.mp4"));
+        assert_eq!(cache.key(tree.path("old.mp4")), tree.path("old.mp4"));
+        assert_eq!(cache.case_folded_keys.as_ref().unwrap().read().unwrap().len(), 1);
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn split_rejects_clashing_paths() {
+        let tree = TestTree::new();
+        let cache = fake_cache(&tree);
+        let keep = |_: &Path, _: Result<&VideoHash, &HashCreationErrorKind>| true;
+
+        assert!(cache.split(keep, tree.path("a.bin"), tree.path("./a.bin")).is_err());
+        assert!(cache.split(keep, tree.path("cache.bin"), tree.path("b.bin")).is_err());
+        assert!(cache.split(keep, tree.path("a.bin"), tree.path("cache.bin")).is_err());
+        assert!(!tree.path("a.bin").exists());
+        assert!(!tree.path("b.bin").exists());
+    }
+
+    #[test]
+    fn failed_split_leaves_no_files() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new().file("a.mp4", 100, mtime).file("not_a_dir", 0, mtime);
+        let cache = fake_cache(&tree);
+        cache.fetch_update(tree.path("a.mp4")).unwrap();
+        let keep = |_: &Path, _: Result<&VideoHash, &HashCreationErrorKind>| true;
+
+        let split = cache.split(keep, tree.path("a.bin"), tree.path("not_a_dir/b.bin"));
+
+        assert!(split.is_err());
+        let mut files = fs::read_dir(tree.root())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec!["a.mp4", "not_a_dir"]);
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn update_sample_counts_its_own_hashes() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new().file("a.mp4", 1, mtime).file("b.mp4", 1, mtime);
+        let cache = fake_cache(&tree);
+        let mut projection = FileProjection::new([tree.root()], Vec::<PathBuf>::new(), Vec::<&str>::new()).unwrap();
+        projection.project_using_list([tree.path("a.mp4"), tree.path("b.mp4")]);
+
+        for fraction in [f64::NAN, -0.5, 1.5] {
+            assert!(cache.update_sample(&projection, fraction, 0).is_err());
+        }
+
+        let report = cache.update_sample(&projection, 1.0, 0).unwrap();
+        assert_eq!((report.sampled, report.failed, report.already_cached), (2, 2, 0));
+        let report = cache.update_sample(&projection, 1.0, 0).unwrap();
+        assert_eq!((report.sampled, report.failed, report.already_cached), (2, 2, 2));
+        assert_eq!(report.estimated_total, Duration::from_secs(0));
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn files_deleted_while_hashing_are_not_retried_or_counted() {
+        let tree = TestTree::new().file("a.mp4", 1, UNIX_EPOCH + Duration::from_secs(1000));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let loader_attempts = attempts.clone();
+        let cache = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+            .hash_loader(move |src_path: &Path| {
+                loader_attempts.fetch_add(1, Ordering::SeqCst);
+                fs::remove_file(src_path).unwrap();
+                Err(HashCreationErrorKind::DetermineVideo {
+                    src_path: src_path.to_path_buf(),
+                    error: "deleted".to_string(),
+                })
+            })
+            .set_load_retry(RetryConfig {
+                max_attempts: 3,
+                initial_delay: Duration::from_millis(1),
+                backoff_factor: 1.0,
+            })
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            cache.fetch_update(tree.path("a.mp4")).unwrap(),
+            FetchUpdateResult::Deleted
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().hashes_created, 0);
+        assert!(cache.cache.keys().is_empty());
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn files_replaced_by_directories_are_removed() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new()
+            .file("to_dir.mp4", 1, mtime)
+            .file("to_link.mp4", 1, mtime)
+            .file("target/a.mp4", 1, mtime);
+        let cache = fake_cache(&tree);
+        let mut projection = FileProjection::new([tree.root()], Vec::<PathBuf>::new(), Vec::<&str>::new()).unwrap();
+        //A list projection is not checked against the filesystem, as if the files had changed type since projection.
+        projection.project_using_list([tree.path("to_dir.mp4"), tree.path("to_link.mp4")]);
+        for name in ["to_dir.mp4", "to_link.mp4"] {
+            cache.fetch_update(tree.path(name)).unwrap();
+            fs::remove_file(tree.path(name)).unwrap();
+        }
+
+        //file -> directory, and file -> symlink to a directory.
+        fs::create_dir(tree.path("to_dir.mp4")).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(tree.path("target"), tree.path("to_link.mp4")).unwrap();
+        #[cfg(not(unix))]
+        fs::create_dir(tree.path("to_link.mp4")).unwrap();
+        let mut removed = cache.update_using_fs_tracked(&projection).unwrap().removed;
+        removed.sort();
+        assert_eq!(removed, vec![tree.path("to_dir.mp4"), tree.path("to_link.mp4")]);
+        assert!(matches!(
+            cache.fetch_update(tree.path("to_dir.mp4")).unwrap(),
+            FetchUpdateResult::Deleted
+        ));
+        assert!(cache.cache.keys().is_empty());
+
+        //directory -> file.
+        fs::remove_dir(tree.path("to_dir.mp4")).unwrap();
+        fs::write(tree.path("to_dir.mp4"), "video").unwrap();
+        assert!(matches!(
+            cache.fetch_update(tree.path("to_dir.mp4")).unwrap(),
+            FetchUpdateResult::Hashed(_)
+        ));
+        assert_eq!(cache.cache.keys(), vec![tree.path("to_dir.mp4")]);
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn updates_stop_when_the_cache_directory_disappears() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = (0..20).fold(TestTree::new(), |tree, i| {
+            tree.file(format!("videos/{}.mp4", i), 1, mtime)
+        });
+        fs::create_dir(tree.path("drive")).unwrap();
+        let cache = VideoHashFilesystemCacheBuilder::new(1, tree.path("drive/cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .max_parallel_hashes(1)
+            .build()
+            .unwrap();
+        let mut projection =
+            FileProjection::new([tree.path("videos")], Vec::<PathBuf>::new(), Vec::<&str>::new()).unwrap();
+        projection.project_using_fs().unwrap();
+
+        //Unplug the drive.
+        fs::remove_dir_all(tree.path("drive")).unwrap();
+        let result = cache.update_using_fs(&projection);
+        assert!(matches!(result, Err(VdfCacheError::SaveFailed { .. })));
+        let cached = cache.cache.keys().len();
+        assert!(0 < cached && cached < 20);
+
+        //Everything hashed before the failure is saved once the drive is back.
+        fs::create_dir(tree.path("drive")).unwrap();
+        cache.save().unwrap();
+        let reloaded = VideoHashFilesystemCacheBuilder::new(1, tree.path("drive/cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .build()
+            .unwrap();
+        assert_eq!(reloaded.cache.keys().len(), cached);
+    }
+
+    #[test]
+    fn cache_files_written_by_generic_filesystem_cache_are_loaded() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new().file("a.mp4", 1, mtime);
+        //generic_filesystem_cache wrote a map of entries without a format marker, and hashes always had stats.
+        let error = HashCreationErrorKind::VideoProcessing {
+            src_path: tree.path("a.mp4"),
+            error: "written by an old version".to_string(),
+        };
+        let value: Result<(VideoHash, VideoStats), _> = Err(error.clone());
+        let old_entries = HashMap::from([(tree.path("a.mp4"), FsCacheEntry { mtime, value })]);
+        fs::write(tree.path("cache.bin"), bincode::serialize(&old_entries).unwrap()).unwrap();
+
+        let cache = fake_cache(&tree);
+        match cache.fetch_update(tree.path("a.mp4")).unwrap() {
+            FetchUpdateResult::Hashed(Err(e)) => assert_eq!(e, error),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn checking_unmodified_files_is_not_counted_as_hashing() {
+        let tree = TestTree::new().file("a.mp4", 1, UNIX_EPOCH + Duration::from_secs(1000));
+        let cache = fake_cache(&tree);
+        let mut projection = FileProjection::new([tree.root()], Vec::<PathBuf>::new(), Vec::<&str>::new()).unwrap();
+        projection.project_using_list([tree.path("a.mp4")]);
+
+        cache.update_using_fs(&projection).unwrap();
+        cache.update_using_fs(&projection).unwrap();
+
+        assert_eq!(cache.last_timings().hashing, Duration::from_secs(0));
+        assert!(cache.last_timings().io > Duration::from_secs(0));
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn entry_kind_map_err_changes_only_failures() {
+        let probe = VideoProbe {
+            duration: Duration::from_secs(1),
+            resolution: (640, 480),
+        };
+        let to_processing = |e: HashCreationErrorKind| HashCreationErrorKind::VideoProcessing {
+            src_path: PathBuf::from("/b.mp4"),
+            error: e.to_string(),
+        };
+
+        let kind = EntryKind::Failed(HashCreationErrorKind::VideoLength("/a.mp4".into()), Some(probe));
+        match kind.map_err(to_processing) {
+            EntryKind::Failed(HashCreationErrorKind::VideoProcessing { src_path, .. }, Some(_)) => {
+                assert_eq!(src_path, Path::new("/b.mp4"))
+            }
+            kind => panic!("unexpected entry {:?}", kind),
+        }
+        assert!(matches!(
+            EntryKind::MetadataOnly(probe).map_err(to_processing),
+            EntryKind::MetadataOnly(_)
+        ));
+    }
+
+    #[test]
+    fn invalid_min_video_durations_are_rejected() {
+        let tree = TestTree::new();
+        for secs in [f64::NAN, -1.0] {
+            let builder =
+                VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin")).min_video_duration_secs(secs);
+            assert!(builder.build().is_err());
+        }
+        assert!(!tree.path("cache.bin").exists());
+    }
+
+    #[test]
+    fn invalid_retry_backoff_factors_are_rejected() {
+        let tree = TestTree::new();
+        for backoff_factor in [f64::NAN, f64::INFINITY, -2.0, 0.5] {
+            let retry = RetryConfig {
+                backoff_factor,
+                ..RetryConfig::default()
+            };
+            let builder = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin")).set_load_retry(retry);
+            assert!(builder.build().is_err());
+        }
+        assert!(!tree.path("cache.bin").exists());
+    }
+
+    #[test]
+    fn videos_too_short_to_hash_keep_their_probe() {
+        let tree = TestTree::new().file("short.mp4", 0, UNIX_EPOCH + Duration::from_secs(1000));
+        let probe = VideoProbe {
+            duration: Duration::from_secs(1),
+            resolution: (640, 480),
+        };
+        let cache = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .video_prober(move |_: &Path| Some(probe))
+            .build()
+            .unwrap();
+
+        cache.update_subset(&[tree.path("short.mp4")]).unwrap();
+
+        match cache.fetch_any(tree.path("short.mp4")).unwrap() {
+            EntryKind::Failed(HashCreationErrorKind::VideoLength(_), stored_probe) => {
+                assert_eq!(stored_probe, Some(probe))
+            }
+            kind => panic!("unexpected entry {:?}", kind),
+        }
+        assert!(matches!(
+            cache.fetch(tree.path("short.mp4")),
+            Err(VdfCacheError::CachedFailure { .. })
+        ));
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn drain_returns_every_entry_as_stored() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let tree = TestTree::new().file("long.mp4", 100, mtime).file("short.mp4", 0, mtime);
+        let probe = VideoProbe {
+            duration: Duration::from_secs(1),
+            resolution: (640, 480),
+        };
+        let cache = VideoHashFilesystemCacheBuilder::new(100, tree.path("cache.bin"))
+            .hash_loader(FakeLoader::load)
+            .video_prober(move |src_path: &Path| match src_path.ends_with("long.mp4") {
+                true => Some(VideoProbe {
+                    duration: Duration::from_secs(3600),
+                    ..probe
+                }),
+                false => Some(probe),
+            })
+            .min_video_duration_secs(10.0)
+            .build()
+            .unwrap();
+        let mut projection = FileProjection::new([tree.root()], Vec::<PathBuf>::new(), Vec::<&str>::new()).unwrap();
+        projection.project_using_list([tree.path("long.mp4"), tree.path("short.mp4")]);
+        let options = UpdateOptions {
+            max_duration: Some(Duration::from_secs(60)),
+            ..UpdateOptions::default()
+        };
+        cache.update_using_fs_with_options(&projection, options).unwrap();
+
+        let drained = cache.drain().unwrap();
+        assert_eq!(drained.len(), 2);
+        assert!(matches!(&drained[0], (path, EntryKind::MetadataOnly(_)) if *path == tree.path("long.mp4")));
+        assert!(matches!(
+            &drained[1],
+            (path, EntryKind::Failed(HashCreationErrorKind::VideoLength(_), Some(stored_probe)))
+                if *path == tree.path("short.mp4") && *stored_probe == probe
+        ));
+        assert!(cache.all_cached_paths().is_empty());
+        cache.flush_without_save();
+    }
+
+    #[test]
+    fn update_after_panicked_update_succeeds() {
+        let tree = TestTree::new().file("a.mp4", 100, UNIX_EPOCH + Duration::from_secs(1000));
+        let cache = fake_cache(&tree);
+
+        let panicking_cache = cache.clone();
+        let panicked = thread::spawn(move || {
+            let _update_guard = panicking_cache.lock_updates();
+            panic!("update panicked");
+        })
+        .join()
+        .is_err();
+        assert!(panicked);
+        assert!(cache.update_lock.is_poisoned());
+
+        cache.update_subset(&[tree.path("a.mp4")]).unwrap();
+        assert_cached(&cache, tree.path("a.mp4"));
+        cache.flush_without_save();
     }
 }
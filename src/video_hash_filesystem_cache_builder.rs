@@ -0,0 +1,480 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
+
+use super::generic_cache_if::{GenericCacheIf, HashLoader, LogConfig, RetryConfig};
+use super::video_probe;
+use crate::*;
+
+/// The order in which [update_using_fs][`VideoHashFilesystemCache::update_using_fs`] visits files.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Default)]
+pub enum UpdateOrder {
+    /// No particular order. This is the default.
+    #[default]
+    Unordered,
+
+    /// Most recently modified files first. Files whose modification time
+    /// cannot be read are processed last.
+    ModifiedNewestFirst,
+
+    /// Least recently modified files first. Files whose modification time
+    /// cannot be read are processed last.
+    ModifiedOldestFirst,
+
+    /// Files are processed in alphabetical order of their path.
+    Alphabetical,
+}
+
+/// A builder for [VideoHashFilesystemCache], for when options other than the defaults are required.
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::path::PathBuf;
+/// use video_hash_filesystem_cache::*;
+///
+/// let cache = VideoHashFilesystemCacheBuilder::new(100, PathBuf::from("cache.bin"))
+///     .update_order(UpdateOrder::ModifiedNewestFirst)
+///     .build()
+///     .expect("failed to create cache");
+/// ```
+#[derive(Debug, Clone)]
+pub struct VideoHashFilesystemCacheBuilder {
+    cache_save_threshold: u32,
+    cache_path: PathBuf,
+    update_order: UpdateOrder,
+    open_retry_attempts: u32,
+    open_retry_delay: Duration,
+    save_retries: u32,
+    save_retry_backoff: Duration,
+    load_retry: RetryConfig,
+    store_stats: bool,
+    canonicalize_paths: bool,
+    case_insensitive_keys: bool,
+    min_video_duration: Option<f64>,
+    log_config: LogConfig,
+    hash_loader: Arc<dyn HashLoader>,
+    video_prober: Arc<dyn VideoProber>,
+    error_handler: Option<Arc<dyn ErrorHandler>>,
+    suspect_checks: SuspectChecks,
+    suspect_predicates: Vec<Arc<dyn SuspectPredicate>>,
+    strict_load: bool,
+    lazy_load: bool,
+    create_dirs: bool,
+    check_writable: bool,
+    overlay_base: Option<PathBuf>,
+    #[cfg_attr(not(feature = "parallel_loading"), allow(dead_code))]
+    max_parallel_hashes: usize,
+}
+
+impl VideoHashFilesystemCacheBuilder {
+    /// Create a builder for a cache stored at `cache_path`, which will save itself to disk
+    /// after `cache_save_threshold` write/delete operations. See [VideoHashFilesystemCache::new].
+    pub fn new(cache_save_threshold: u32, cache_path: PathBuf) -> Self {
+        Self {
+            cache_save_threshold,
+            cache_path,
+            update_order: UpdateOrder::default(),
+            open_retry_attempts: 0,
+            open_retry_delay: Duration::from_secs(0),
+            save_retries: 0,
+            save_retry_backoff: Duration::from_secs(0),
+            load_retry: RetryConfig::default(),
+            store_stats: true,
+            canonicalize_paths: false,
+            case_insensitive_keys: false,
+            min_video_duration: None,
+            log_config: LogConfig::default(),
+            hash_loader: Arc::new(GenericCacheIf::default_hash_loader),
+            video_prober: Arc::new(video_probe::ffprobe),
+            error_handler: None,
+            suspect_checks: SuspectChecks::default(),
+            suspect_predicates: vec![],
+            strict_load: true,
+            lazy_load: false,
+            create_dirs: true,
+            check_writable: false,
+            overlay_base: None,
+            max_parallel_hashes: 0,
+        }
+    }
+
+    /// Set the order in which [update_using_fs][`VideoHashFilesystemCache::update_using_fs`] processes files.
+    pub fn update_order(mut self, update_order: UpdateOrder) -> Self {
+        self.update_order = update_order;
+        self
+    }
+
+    /// Retry opening the cache file up to `attempts` more times if it fails with a transient Io error
+    /// (`TimedOut`, `Interrupted` or `WouldBlock`), which can occur when the cache is stored on a network share.
+    /// The delay before the first retry is `delay`, and the delay doubles after each retry.
+    /// Other errors are returned immediately.
+    ///
+    /// By default opening the cache is not retried.
+    pub fn set_open_retry(mut self, attempts: u32, delay: Duration) -> Self {
+        self.open_retry_attempts = attempts;
+        self.open_retry_delay = delay;
+        self
+    }
+
+    /// Retry saving the cache up to `retries` more times if writing the cache file fails with an Io error,
+    /// which can occur when the cache is stored on a network share. The delay before the first retry is `backoff`,
+    /// and the delay doubles after each retry. Failed attempts are logged, and an error is only returned once
+    /// all retries have failed.
+    ///
+    /// This applies to both explicit calls to [save][`VideoHashFilesystemCache::save`] and automatic saves.
+    /// Other threads can continue to use the cache while a save is waiting to be retried.
+    ///
+    /// By default saving the cache is not retried.
+    pub fn set_save_retry(mut self, retries: u32, backoff: Duration) -> Self {
+        self.save_retries = retries;
+        self.save_retry_backoff = backoff;
+        self
+    }
+
+    /// Retry creating a hash if it fails because the video file could not be read, which can occur when videos are
+    /// stored on a network share that is temporarily unavailable. Errors from videos which can be read (such as videos
+    /// which are too short) are not retried. If all attempts fail, the error from the last attempt is cached.
+    ///
+    /// By default creating a hash is not retried.
+    pub fn set_load_retry(mut self, retry_config: RetryConfig) -> Self {
+        self.load_retry = retry_config;
+        self
+    }
+
+    /// Set whether [VideoStats][vid_dup_finder_lib::VideoStats] are stored alongside each hash. Not storing stats
+    /// makes the cache file smaller, but [fetch_stats][`VideoHashFilesystemCache::fetch_stats`] will return
+    /// [StatsNotStored][VdfCacheError::StatsNotStored] for hashes created while stats are not stored.
+    ///
+    /// Changing this option for an existing cache does not cause any hashes to be recreated. Stats are
+    /// added or removed when each hash is next recreated because its file has been modified, so a cache may contain
+    /// a mixture of entries with and without stats. To remove all stats from an existing cache immediately, use
+    /// [discard_stats][`VideoHashFilesystemCache::discard_stats`].
+    ///
+    /// By default stats are stored.
+    pub fn store_stats(mut self, store_stats: bool) -> Self {
+        self.store_stats = store_stats;
+        self
+    }
+
+    /// If true, paths passed to the cache are canonicalized using the filesystem (resolving symlinks) before being
+    /// used as keys, so that a file reached through different symlinks is only stored once. Paths which cannot be
+    /// canonicalized, for instance because they do not exist, are made absolute instead. Every path passed to the
+    /// cache is then looked up on the filesystem, even by methods such as
+    /// [fetch][`VideoHashFilesystemCache::fetch`] which otherwise only read the cache.
+    ///
+    /// When using [update_using_fs][`VideoHashFilesystemCache::update_using_fs`], the [FileProjection] should also
+    /// canonicalize its paths (see [canonicalize_paths][`FileProjection::canonicalize_paths`]) so that cached paths
+    /// can be matched with projected paths.
+    ///
+    /// This is false by default, for compatibility with existing caches.
+    pub fn canonicalize_paths(mut self, canonicalize_paths: bool) -> Self {
+        self.canonicalize_paths = canonicalize_paths;
+        self
+    }
+
+    /// If true, paths which differ only by case (e.g. `Movie.MP4` and `movie.mp4`) are treated as the same key,
+    /// so that a file is only hashed once however its name is capitalized. The spelling that was first used for a
+    /// file is kept, and is the one returned by methods such as
+    /// [all_cached_paths][`VideoHashFilesystemCache::all_cached_paths`].
+    ///
+    /// This should only be enabled when all cached files are on case-insensitive filesystems, such as the defaults
+    /// on macOS and Windows. Enabling it on a case-sensitive filesystem is incorrect, because distinct files whose
+    /// names differ only by case would share a single entry. To enable it based on the current platform, pass
+    /// `cfg!(any(target_os = "macos", target_os = "windows"))`.
+    ///
+    /// This is false by default.
+    pub fn case_insensitive_keys(mut self, case_insensitive_keys: bool) -> Self {
+        self.case_insensitive_keys = case_insensitive_keys;
+        self
+    }
+
+    /// Treat videos shorter than `secs` seconds (such as thumbnails or previews) as too short to be hashed. Their
+    /// entries are reported as [VideoLength][vid_dup_finder_lib::HashCreationErrorKind::VideoLength] errors, even if a
+    /// hash could be created. The hashes are kept in the cache and are only hidden when they are read, so changing the
+    /// minimum takes effect immediately, without creating any hashes again.
+    ///
+    /// The duration of a video is read from its stats, so hashes whose stats were not stored are never hidden. While
+    /// stats are not being stored (see [store_stats][Self::store_stats]), new hashes of videos shorter than the minimum
+    /// are discarded instead.
+    ///
+    /// [build][Self::build] returns an error if `secs` is negative or not a number. By default there is no minimum
+    /// duration.
+    pub fn min_video_duration_secs(mut self, secs: f64) -> Self {
+        self.min_video_duration = Some(secs);
+        self
+    }
+
+    /// Set the log target and levels used for messages about creating hashes, for instance to distinguish
+    /// the messages of several caches in the same process.
+    ///
+    /// By default messages are logged with the target `hash_creation`. See [LogConfig] for the default levels.
+    pub fn set_log_config(mut self, log_config: LogConfig) -> Self {
+        self.log_config = log_config;
+        self
+    }
+
+    /// Limit [update_using_fs][`VideoHashFilesystemCache::update_using_fs`] to creating `n` hashes at the same
+    /// time, using a dedicated thread pool. This is useful on systems which are shared with other processes.
+    /// If `n` is 1, hashes are created sequentially on the calling thread. If `n` is 0, all available CPUs are used.
+    ///
+    /// This option has no effect unless the `parallel_loading` feature is enabled.
+    ///
+    /// By default all available CPUs are used.
+    pub fn max_parallel_hashes(mut self, n: usize) -> Self {
+        self.max_parallel_hashes = n;
+        self
+    }
+
+    /// If true, fail to load the cache if any of its entries cannot be decoded, for instance after a partial disk
+    /// failure. If false, undecodable entries are skipped and listed in
+    /// [load_report][`VideoHashFilesystemCache::load_report`], so that the rest of the cache can still be used.
+    ///
+    /// Cache files written by older versions of this crate are always loaded strictly.
+    ///
+    /// This is true by default.
+    pub fn strict_load(mut self, strict_load: bool) -> Self {
+        self.strict_load = strict_load;
+        self
+    }
+
+    /// If true, only the paths in the cache file are read when the cache is loaded, and each hash is read from the
+    /// file when it is fetched. This makes loading a large cache much faster and uses much less memory when only a
+    /// few hashes are needed, for instance to search for duplicates of a single video.
+    ///
+    /// The whole cache is loaded into memory before it is first modified or saved, and by methods which visit every
+    /// entry such as [all_cached_paths][`VideoHashFilesystemCache::all_cached_paths`]. Hashes which cannot be decoded
+    /// are only found when they are read, so they are not listed in
+    /// [load_report][`VideoHashFilesystemCache::load_report`], even with [strict_load][Self::strict_load].
+    ///
+    /// Cache files written by older versions of this crate are always loaded in full.
+    ///
+    /// This is false by default.
+    pub fn lazy_load(mut self, lazy_load: bool) -> Self {
+        self.lazy_load = lazy_load;
+        self
+    }
+
+    /// If true, create the directory containing the cache file (and any missing parent directories) when the cache
+    /// is loaded. If false, the directory must already exist.
+    ///
+    /// This is true by default.
+    pub fn create_dirs(mut self, create_dirs: bool) -> Self {
+        self.create_dirs = create_dirs;
+        self
+    }
+
+    /// If true, check that the cache can be saved when it is loaded, by creating and removing a file named after the
+    /// cache file with a `.probe` suffix. This reports problems such as missing permissions immediately, instead of at
+    /// the first save (which may be hours later). This briefly modifies the directory containing the cache, so it
+    /// cannot be used with read-only storage.
+    ///
+    /// This is false by default.
+    pub fn check_writable(mut self, check_writable: bool) -> Self {
+        self.check_writable = check_writable;
+        self
+    }
+
+    /// Replace the function used to create hashes. This can be used to create hashes with different settings, or by
+    /// benchmarks and tests which need to fill a cache without decoding real videos (the `test-util` cargo feature
+    /// provides a suitable function, `FakeLoader::load`). Any function or closure with the right signature can be used,
+    /// or any other type which implements [HashLoader].
+    ///
+    /// By default hashes are created using [VideoHash::from_path_with_stats][vid_dup_finder_lib::VideoHash].
+    pub fn hash_loader(mut self, hash_loader: impl HashLoader + 'static) -> Self {
+        self.hash_loader = Arc::new(hash_loader);
+        self
+    }
+
+    /// Replace the function used to read the duration and resolution of videos before they are hashed, when an update
+    /// limits them with [UpdateOptions]. Any function or closure with the right signature can be used, or any other
+    /// type which implements [VideoProber].
+    ///
+    /// By default videos are probed by running `ffprobe`, which is installed alongside `ffmpeg`.
+    pub fn video_prober(mut self, video_prober: impl VideoProber + 'static) -> Self {
+        self.video_prober = Arc::new(video_prober);
+        self
+    }
+
+    /// Report the nonfatal errors which occur while updating the cache (such as failures to create a hash or to access
+    /// a file) by passing each of them to ``error_handler`` as soon as it occurs, instead of returning them.
+    ///
+    /// When an error handler is set, the errors returned by
+    /// [update_using_fs][`VideoHashFilesystemCache::update_using_fs`] and the other bulk updates are always empty,
+    /// and the errors listed in an [UpdateDelta] are replaced by a count of the errors handled. Fatal errors are still
+    /// returned. When parallel loading is enabled, ``error_handler`` is called from several threads at the same time.
+    pub fn error_handler(mut self, error_handler: impl ErrorHandler + 'static) -> Self {
+        self.error_handler = Some(Arc::new(error_handler));
+        self
+    }
+
+    /// Choose which built-in sanity checks are applied by
+    /// [find_suspect_entries][`VideoHashFilesystemCache::find_suspect_entries`]. By default all of them are applied.
+    pub fn suspect_checks(mut self, suspect_checks: SuspectChecks) -> Self {
+        self.suspect_checks = suspect_checks;
+        self
+    }
+
+    /// Add a sanity check to those applied by
+    /// [find_suspect_entries][`VideoHashFilesystemCache::find_suspect_entries`], in addition to the built-in checks.
+    /// Each entry which ``suspect_predicate`` reports is listed with a [Custom][SuspectReason::Custom] reason. This may
+    /// be called more than once to add several checks, which are applied in the order they were added.
+    pub fn suspect_predicate(mut self, suspect_predicate: impl SuspectPredicate + 'static) -> Self {
+        self.suspect_predicates.push(Arc::new(suspect_predicate));
+        self
+    }
+
+    /// Use the cache at the cache path as an overlay on top of the cache at `base_path`, for experiments which
+    /// must not disturb an existing cache. If no cache exists at the cache path, then the cache is loaded from
+    /// `base_path` instead. All changes are saved to the cache path, and the file at `base_path` is never modified.
+    ///
+    /// Once the overlay has been saved it contains a complete copy of the cache, and later changes to the base cache
+    /// are not seen. To start a new experiment from the current base cache, delete the overlay.
+    ///
+    /// [build][Self::build] returns an error if neither the cache path nor `base_path` exist. See
+    /// [open_overlay][VideoHashFilesystemCache::open_overlay].
+    pub fn overlay_base(mut self, base_path: PathBuf) -> Self {
+        self.overlay_base = Some(base_path);
+        self
+    }
+
+    //Used to create caches with the same options as an existing cache, but stored somewhere else.
+    pub(crate) fn cache_path(mut self, cache_path: PathBuf) -> Self {
+        self.cache_path = cache_path;
+        self.overlay_base = None;
+        self
+    }
+
+    /// Load the cache from disk, or create a new one if no cache exists at the cache path.
+    ///
+    /// Returns an error if it was not possible to load the cache or create a new one.
+    pub fn build(self) -> Result<VideoHashFilesystemCache, VdfCacheError> {
+        if let Some(secs) = self.min_video_duration.filter(|secs| secs.is_nan() || *secs < 0.0) {
+            let msg = format!("minimum video duration must be a non-negative number, not {}", secs);
+            let e = std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+            return Err(FsCacheErrorKind::io(&self.cache_path, e).into());
+        }
+        let backoff_factor = self.load_retry.backoff_factor;
+        if !(backoff_factor.is_finite() && backoff_factor >= 1.0) {
+            let msg = format!(
+                "retry backoff factor must be a finite number of at least 1, not {}",
+                backoff_factor
+            );
+            let e = std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+            return Err(FsCacheErrorKind::io(&self.cache_path, e).into());
+        }
+        self.prepare_cache_path()?;
+
+        let mut attempt = 0;
+        let mut delay = self.open_retry_delay;
+
+        let mut cache = loop {
+            let interface = GenericCacheIf::new(
+                self.load_retry,
+                self.store_stats,
+                self.log_config.clone(),
+                self.min_video_duration,
+                self.hash_loader.clone(),
+                self.video_prober.clone(),
+            );
+            match ProcessingFsCache::new(
+                self.cache_save_threshold,
+                self.cache_path.clone(),
+                self.overlay_base.as_deref(),
+                interface,
+                self.strict_load,
+                self.lazy_load,
+            ) {
+                Ok(cache) => break cache,
+                Err(e) if attempt < self.open_retry_attempts && e.is_transient_io() => {
+                    warn!(
+                        "Failed to open cache at {} (attempt {}), retrying: {}",
+                        self.cache_path.display(),
+                        attempt + 1,
+                        e
+                    );
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        cache.set_save_retry(self.save_retries, self.save_retry_backoff);
+
+        #[cfg(feature = "parallel_loading")]
+        let thread_pool = match self.max_parallel_hashes {
+            0 | 1 => None,
+            n => Some(Arc::new(rayon::ThreadPoolBuilder::new().num_threads(n).build()?)),
+        };
+
+        let ret = VideoHashFilesystemCache {
+            cache: Arc::new(cache),
+            update_order: self.update_order,
+            last_timings: Arc::new(Mutex::new(ScanTimings::default())),
+            update_lock: Arc::new(Mutex::new(())),
+            canonicalize_paths: self.canonicalize_paths,
+            case_folded_keys: self
+                .case_insensitive_keys
+                .then(|| Arc::new(RwLock::new(HashMap::new()))),
+            min_video_duration: self.min_video_duration,
+            video_prober: self.video_prober.clone(),
+            error_handler: self.error_handler.clone(),
+            suspect_checks: self.suspect_checks,
+            suspect_predicates: self.suspect_predicates.clone(),
+            #[cfg(feature = "parallel_loading")]
+            max_parallel_hashes: self.max_parallel_hashes,
+            #[cfg(feature = "parallel_loading")]
+            thread_pool,
+            options: self,
+        };
+        ret.rebuild_key_index();
+        ret.normalize_loaded_keys()?;
+
+        Ok(ret)
+    }
+    //Check that the cache file can be loaded and saved, so that problems are reported before any work is done.
+    fn prepare_cache_path(&self) -> Result<(), VdfCacheError> {
+        let cache_path = &self.cache_path;
+        if cache_path.is_dir() {
+            return Err(VdfCacheError::CachePathIsDirectory(cache_path.clone()));
+        }
+
+        //A missing base cache is probably a mistake, which would otherwise silently start the overlay empty.
+        if let Some(base_path) = &self.overlay_base {
+            if !cache_path.exists() && !base_path.exists() {
+                let src = std::io::Error::from(std::io::ErrorKind::NotFound);
+                return Err(FsCacheErrorKind::io(base_path, src).into());
+            }
+        }
+
+        //A relative path with a single component has an empty parent, which is the current directory.
+        let cache_dir = cache_path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(cache_dir) = cache_dir {
+            if self.create_dirs && !cache_dir.exists() {
+                fs::create_dir_all(cache_dir).map_err(|src| FsCacheErrorKind::io(cache_dir, src))?;
+            }
+        }
+
+        //Saves replace the cache file with a new file in the same directory, so check that a file can be created
+        //there. Opening the cache file itself for writing is not enough.
+        if self.check_writable {
+            let mut probe_path = cache_path.clone().into_os_string();
+            probe_path.push(".probe");
+            let probe_path = PathBuf::from(probe_path);
+
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&probe_path)
+                .and_then(|_| fs::remove_file(&probe_path))
+                .map_err(|src| FsCacheErrorKind::io(cache_path, src))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use std::{convert::TryFrom, fmt, path::Path, process::Command, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use vid_dup_finder_lib::VideoStats;
+
+/// The duration and resolution of a video, read from its metadata without decoding it. See
+/// [UpdateOptions][crate::UpdateOptions].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VideoProbe {
+    /// The duration of the video.
+    pub duration: Duration,
+
+    /// The width and height of the first video stream.
+    pub resolution: (u32, u32),
+}
+
+impl VideoProbe {
+    //The probe of a video whose stats were read while creating its hash.
+    pub(crate) fn from_stats(stats: &VideoStats) -> Option<Self> {
+        Some(Self {
+            duration: Duration::try_from_secs_f64(stats.duration).ok()?,
+            resolution: stats.resolution,
+        })
+    }
+}
+
+/// Reads the duration and resolution of a video before its hash is created, so that videos which exceed the limits
+/// in [UpdateOptions][crate::UpdateOptions] can be skipped. See
+/// [video_prober][crate::VideoHashFilesystemCacheBuilder::video_prober].
+///
+/// This is implemented for all functions and closures with the same signature as [probe][VideoProber::probe].
+pub trait VideoProber: Send + Sync {
+    /// Read the duration and resolution of the video at ``src_path``, or return None if they cannot be read.
+    fn probe(&self, src_path: &Path) -> Option<VideoProbe>;
+}
+
+impl<F> VideoProber for F
+where
+    F: Fn(&Path) -> Option<VideoProbe> + Send + Sync,
+{
+    fn probe(&self, src_path: &Path) -> Option<VideoProbe> {
+        self(src_path)
+    }
+}
+
+impl fmt::Debug for dyn VideoProber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VideoProber")
+    }
+}
+
+//Probe a video with ffprobe, which is installed alongside the ffmpeg used by vid_dup_finder_lib. Only the container
+//headers are read, so this is much faster than creating a hash.
+pub(crate) fn ffprobe(src_path: &Path) -> Option<VideoProbe> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream=width,height:format=duration", "-of", "json"])
+        .arg(src_path)
+        .output();
+
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            debug!("ffprobe failed for {}: {}", src_path.display(), output.status);
+            return None;
+        }
+        Err(e) => {
+            debug!("Failed to run ffprobe for {}: {}", src_path.display(), e);
+            return None;
+        }
+    };
+
+    let json: Value = serde_json::from_slice(&output.stdout).ok()?;
+    let stream = json.get("streams")?.get(0)?;
+    let dimension = |name: &str| u32::try_from(stream.get(name)?.as_u64()?).ok();
+
+    //ffprobe writes the duration as a string, to avoid losing precision.
+    let duration = json.get("format")?.get("duration")?.as_str()?.parse::<f64>().ok()?;
+    let duration = Duration::try_from_secs_f64(duration).ok()?;
+
+    Some(VideoProbe {
+        duration,
+        resolution: (dimension("width")?, dimension("height")?),
+    })
+}